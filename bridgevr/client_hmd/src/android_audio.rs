@@ -7,6 +7,7 @@ impl AudioRecorder {
     pub fn start_recording(
         device_idx: Option<u64>,
         loopback: bool,
+        max_packet_size: u64,
         mut packet_enqueuer: PacketEnqueuer,
     ) -> StrResult<AudioRecorder> {
         todo!()
@@ -1,9 +1,152 @@
+use crate::{data::*, encryption::ENCRYPTION_OVERHEAD_BYTES, *};
+
+const TRACE_CONTEXT: &str = "Audio";
+
+// Bytes added to each fragment by the other AudioPacket fields, the stream id and the Laminar
+// header.
+const AUDIO_PACKET_OVERHEAD_BYTES: usize = 64;
+
+// Smallest max_packet_size that leaves room for the samples in every audio fragment
+pub const MIN_AUDIO_PACKET_SIZE_BYTES: usize =
+    AUDIO_PACKET_OVERHEAD_BYTES + ENCRYPTION_OVERHEAD_BYTES + 64;
+
+// Check that the device index selected in the settings is in `devices`, as returned by
+// list_input_devices() or list_output_devices(). None selects the default device.
+pub fn validate_device_index(device_index: Option<u64>, devices: &[(u64, String)]) -> StrResult {
+    match device_index {
+        Some(index) if !devices.iter().any(|(i, _)| *i == index) => {
+            let devices_str = devices.iter().fold(String::new(), |s, (i, name)| {
+                s + &format!(" {{ {}: {} }}", i, name)
+            });
+            trace_str!(
+                "Audio device {} not found. Available devices:{}",
+                index,
+                devices_str
+            )
+        }
+        _ => Ok(()),
+    }
+}
+
+// Split a buffer of samples into packets that do not exceed max_packet_size (from
+// SocketConfig::effective_max_packet_size()). fragment_index and fragment_count are used by
+// AudioBufferReassembler to rebuild the buffer.
+pub fn packetize_audio_buffer(
+    buffer_index: u64,
+    samples: &[u8],
+    max_packet_size: u64,
+) -> StrResult<Vec<AudioPacket<'_>>> {
+    let max_fragment_size = trace_none!(
+        (max_packet_size as usize)
+            .checked_sub(AUDIO_PACKET_OVERHEAD_BYTES + ENCRYPTION_OVERHEAD_BYTES),
+        "Max packet size too small"
+    )?;
+
+    let fragments: Vec<_> = if samples.is_empty() {
+        vec![samples]
+    } else {
+        samples.chunks(max_fragment_size).collect()
+    };
+
+    if fragments.len() > u8::MAX as usize {
+        return trace_str!(
+            "Audio buffer of {} bytes too big for max packet size {}",
+            samples.len(),
+            max_packet_size
+        );
+    }
+    let fragment_count = fragments.len() as u8;
+
+    Ok(fragments
+        .into_iter()
+        .enumerate()
+        .map(|(idx, fragment)| AudioPacket {
+            buffer_index,
+            fragment_index: idx as _,
+            fragment_count,
+            samples: fragment,
+        })
+        .collect())
+}
+
+struct PartialAudioBuffer {
+    buffer_index: u64,
+    fragments: Vec<Option<Vec<u8>>>,
+    missing_count: usize,
+}
+
+// Audio buffers are played in order, so only the newest buffer is reassembled. An incomplete
+// buffer is discarded as soon as a fragment of a newer buffer is received.
+#[derive(Default)]
+pub struct AudioBufferReassembler {
+    maybe_partial_buffer: Option<PartialAudioBuffer>,
+    last_buffer_index: Option<u64>,
+    discarded_count: usize,
+}
+
+impl AudioBufferReassembler {
+    // Returns the samples when the last missing fragment of a buffer is received.
+    pub fn push(&mut self, packet: &AudioPacket) -> Option<Vec<u8>> {
+        let fragment_count = packet.fragment_count as usize;
+        let fragment_index = packet.fragment_index as usize;
+        if fragment_index >= fragment_count
+            || matches!(self.last_buffer_index, Some(idx) if packet.buffer_index <= idx)
+        {
+            return None;
+        }
+
+        match &self.maybe_partial_buffer {
+            Some(partial_buffer) if partial_buffer.buffer_index == packet.buffer_index => (),
+            Some(partial_buffer) if partial_buffer.buffer_index > packet.buffer_index => {
+                return None
+            }
+            maybe_partial_buffer => {
+                if maybe_partial_buffer.is_some() {
+                    self.discarded_count += 1;
+                }
+                self.maybe_partial_buffer = Some(PartialAudioBuffer {
+                    buffer_index: packet.buffer_index,
+                    fragments: vec![None; fragment_count],
+                    missing_count: fragment_count,
+                });
+            }
+        }
+
+        // unwrap never fails
+        let partial_buffer = self.maybe_partial_buffer.as_mut().unwrap();
+        if let Some(slot @ None) = partial_buffer.fragments.get_mut(fragment_index) {
+            *slot = Some(packet.samples.to_vec());
+            partial_buffer.missing_count -= 1;
+        }
+
+        if partial_buffer.missing_count == 0 {
+            self.last_buffer_index = Some(packet.buffer_index);
+            self.maybe_partial_buffer.take().map(|partial_buffer| {
+                partial_buffer
+                    .fragments
+                    .into_iter()
+                    .flatten()
+                    .flatten()
+                    .collect()
+            })
+        } else {
+            None
+        }
+    }
+
+    // Number of incomplete buffers discarded since the last call
+    pub fn take_discarded_count(&mut self) -> usize {
+        let discarded_count = self.discarded_count;
+        self.discarded_count = 0;
+        discarded_count
+    }
+}
+
 // use crate::{
 //     data::*,
 //     event_timing::*,
 //     sockets::*,
 //     thread_loop::{self, *},
-//     *,
 // };
 // use cpal::{
 //     traits::{DeviceTrait, EventLoopTrait, HostTrait},
@@ -13,8 +156,6 @@
 // use safe_transmute::*;
 // use std::{cmp::min, sync::mpsc::*, sync::*, thread::*, time::Duration, time::*, *};
 
-// const TRACE_CONTEXT: &str = "Audio";
-
 // const TIMEOUT: Duration = Duration::from_millis(500);
 
 // enum AudioMode {
@@ -23,6 +164,45 @@
 //     Loopback,
 // }
 
+// // Devices usable in the given mode. The position in the list is the index used in the settings.
+// // Loopback records from output devices.
+// fn usable_devices(host: &Host, mode: &AudioMode) -> StrResult<Vec<(Device, Format)>> {
+//     Ok(trace_err!(host.devices())?
+//         .filter_map(|dev| {
+//             match mode {
+//                 AudioMode::Input => dev.default_input_format(),
+//                 AudioMode::Output | AudioMode::Loopback => dev.default_output_format(),
+//             }
+//             .map(|format| (dev, format))
+//             .ok()
+//         })
+//         .collect())
+// }
+
+// fn device_names(devices_and_formats: &[(Device, Format)]) -> Vec<(u64, String)> {
+//     devices_and_formats
+//         .iter()
+//         .enumerate()
+//         .map(|(i, (dev, _))| (i as _, dev.name().unwrap_or_else(|_| "Unknown".into())))
+//         .collect()
+// }
+
+// // Index and name of each device that can be used for recording
+// pub fn list_input_devices() -> StrResult<Vec<(u64, String)>> {
+//     Ok(device_names(&usable_devices(
+//         &cpal::default_host(),
+//         &AudioMode::Input,
+//     )?))
+// }
+
+// // Index and name of each device that can be used for playback or loopback recording
+// pub fn list_output_devices() -> StrResult<Vec<(u64, String)>> {
+//     Ok(device_names(&usable_devices(
+//         &cpal::default_host(),
+//         &AudioMode::Output,
+//     )?))
+// }
+
 // struct AudioSession {
 //     event_loop: Arc<EventLoop>,
 //     stream: StreamId,
@@ -38,28 +218,12 @@
 //         let host = cpal::default_host();
 //         let event_loop = Arc::new(host.event_loop());
 
-//         let mut devices_and_formats: Vec<_> = trace_err!(host.devices())?
-//             .filter_map(|dev| {
-//                 match mode {
-//                     AudioMode::Input => dev.default_input_format(),
-//                     AudioMode::Output | AudioMode::Loopback => dev.default_output_format(),
-//                 }
-//                 .map(|format| (dev, format))
-//                 .ok()
-//             })
-//             .collect();
-
-//         let devices_str =
-//             devices_and_formats
-//                 .iter()
-//                 .enumerate()
-//                 .fold(String::new(), |s, (i, (dev, _))| {
-//                     s + &format!(
-//                         " {{ {}: {} }}",
-//                         i,
-//                         dev.name().unwrap_or_else(|_| "Unknown".into())
-//                     )
-//                 });
+//         let mut devices_and_formats = usable_devices(&host, &mode)?;
+//         let device_names = device_names(&devices_and_formats);
+
+//         let devices_str = device_names
+//             .iter()
+//             .fold(String::new(), |s, (i, name)| s + &format!(" {{ {}: {} }}", i, name));
 //         let io_str = match mode {
 //             AudioMode::Input => "input",
 //             AudioMode::Output => "output",
@@ -71,13 +235,9 @@
 //         );
 
 //         let (device, mut format) = if let Some(idx) = device_idx {
-//             let idx = idx as usize;
-//             if idx < devices_and_formats.len() {
-//                 // the bound check prevents panic
-//                 devices_and_formats.remove(idx)
-//             } else {
-//                 return trace_str!("Index out of bound");
-//             }
+//             validate_device_index(device_idx, &device_names)?;
+//             // the validation prevents panic
+//             devices_and_formats.remove(idx as usize)
 //         } else {
 //             match mode {
 //                 AudioMode::Input => {
@@ -144,6 +304,8 @@
 //     pub fn start_recording(
 //         device_idx: Option<u64>,
 //         loopback: bool,
+//         // from SocketConfig::effective_max_packet_size()
+//         max_packet_size: u64,
 //         mut packet_enqueuer: PacketEnqueuer,
 //     ) -> StrResult<AudioRecorder> {
 //         let mode = if loopback {
@@ -152,19 +314,26 @@
 //             AudioMode::Input
 //         };
 
+//         let mut buffer_index = 0;
 //         let session = trace_err!(AudioSession::start(device_idx, mode, move |io_data| {
 //             match io_data {
 //                 StreamData::Input {
 //                     buffer: UnknownTypeInputBuffer::F32(samples),
 //                 } => {
-//                     let audio_packet = AudioPacket {
-//                         samples: transmute_to_bytes(&samples[..]),
-//                     };
-
-//                     packet_enqueuer
-//                         .enqueue(&audio_packet)
-//                         .map_err(|e| debug!("{}", e))
-//                         .ok();
+//                     let maybe_audio_packets = packetize_audio_buffer(
+//                         buffer_index,
+//                         transmute_to_bytes(&samples[..]),
+//                         max_packet_size,
+//                     )
+//                     .map_err(|e| warn!("{}", e));
+//                     buffer_index += 1;
+
+//                     for audio_packet in maybe_audio_packets.iter().flatten() {
+//                         packet_enqueuer
+//                             .enqueue(audio_packet)
+//                             .map_err(|e| debug!("{}", e))
+//                             .ok();
+//                     }
 //                 }
 //                 _ => warn!("[Audio recorder] Invalid format"),
 //             }
@@ -191,6 +360,7 @@
 //     ) -> StrResult<AudioPlayer> {
 //         let (timestamp_packet_sender, timestamp_packet_receiver) = channel();
 
+//         let mut reassembler = AudioBufferReassembler::default();
 //         let packet_timestamp_thread =
 //             thread_loop::spawn("Audio player packet forward loop", move || {
 //                 let maybe_packet = packet_dequeuer
@@ -198,14 +368,15 @@
 //                     .map_err(|e| debug!("{}", e));
 
 //                 if let Ok(packet) = maybe_packet {
-//                     let maybe_audio_packet =
-//                         packet.get::<AudioPacket>().map_err(|e| debug!("{}", e));
-//                     if let Ok(audio_packet) = maybe_audio_packet {
-//                         // Ignore the packet if transmute fails. The chance of a packet having the
+//                     let maybe_buffer = packet
+//                         .get::<AudioPacket>()
+//                         .map_err(|e| debug!("{}", e))
+//                         .ok()
+//                         .and_then(|audio_packet| reassembler.push(&audio_packet));
+//                     if let Some(buffer) = maybe_buffer {
+//                         // Ignore the buffer if transmute fails. The chance of a packet having the
 //                         // length corrupted but resulting valid by bincode is non existent
-//                         if let Ok(samples) =
-//                             transmute_many::<f32, PermissiveGuard>(audio_packet.samples)
-//                         {
+//                         if let Ok(samples) = transmute_many::<f32, PermissiveGuard>(&buffer) {
 //                             timestamp_packet_sender
 //                                 .send((Instant::now(), samples.to_vec()))
 //                                 .map_err(|e| debug!("{}", e))
@@ -321,3 +492,106 @@
 //         self.packet_timestamp_thread.request_stop()
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn devices() -> Vec<(u64, String)> {
+        vec![(0, "Speakers".into()), (3, "Headphones".into())]
+    }
+
+    #[test]
+    fn default_or_listed_device_is_valid() {
+        assert!(validate_device_index(None, &devices()).is_ok());
+        assert!(validate_device_index(None, &[]).is_ok());
+        assert!(validate_device_index(Some(3), &devices()).is_ok());
+    }
+
+    #[test]
+    fn unlisted_device_is_reported_with_available_devices() {
+        let error = validate_device_index(Some(1), &devices()).unwrap_err();
+        assert!(error.contains(
+            "Audio device 1 not found. Available devices: { 0: Speakers } { 3: Headphones }"
+        ));
+    }
+
+    const FRAGMENT_SIZE: usize = 100;
+    const MAX_PACKET_SIZE: u64 =
+        (AUDIO_PACKET_OVERHEAD_BYTES + ENCRYPTION_OVERHEAD_BYTES + FRAGMENT_SIZE) as _;
+
+    fn samples(size: usize) -> Vec<u8> {
+        (0..size).map(|idx| idx as u8).collect()
+    }
+
+    #[test]
+    fn buffer_is_split_by_max_packet_size() {
+        let samples = samples(250);
+        let packets = packetize_audio_buffer(4, &samples, MAX_PACKET_SIZE).unwrap();
+
+        assert_eq!(packets.len(), 3);
+        for (idx, packet) in packets.iter().enumerate() {
+            assert_eq!(packet.buffer_index, 4);
+            assert_eq!(packet.fragment_index, idx as u8);
+            assert_eq!(packet.fragment_count, 3);
+        }
+        assert_eq!(packets[0].samples, &samples[..FRAGMENT_SIZE]);
+        assert_eq!(packets[2].samples.len(), 50);
+
+        let packets = packetize_audio_buffer(4, &[], MAX_PACKET_SIZE).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert!(packets[0].samples.is_empty());
+    }
+
+    #[test]
+    fn invalid_packet_sizes_are_rejected() {
+        let too_small = (AUDIO_PACKET_OVERHEAD_BYTES + ENCRYPTION_OVERHEAD_BYTES - 1) as u64;
+        assert!(packetize_audio_buffer(0, &samples(10), too_small).is_err());
+
+        let too_many_fragments = samples(FRAGMENT_SIZE * u8::MAX as usize + 1);
+        assert!(packetize_audio_buffer(0, &too_many_fragments, MAX_PACKET_SIZE).is_err());
+    }
+
+    #[test]
+    fn fragments_are_reassembled_in_any_order() {
+        let samples = samples(250);
+        let packets = packetize_audio_buffer(0, &samples, MAX_PACKET_SIZE).unwrap();
+
+        let mut reassembler = AudioBufferReassembler::default();
+        assert_eq!(reassembler.push(&packets[2]), None);
+        assert_eq!(reassembler.push(&packets[0]), None);
+        assert_eq!(reassembler.push(&packets[0]), None);
+        assert_eq!(reassembler.push(&packets[1]), Some(samples.clone()));
+
+        // Already played
+        assert_eq!(reassembler.push(&packets[0]), None);
+        assert_eq!(reassembler.take_discarded_count(), 0);
+    }
+
+    #[test]
+    fn incomplete_buffer_is_discarded_by_newer_buffer() {
+        let old_samples = samples(250);
+        let old_packets = packetize_audio_buffer(0, &old_samples, MAX_PACKET_SIZE).unwrap();
+        let new_samples = samples(50);
+        let new_packets = packetize_audio_buffer(1, &new_samples, MAX_PACKET_SIZE).unwrap();
+
+        let mut reassembler = AudioBufferReassembler::default();
+        reassembler.push(&old_packets[0]);
+        assert_eq!(reassembler.push(&new_packets[0]), Some(new_samples.clone()));
+        assert_eq!(reassembler.push(&old_packets[1]), None);
+        assert_eq!(reassembler.take_discarded_count(), 1);
+        assert_eq!(reassembler.take_discarded_count(), 0);
+    }
+
+    #[test]
+    fn invalid_fragment_index_is_ignored() {
+        let mut reassembler = AudioBufferReassembler::default();
+        let packet = AudioPacket {
+            buffer_index: 0,
+            fragment_index: 1,
+            fragment_count: 1,
+            samples: &[1, 2],
+        };
+        assert_eq!(reassembler.push(&packet), None);
+    }
+}
@@ -1,5 +1,7 @@
 #![allow(clippy::large_enum_variant)]
 
+// WARNING: never use usize or isize in packets or settings, their size is hardware dependent
+
 mod settings;
 mod constants;
 
@@ -36,6 +38,31 @@ pub enum MotionSampleDesc {
     Dof6(MotionSample6DofDesc),
 }
 
+// Index of the per eye arrays ([_; 2]). Use Eye::from_index() for indices received from the runtime
+// or from the network, so that out of range values are rejected instead of panicking.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+impl Eye {
+    pub fn from_index(index: u32) -> Option<Self> {
+        match index {
+            0 => Some(Eye::Left),
+            1 => Some(Eye::Right),
+            _ => {
+                warn!("Invalid eye index {}", index);
+                None
+            }
+        }
+    }
+
+    pub fn index(self) -> usize {
+        self as _
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ClientHandshakePacket {
     pub bridgevr_name: String,
@@ -48,18 +75,30 @@ pub struct ClientHandshakePacket {
     pub preferred_audio_player_sample_rates: u32,
     pub available_microphone_sample_rates: Vec<u32>,
     pub preferred_microphone_sample_rates: Vec<u32>,
+
+    // If true, the server can compress large control channel messages
+    pub supports_control_compression: bool,
+
+    // X25519 public key. None if the client does not support data channel encryption
+    pub encryption_public_key: Option<[u8; 32]>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ServerConfig {
     pub version: String,
     pub target_eye_resolution: (u32, u32),
+
+    // Settings::video::frame_slice_count, resolved
+    pub frame_slice_count: u8,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ServerHandshakePacket {
     pub config: ServerConfig,
     pub settings: Settings,
+
+    // X25519 public key. None if the data channels are not encrypted
+    pub encryption_public_key: Option<[u8; 32]>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -68,12 +107,24 @@ pub struct VideoPacket<'a> {
     pub sub_nal_index: u8,
     pub sub_nal_count: u8,
     pub hmd_pose: Pose,
+
+    // Server monotonic clock, sampled at encode time. The client converts it to its own clock using
+    // the clock offset established during the handshake (see video_latency()).
+    pub server_timestamp_ns: u64,
+
     pub sub_nal: &'a [u8],
 }
 
-// Since BridgeVR does not attempt at any clock synchronization, sending a timestamp is useless
+// Audio is played as soon as it is received, so unlike VideoPacket there is no server
+// timestamp. Audio latency is not measured
 #[derive(Serialize, Deserialize)]
 pub struct AudioPacket<'a> {
+    // Buffers bigger than the maximum packet size are split in fragments (see
+    // packetize_audio_buffer())
+    pub buffer_index: u64,
+    pub fragment_index: u8,
+    pub fragment_count: u8,
+
     // unfortunately serde does not support slice formats other than u8
     pub samples: &'a [u8],
 }
@@ -91,6 +142,12 @@ pub enum OtherServerPacket {
         device_type: TrackedDeviceType,
         sample: HapticSample,
     },
+    // The encoder of a slice failed to open and the fallback encoder is used. The client must
+    // restart the decoder of the slice for this codec
+    VideoEncoderFallback {
+        slice_idx: u8,
+        codec_name: String,
+    },
     Shutdown,
 }
 
@@ -187,7 +244,19 @@ pub enum InputDeviceData {
 }
 
 #[derive(Serialize, Deserialize, Default)]
-pub struct ClientStatistics {}
+pub struct ClientStatistics {
+    // Incomplete frames dropped by the reorder buffer since the last report
+    pub reorder_buffer_dropped_frames: u32,
+    // Frames dropped because they were too old to be decoded since the last report
+    pub late_dropped_frames: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct BatteryDesc {
+    // 0 to 1
+    pub percentage: f32,
+    pub is_charging: bool,
+}
 
 #[derive(Serialize, Deserialize)]
 pub enum OtherClientPacket {
@@ -200,6 +269,11 @@ pub enum OtherClientPacket {
         timestamp_ns: u64,
     },
     Statistics(ClientStatistics),
+    // Sent periodically by wireless devices
+    Battery {
+        device_type: TrackedDeviceType,
+        battery: BatteryDesc,
+    },
     Disconnected,
 }
 
@@ -212,14 +286,43 @@ pub struct SessionDesc {
     pub settings_cache: serde_json::Value,
 }
 
+// Platform directory for user configuration files. None if the environment does not define it.
+fn user_config_dir() -> Option<PathBuf> {
+    let env_path = |name| std::env::var_os(name).map(PathBuf::from);
+
+    if cfg!(windows) {
+        env_path("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        env_path("HOME").map(|home| home.join("Library").join("Application Support"))
+    } else {
+        env_path("XDG_CONFIG_HOME").or_else(|| env_path("HOME").map(|home| home.join(".config")))
+    }
+    .map(|config_dir| config_dir.join("BridgeVR"))
+}
+
 pub struct SessionDescLoader {
     session_desc: SessionDesc,
     path: PathBuf,
+
+    // Used when the primary path is not writable, for example for a read-only install directory
+    fallback_path: Option<PathBuf>,
+    using_fallback: bool,
 }
 
 impl SessionDescLoader {
     pub fn load(path: &Path) -> Self {
-        let session_desc = if let Ok(file_content) = fs::read_to_string(path) {
+        let fallback_path = path
+            .file_name()
+            .and_then(|file_name| Some(user_config_dir()?.join(file_name)));
+
+        // The fallback file exists only if the last save to the primary path failed, so it is the
+        // most recent one
+        let load_path = match &fallback_path {
+            Some(fallback_path) if fallback_path.exists() => fallback_path,
+            _ => path,
+        };
+
+        let session_desc = if let Ok(file_content) = fs::read_to_string(load_path) {
             json::from_str(&file_content).unwrap_or_else(|_| {
                 warn!("Invalid session file. Using default values.");
                 <_>::default()
@@ -232,6 +335,8 @@ impl SessionDescLoader {
         Self {
             session_desc,
             path: PathBuf::from(path),
+            fallback_path,
+            using_fallback: false,
         }
     }
 
@@ -239,11 +344,228 @@ impl SessionDescLoader {
         &mut self.session_desc
     }
 
-    pub fn save(&self) -> StrResult {
+    // Path of the file written by the last save()
+    pub fn effective_path(&self) -> &Path {
+        match &self.fallback_path {
+            Some(fallback_path) if self.using_fallback => fallback_path,
+            _ => &self.path,
+        }
+    }
+
+    pub fn save(&mut self) -> StrResult {
         const TRACE_CONTEXT: &str = "Session";
-        trace_err!(fs::write(
-            &self.path,
-            trace_err!(json::to_string_pretty(&self.session_desc))?
+        let session_json = trace_err!(json::to_string_pretty(&self.session_desc))?;
+
+        if !self.using_fallback {
+            match fs::write(&self.path, &session_json) {
+                Ok(()) => {
+                    // A stale fallback file would shadow the primary one on the next load
+                    if let Some(fallback_path) = &self.fallback_path {
+                        fs::remove_file(fallback_path).ok();
+                    }
+                    return Ok(());
+                }
+                Err(e) => {
+                    let fallback_path = if let Some(fallback_path) = &self.fallback_path {
+                        fallback_path
+                    } else {
+                        return trace_err!(Err(e));
+                    };
+                    warn!(
+                        "Cannot write session file {} ({}). Using {}",
+                        self.path.display(),
+                        e,
+                        fallback_path.display()
+                    );
+                    self.using_fallback = true;
+                }
+            }
+        }
+
+        let fallback_path = trace_none!(self.fallback_path.as_ref())?;
+        if let Some(dir) = fallback_path.parent() {
+            trace_err!(fs::create_dir_all(dir))?;
+        }
+        trace_err!(fs::write(fallback_path, session_json))
+    }
+}
+
+const REDACTED: &str = "<redacted>";
+
+// Sanitized snapshot of the configuration, to be attached to bug reports. Client IP and client name
+// are redacted.
+pub fn bug_report_bundle(
+    settings: &Settings,
+    session_desc: &SessionDesc,
+) -> StrResult<json::Value> {
+    const TRACE_CONTEXT: &str = "Bug report";
+
+    let mut settings = settings.clone();
+    if settings.connection.client_ip.is_some() {
+        settings.connection.client_ip = Some(REDACTED.into());
+    }
+
+    let last_client_handshake_packet =
+        session_desc
+            .last_client_handshake_packet
+            .clone()
+            .map(|mut handshake_packet| {
+                handshake_packet.bridgevr_name = REDACTED.into();
+                handshake_packet
+            });
+
+    Ok(json::json!({
+        "server_version": BVR_SERVER_VERSION,
+        "settings": trace_err!(json::to_value(settings))?,
+        "last_client_handshake_packet": trace_err!(json::to_value(last_client_handshake_packet))?,
+    }))
+}
+
+pub fn save_bug_report_bundle(
+    path: &Path,
+    settings: &Settings,
+    session_desc: &SessionDesc,
+) -> StrResult {
+    const TRACE_CONTEXT: &str = "Bug report";
+    trace_err!(fs::write(
+        path,
+        trace_err!(json::to_string_pretty(&bug_report_bundle(
+            settings,
+            session_desc
+        )?))?
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_settings() -> Settings {
+        json::from_str(include_str!("../../../../settings.json")).unwrap()
+    }
+
+    fn handshake_packet(bridgevr_name: &str) -> ClientHandshakePacket {
+        let fov = Fov {
+            left: 45.,
+            top: 45.,
+            right: 45.,
+            bottom: 45.,
+        };
+        ClientHandshakePacket {
+            bridgevr_name: bridgevr_name.into(),
+            version: BVR_SERVER_VERSION.into(),
+            native_eye_resolution: (1440, 1600),
+            fov: [fov; 2],
+            fps: 72,
+            max_video_encoder_instances: 1,
+            available_audio_player_sample_rates: vec![],
+            preferred_audio_player_sample_rates: 48000,
+            available_microphone_sample_rates: vec![],
+            preferred_microphone_sample_rates: vec![],
+            supports_control_compression: true,
+            encryption_public_key: None,
+        }
+    }
+
+    #[test]
+    fn bug_report_redacts_private_info() {
+        let mut settings = default_settings();
+        settings.connection.client_ip = Some("192.168.1.42".into());
+        let session_desc = SessionDesc {
+            last_client_handshake_packet: Some(handshake_packet("Alice's Quest")),
+            ..<_>::default()
+        };
+
+        let bundle = bug_report_bundle(&settings, &session_desc).unwrap();
+        let bundle_str = bundle.to_string();
+        assert!(!bundle_str.contains("192.168.1.42"));
+        assert!(!bundle_str.contains("Alice's Quest"));
+
+        assert_eq!(bundle["settings"]["connection"]["client_ip"], REDACTED);
+        assert_eq!(
+            bundle["last_client_handshake_packet"]["bridgevr_name"],
+            REDACTED
+        );
+        assert_eq!(bundle["server_version"], BVR_SERVER_VERSION);
+    }
+
+    #[test]
+    fn bug_report_keeps_unset_fields_unset() {
+        let mut settings = default_settings();
+        settings.connection.client_ip = None;
+
+        let bundle = bug_report_bundle(&settings, &SessionDesc::default()).unwrap();
+        assert!(bundle["settings"]["connection"]["client_ip"].is_null());
+        assert!(bundle["last_client_handshake_packet"].is_null());
+    }
+
+    fn temp_session_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "bridgevr_test_session_{}_{}.json",
+            name,
+            std::process::id()
         ))
     }
+
+    #[test]
+    fn missing_or_invalid_session_loads_defaults() {
+        let path = temp_session_path("missing");
+        assert!(SessionDescLoader::load(&path).get_mut().bitrate.is_none());
+
+        let path = temp_session_path("invalid");
+        fs::write(&path, "not json").unwrap();
+        let mut loader = SessionDescLoader::load(&path);
+        fs::remove_file(&path).ok();
+        assert!(loader.get_mut().bitrate.is_none());
+    }
+
+    #[test]
+    fn session_is_saved_to_primary_path() {
+        let path = temp_session_path("save");
+        let mut loader = SessionDescLoader::load(&path);
+        loader.get_mut().bitrate = Some(30_000_000);
+        loader.save().unwrap();
+        assert_eq!(loader.effective_path(), path.as_path());
+
+        let mut loader = SessionDescLoader::load(&path);
+        fs::remove_file(&path).ok();
+        assert_eq!(loader.get_mut().bitrate, Some(30_000_000));
+    }
+
+    #[test]
+    fn haptic_packet_with_out_of_range_device_is_rejected() {
+        let mut packet = bincode::serialize(&OtherServerPacket::Haptic {
+            device_type: TrackedDeviceType::RightController,
+            sample: HapticSample {
+                duration_seconds: 0.1,
+                frequency: 100.,
+                amplitude: 1.,
+            },
+        })
+        .unwrap();
+        assert!(matches!(
+            bincode::deserialize(&packet),
+            Ok(OtherServerPacket::Haptic {
+                device_type: TrackedDeviceType::RightController,
+                ..
+            })
+        ));
+
+        // The device type follows the packet variant index
+        packet[4..8].copy_from_slice(&1000_u32.to_le_bytes());
+        assert!(bincode::deserialize::<OtherServerPacket>(&packet).is_err());
+    }
+
+    #[test]
+    fn eye_index_round_trip() {
+        for eye in &[Eye::Left, Eye::Right] {
+            assert_eq!(Eye::from_index(eye.index() as _), Some(*eye));
+        }
+    }
+
+    #[test]
+    fn out_of_range_eye_index_is_rejected() {
+        assert_eq!(Eye::from_index(2), None);
+        assert_eq!(Eye::from_index(u32::MAX), None);
+    }
 }
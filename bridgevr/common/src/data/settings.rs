@@ -1,7 +1,9 @@
-use crate::*;
+use crate::{audio::MIN_AUDIO_PACKET_SIZE_BYTES, frame_slices::recommended_slice_count, *};
+use log::warn;
 use serde::{Deserialize, Serialize};
 use settings_schema::{
-    DictionaryDefault, OptionalDefault, SettingsSchema, Switch, SwitchDefault, VectorDefault,
+    fill_missing_json, replace_unknown_variants_json, validate_json, DictionaryDefault,
+    OptionalDefault, SettingsSchema, Switch, SwitchDefault, VectorDefault,
 };
 use std::{fs, path::*};
 
@@ -13,7 +15,38 @@ pub struct Fov {
     pub bottom: f32,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy)]
+// Step of the angles of FovKey, in degrees
+const FOV_KEY_PRECISION_DEG: f32 = 0.01;
+
+impl Fov {
+    // True if no angle differs by more than eps degrees
+    pub fn approx_eq(&self, other: &Fov, eps: f32) -> bool {
+        (self.left - other.left).abs() <= eps
+            && (self.top - other.top).abs() <= eps
+            && (self.right - other.right).abs() <= eps
+            && (self.bottom - other.bottom).abs() <= eps
+    }
+}
+
+// Fov that can be used as a HashMap key, to cache the data derived from it (projection matrices,
+// distortion meshes). The angles are rounded to FOV_KEY_PRECISION_DEG: FOVs closer than that usually
+// map to the same key, but not if they are on opposite sides of a rounding boundary.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct FovKey([i32; 4]);
+
+impl From<Fov> for FovKey {
+    fn from(fov: Fov) -> Self {
+        let quantize = |angle: f32| (angle / FOV_KEY_PRECISION_DEG).round() as i32;
+        Self([
+            quantize(fov.left),
+            quantize(fov.top),
+            quantize(fov.right),
+            quantize(fov.bottom),
+        ])
+    }
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub struct Pose {
     #[schema(step = 0.001)]
     pub position: [f32; 3],
@@ -21,7 +54,7 @@ pub struct Pose {
     pub orientation: [f32; 4],
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct SocketConfig {
     pub idle_connection_timeout_ms: Option<u64>,
     pub max_packet_size: Option<u64>,
@@ -35,7 +68,47 @@ pub struct SocketConfig {
     pub max_packets_in_flight: Option<u16>,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+// Laminar defaults, used for the unset entries
+const DEFAULT_MAX_PACKET_SIZE_BYTES: u64 = 16 * 1024;
+const DEFAULT_MAX_FRAGMENTS: u8 = 16;
+const DEFAULT_FRAGMENT_SIZE_BYTES: u16 = 1024;
+
+impl SocketConfig {
+    pub fn effective_max_packet_size(&self) -> u64 {
+        self.max_packet_size
+            .unwrap_or(DEFAULT_MAX_PACKET_SIZE_BYTES)
+    }
+}
+
+// Laminar rejects packets that need more than max_fragments fragments
+pub fn validate_max_packet_size(socket_config: &SocketConfig) -> StrResult {
+    const TRACE_CONTEXT: &str = "Settings";
+
+    let max_packet_size = socket_config.effective_max_packet_size();
+    let max_fragments = socket_config.max_fragments.unwrap_or(DEFAULT_MAX_FRAGMENTS);
+    let fragment_size = socket_config
+        .fragment_size
+        .unwrap_or(DEFAULT_FRAGMENT_SIZE_BYTES);
+    let transport_max_packet_size = max_fragments as u64 * fragment_size as u64;
+
+    if max_packet_size < MIN_AUDIO_PACKET_SIZE_BYTES as u64 {
+        trace_str!(
+            "Max packet size {} is smaller than the minimum of {}",
+            max_packet_size,
+            MIN_AUDIO_PACKET_SIZE_BYTES
+        )
+    } else if max_packet_size > transport_max_packet_size {
+        trace_str!(
+            "Max packet size {} exceeds max fragments * fragment size ({})",
+            max_packet_size,
+            transport_max_packet_size
+        )
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct ConnectionDesc {
     pub client_ip: Option<String>,
 
@@ -45,10 +118,17 @@ pub struct ConnectionDesc {
     #[schema(min = 1024)]
     pub client_port: u16,
 
+    // Video packets are split to fit in a single IP packet of this size
+    #[schema(min = 576, max = 9000)]
+    pub mtu: u16,
+
+    // Encrypt video, audio and input with ChaCha20-Poly1305. The client must support it
+    pub encrypt_data_channels: bool,
+
     pub config: SocketConfig,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum FrameSize {
     #[schema(min = 0.25, max = 1.5, step = 0.25)]
     Scale(f32),
@@ -59,8 +139,62 @@ pub enum FrameSize {
     },
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy)]
+// Most hardware encoders require even frame dimensions. Odd values can crash NVENC.
+pub const FRAME_SIZE_ALIGNMENT: u32 = 2;
+
+const MAX_ASPECT_RATIO_DIVERGENCE: f32 = 0.01;
+
+fn align_up(value: u32, alignment: u32) -> u32 {
+    value.div_ceil(alignment) * alignment
+}
+
+fn aspect_ratio_diverges(resolution: (u32, u32), native_resolution: (u32, u32)) -> bool {
+    let aspect_ratio = resolution.0 as f32 / resolution.1 as f32;
+    let native_aspect_ratio = native_resolution.0 as f32 / native_resolution.1 as f32;
+    (aspect_ratio / native_aspect_ratio - 1.).abs() > MAX_ASPECT_RATIO_DIVERGENCE
+}
+
+impl FrameSize {
+    // Round `Absolute` dimensions up to FRAME_SIZE_ALIGNMENT. `Scale` is resolved only when the
+    // client native resolution is known, see `target_eye_resolution()`.
+    pub fn aligned(self) -> Self {
+        match self {
+            FrameSize::Scale(scale) => FrameSize::Scale(scale),
+            FrameSize::Absolute { width, height } => FrameSize::Absolute {
+                width: align_up(width, FRAME_SIZE_ALIGNMENT),
+                height: align_up(height, FRAME_SIZE_ALIGNMENT),
+            },
+        }
+    }
+
+    pub fn target_eye_resolution(self, native_eye_resolution: (u32, u32)) -> (u32, u32) {
+        let (native_width, native_height) = native_eye_resolution;
+        let (width, height) = match self {
+            FrameSize::Scale(scale) => (
+                (native_width as f32 * scale) as u32,
+                (native_height as f32 * scale) as u32,
+            ),
+            FrameSize::Absolute { width, height } => {
+                if aspect_ratio_diverges((width, height), native_eye_resolution) {
+                    warn!(
+                        "Frame size {}x{} does not match the client native aspect ratio ({}x{})",
+                        width, height, native_width, native_height
+                    );
+                }
+                (width, height)
+            }
+        };
+
+        (
+            align_up(width, FRAME_SIZE_ALIGNMENT),
+            align_up(height, FRAME_SIZE_ALIGNMENT),
+        )
+    }
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum CompositionFilteringType {
+    #[schema(name = "Nearest neighbour")]
     NearestNeighbour,
 
     Bilinear,
@@ -69,19 +203,26 @@ pub enum CompositionFilteringType {
     Lanczos(f32),
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub struct FoveatedRenderingDesc {
     #[schema(min = 0.5, max = 10., step = 0.1)]
-    strength: f32,
+    pub strength: f32,
 
     #[schema(advanced, min = 0.5, max = 2., step = 0.1)]
-    shape_ratio: f32,
+    pub shape_ratio: f32,
 
     #[schema(min = -0.05, max = 0.05, step = 0.001)]
-    vertical_offset: f32,
+    pub vertical_offset: f32,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct FoveatedSliceBitrateDesc {
+    // Fraction of the equal share of bitrate that each slice is guaranteed to get
+    #[schema(min = 0., max = 1., step = 0.01)]
+    pub min_slice_share: f32,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone, Debug)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub enum FfmpegOptionValue {
     String(String),
     Int(i64),
@@ -94,7 +235,17 @@ pub enum FfmpegOptionValue {
     Dictionary(Vec<(String, String)>),
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum VideoPixelFormat {
+    NV12,
+
+    // Requires an encoder with 10 bit support
+    P010,
+
+    RGBA,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct VideoCodecDesc {
     pub codec_name: String,
     pub context_options: Vec<(String, FfmpegOptionValue)>,
@@ -104,20 +255,60 @@ pub struct VideoCodecDesc {
     pub hw_frames_context_options: Vec<(String, FfmpegOptionValue)>,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum VideoEncoderPreset {
+    UltraLowLatency,
+    Balanced,
+    Quality,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct VideoEncoderDesc {
+    // Expanded into private data options. Options set explicitly in priv_data_options override the
+    // ones with the same name set by the preset.
+    pub preset: VideoEncoderPreset,
+
     pub linux_windows_amd: VideoCodecDesc,
     pub linux_windows_nvidia: VideoCodecDesc,
     pub macos: VideoCodecDesc,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GpuVendor {
+    Amd,
+    Nvidia,
+    Other,
+}
+
+impl GpuVendor {
+    pub fn from_pci_vendor_id(vendor_id: usize) -> Self {
+        match vendor_id {
+            0x1002 => GpuVendor::Amd,
+            0x10de => GpuVendor::Nvidia,
+            _ => GpuVendor::Other,
+        }
+    }
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct VideoDecoderDesc {
     pub android: VideoCodecDesc,
     pub windows: VideoCodecDesc,
+
+    // Frames received ahead of the oldest incomplete one before this is given up
+    #[schema(min = 1, max = 16, gui = "UpDown")]
+    pub reorder_window_frames: u32,
+
+    // An incomplete frame is dropped if its slices are not received within this time
+    #[schema(min = 1, gui = "UpDown")]
+    pub reorder_timeout_ms: u64,
+
+    // Frames older than this (since encoding) when ready to be decoded are dropped
+    #[schema(min = 1, gui = "UpDown")]
+    pub max_decode_latency_ms: u64,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub enum LatencyMode {
     Automatic {
         #[schema(min = 1, gui = "UpDown")]
@@ -126,7 +317,7 @@ pub enum LatencyMode {
     Manual,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct LatencyDesc {
     // todo: when the users set this to 0, show message:
     // "BridgeVR cannot do magic! A value greater than 0 is needed to avoid missing frames"
@@ -139,7 +330,16 @@ pub struct LatencyDesc {
     pub mode: LatencyMode,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum FrameSliceCount {
+    // Chosen from the resolution, frame rate and bitrate, see recommended_slice_count()
+    Automatic,
+
+    #[schema(min = 1, max = 8, gui = "UpDown")]
+    Manual(u8),
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct VideoDesc {
     pub frame_size: FrameSize,
 
@@ -152,14 +352,46 @@ pub struct VideoDesc {
     #[schema(advanced)]
     pub composition_filtering: CompositionFilteringType,
 
+    // Applied after the eye layers are resampled. Strength in [0, 1]
+    #[schema(advanced, min = 0., max = 1., step = 0.01)]
+    pub sharpening: Switch<f32>,
+
     pub foveated_rendering: Switch<FoveatedRenderingDesc>,
 
-    #[schema(advanced, min = 1, max = 8, gui = "UpDown")]
-    pub frame_slice_count: u8,
+    #[schema(advanced)]
+    pub frame_slice_count: FrameSliceCount,
+
+    // Send each slice as soon as it is encoded, instead of waiting for all the slices of the frame.
+    // Lowers the latency but spreads the packets of a frame over a longer time
+    #[schema(advanced)]
+    pub progressive_slices: bool,
+
+    // Strict limit of the frames between two keyframes, so that the stream recovers from a loss in a
+    // bounded time. Used as the GOP size of the encoder, so no frame references across the boundary.
+    // 0: no limit
+    #[schema(advanced, gui = "UpDown")]
+    pub max_gop: u32,
+
+    // Used only if foveated rendering is enabled
+    #[schema(advanced)]
+    pub foveated_slice_bitrate: Switch<FoveatedSliceBitrateDesc>,
+
+    #[schema(advanced)]
+    pub pixel_format: VideoPixelFormat,
 
     #[schema(advanced)]
     pub encoder: VideoEncoderDesc,
 
+    // Used if the encoder fails to open, for example if the driver is too old or the session limit
+    // is reached. Usually a software encoder
+    #[schema(advanced)]
+    pub encoder_fallback: Switch<VideoEncoderDesc>,
+
+    // Bitrate factor for the first frame after a scene change, that would otherwise be as large as
+    // a keyframe. The quality recovers over the next frames
+    #[schema(advanced, min = 0.1, max = 1., step = 0.05)]
+    pub scene_change_bitrate_scale: Switch<f32>,
+
     #[schema(advanced)]
     pub decoder: VideoDecoderDesc,
 
@@ -174,14 +406,14 @@ pub struct VideoDesc {
     pub reliable: bool,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub enum AudioFormat {
     Bit8,
     Bit16,
     Bit24,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct AudioDesc {
     #[schema(advanced)]
     pub input_device_index: Option<u64>,
@@ -222,20 +454,20 @@ pub enum TrackedDeviceType {
     GenericTracker12,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct TrackedDeviceDesc {
     pub device_type: TrackedDeviceType,
     pub default_pose: Pose,
     pub pose_offset: Pose,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
 pub enum CompositorType {
     Custom,
     SteamVR,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone, Debug)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub enum OpenvrPropValue {
     Bool(bool),
     Int32(i32),
@@ -246,7 +478,80 @@ pub enum OpenvrPropValue {
     Double(f64),
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OpenvrPropValueType {
+    Bool,
+    Int32,
+    Uint64,
+    Float,
+    String,
+    Vector3,
+    Double,
+}
+
+impl OpenvrPropValue {
+    pub fn value_type(&self) -> OpenvrPropValueType {
+        match self {
+            OpenvrPropValue::Bool(_) => OpenvrPropValueType::Bool,
+            OpenvrPropValue::Int32(_) => OpenvrPropValueType::Int32,
+            OpenvrPropValue::Uint64(_) => OpenvrPropValueType::Uint64,
+            OpenvrPropValue::Float(_) => OpenvrPropValueType::Float,
+            OpenvrPropValue::String(_) => OpenvrPropValueType::String,
+            OpenvrPropValue::Vector3(_) => OpenvrPropValueType::Vector3,
+            OpenvrPropValue::Double(_) => OpenvrPropValueType::Double,
+        }
+    }
+}
+
+impl From<bool> for OpenvrPropValue {
+    fn from(value: bool) -> Self {
+        OpenvrPropValue::Bool(value)
+    }
+}
+
+impl From<i32> for OpenvrPropValue {
+    fn from(value: i32) -> Self {
+        OpenvrPropValue::Int32(value)
+    }
+}
+
+impl From<u64> for OpenvrPropValue {
+    fn from(value: u64) -> Self {
+        OpenvrPropValue::Uint64(value)
+    }
+}
+
+impl From<f32> for OpenvrPropValue {
+    fn from(value: f32) -> Self {
+        OpenvrPropValue::Float(value)
+    }
+}
+
+impl From<String> for OpenvrPropValue {
+    fn from(value: String) -> Self {
+        OpenvrPropValue::String(value)
+    }
+}
+
+impl From<&str> for OpenvrPropValue {
+    fn from(value: &str) -> Self {
+        OpenvrPropValue::String(value.into())
+    }
+}
+
+impl From<[f32; 3]> for OpenvrPropValue {
+    fn from(value: [f32; 3]) -> Self {
+        OpenvrPropValue::Vector3(value)
+    }
+}
+
+impl From<f64> for OpenvrPropValue {
+    fn from(value: f64) -> Self {
+        OpenvrPropValue::Double(value)
+    }
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub enum OpenvrInputType {
     Boolean,
     NormalizedOneSided,
@@ -254,42 +559,81 @@ pub enum OpenvrInputType {
     Skeletal,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct OpenvrInputValue {
     pub input_type: OpenvrInputType,
     pub source_paths: Vec<String>,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct OpenvrTrackedDeviceDesc {
     pub device_type: TrackedDeviceType,
     pub properties: Vec<(String, OpenvrPropValue)>,
     pub input_mapping: Vec<(String, OpenvrInputValue)>,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct MotionPredictionDesc {
+    // If disabled, the velocities are not sent to SteamVR, which then does not extrapolate the pose
+    pub enabled: bool,
+
+    // 0: no smoothing. Higher values reduce jitter of noisy velocities but add lag
+    #[schema(min = 0., max = 0.95, step = 0.01)]
+    pub velocity_smoothing: f32,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct OpenvrDesc {
     pub custom_fov: Option<[Fov; 2]>,
     pub tracked_devices: Vec<OpenvrTrackedDeviceDesc>,
+
+    // Disable the HMD to use BridgeVR only as a controller bridge, or disable the controllers to
+    // use it only for the HMD. Devices in tracked_devices that are not enabled are not registered
+    pub enable_hmd: bool,
+
+    // Left and right controller
+    pub enable_controllers: [bool; 2],
+
+    // Left and right controller. If unset, the render model is chosen by SteamVR. Overridden by
+    // Prop_RenderModelName_String in tracked_devices
+    pub controller_render_model: [Option<String>; 2],
+
     pub block_standby: bool,
     pub server_idle_timeout_s: u64,
     pub preferred_render_eye_resolution: Option<FrameSize>,
+
+    // Refresh rate of the virtual display. If not set, the client refresh rate is used
+    #[schema(min = 1, max = 240, gui = "UpDown")]
+    pub refresh_rate_override: Option<u32>,
+
     pub compositor_type: CompositorType,
+    pub hmd_motion_prediction: MotionPredictionDesc,
+
+    // Used only for 3DoF headsets. If disabled, the HMD position is fixed
+    pub hmd_neck_model: bool,
+
+    // Used for all the tracked devices other than the HMD
+    pub controllers_motion_prediction: MotionPredictionDesc,
+
+    // Maximum wait for the frame textures to be available and for the frame to be presented. Slow
+    // GPUs may need a higher value
+    #[schema(advanced, min = 10, max = 5000, gui = "UpDown")]
+    pub present_timeout_ms: u64,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct VrServerDesc {
     pub openvr: OpenvrDesc,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub enum HmdTrackingMode {
     Absolute,
     XYRelativeZAbsolute,
     Relative,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct OvrMobileDesc {
     #[schema(min = 0, max = 3, gui = "Slider")]
     pub cpu_level: i32,
@@ -300,18 +644,18 @@ pub struct OvrMobileDesc {
     pub dynamic_clock_throttling: bool,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct OpenxrDesc {
     pub hmd_tracking_mode: HmdTrackingMode,
     pub ovr_mobile: OvrMobileDesc,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct VrClientDesc {
     pub openxr: OpenxrDesc,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Settings {
     #[schema(advanced)]
     pub connection: ConnectionDesc,
@@ -332,9 +676,466 @@ pub struct Settings {
     pub vr_client: VrClientDesc,
 }
 
+fn validate_frame_size(frame_size: FrameSize) -> FrameSize {
+    let aligned_frame_size = frame_size.aligned();
+    if let (
+        FrameSize::Absolute { width, height },
+        FrameSize::Absolute {
+            width: aligned_width,
+            height: aligned_height,
+        },
+    ) = (frame_size, aligned_frame_size)
+    {
+        if (width, height) != (aligned_width, aligned_height) {
+            warn!(
+                "Frame size {}x{} rounded to {}x{}",
+                width, height, aligned_width, aligned_height
+            );
+        }
+    }
+
+    aligned_frame_size
+}
+
+fn preset_priv_data_options(
+    preset: VideoEncoderPreset,
+    codec_name: &str,
+) -> Vec<(&'static str, FfmpegOptionValue)> {
+    use FfmpegOptionValue::*;
+    use VideoEncoderPreset::*;
+
+    let string = |value: &str| String(value.into());
+
+    if codec_name.ends_with("_nvenc") {
+        let (nv_preset, rc) = match preset {
+            UltraLowLatency => ("llhp", "cbr"),
+            Balanced => ("ll", "cbr_ld_hq"),
+            Quality => ("llhq", "cbr_ld_hq"),
+        };
+        vec![
+            ("preset", string(nv_preset)),
+            ("rc", string(rc)),
+            ("zerolatency", Int(1)),
+            ("delay", Int(0)),
+        ]
+    } else if codec_name.ends_with("_amf") {
+        let (usage, quality) = match preset {
+            UltraLowLatency => ("ultralowlatency", "speed"),
+            Balanced => ("ultralowlatency", "balanced"),
+            Quality => ("lowlatency", "quality"),
+        };
+        vec![
+            ("usage", string(usage)),
+            ("quality", string(quality)),
+            ("rc", string("cbr")),
+        ]
+    } else if codec_name.ends_with("_videotoolbox") {
+        let realtime = if preset == Quality { 0 } else { 1 };
+        vec![("realtime", Int(realtime))]
+    } else {
+        vec![]
+    }
+}
+
+// Private data options of the preset for the codec merged with the explicit ones. Explicit options
+// take precedence.
+pub fn effective_priv_data_options(
+    preset: VideoEncoderPreset,
+    codec_desc: &VideoCodecDesc,
+) -> Vec<(String, FfmpegOptionValue)> {
+    let mut options = preset_priv_data_options(preset, &codec_desc.codec_name)
+        .into_iter()
+        .filter(|(name, _)| {
+            !codec_desc
+                .priv_data_options
+                .iter()
+                .any(|(explicit_name, _)| explicit_name == name)
+        })
+        .map(|(name, value)| (name.to_owned(), value))
+        .collect::<Vec<_>>();
+    options.extend(codec_desc.priv_data_options.iter().cloned());
+
+    options
+}
+
+// Codecs with unknown names are assumed to be 8 bit only.
+fn codec_supports_10_bit(codec_name: &str) -> bool {
+    codec_name.starts_with("hevc") || codec_name.contains("265") || codec_name.contains("av1")
+}
+
+// Codec used on this platform with the given GPU. GPUs other than AMD use the NVIDIA codec.
+pub fn platform_encoder_codec_desc(
+    encoder_desc: &VideoEncoderDesc,
+    gpu_vendor: GpuVendor,
+) -> &VideoCodecDesc {
+    if cfg!(target_os = "macos") {
+        &encoder_desc.macos
+    } else if gpu_vendor == GpuVendor::Amd {
+        &encoder_desc.linux_windows_amd
+    } else {
+        &encoder_desc.linux_windows_nvidia
+    }
+}
+
+// `gpu_vendor` is None if the graphics adapter is not known yet. In that case the check fails only
+// if no codec of this platform supports 10 bit.
+pub fn validate_pixel_format(
+    pixel_format: VideoPixelFormat,
+    encoder_desc: &VideoEncoderDesc,
+    gpu_vendor: Option<GpuVendor>,
+) -> StrResult {
+    const TRACE_CONTEXT: &str = "Settings";
+
+    if pixel_format == VideoPixelFormat::P010 {
+        let platform_codec_descs = match gpu_vendor {
+            Some(gpu_vendor) => vec![platform_encoder_codec_desc(encoder_desc, gpu_vendor)],
+            None => vec![
+                platform_encoder_codec_desc(encoder_desc, GpuVendor::Amd),
+                platform_encoder_codec_desc(encoder_desc, GpuVendor::Nvidia),
+            ],
+        };
+        if !platform_codec_descs
+            .iter()
+            .any(|codec_desc| codec_supports_10_bit(&codec_desc.codec_name))
+        {
+            return trace_str!(
+                "Pixel format P010 is not supported by encoder {}",
+                platform_codec_descs[0].codec_name
+            );
+        }
+
+        // The other codecs matter if the settings are copied to another machine
+        for codec_desc in &[
+            &encoder_desc.linux_windows_amd,
+            &encoder_desc.linux_windows_nvidia,
+            &encoder_desc.macos,
+        ] {
+            if !codec_supports_10_bit(&codec_desc.codec_name) {
+                warn!(
+                    "Pixel format P010 is not supported by encoder {}",
+                    codec_desc.codec_name
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// A zero denominator is not caught by FFmpeg and results in a division by zero when the option is
+// used.
+pub fn validate_codec_options(codec_desc: &VideoCodecDesc) -> StrResult {
+    const TRACE_CONTEXT: &str = "Settings";
+
+    for (key, value) in codec_desc
+        .context_options
+        .iter()
+        .chain(&codec_desc.priv_data_options)
+        .chain(&codec_desc.frame_options)
+        .chain(&codec_desc.hw_frames_context_options)
+    {
+        if let FfmpegOptionValue::Rational { den: 0, .. }
+        | FfmpegOptionValue::VideoRate { den: 0, .. } = value
+        {
+            return trace_str!(
+                "Option \"{}\" of codec \"{}\" has a zero denominator",
+                key,
+                codec_desc.codec_name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Taken from the "b" context option. None: the codec default is used
+fn codec_bitrate_bps(codec_desc: &VideoCodecDesc) -> StrResult<Option<i64>> {
+    const TRACE_CONTEXT: &str = "Settings";
+
+    match codec_desc
+        .context_options
+        .iter()
+        .find(|(name, _)| name == "b")
+    {
+        Some((_, FfmpegOptionValue::Int(bitrate_bps))) if *bitrate_bps > 0 => {
+            Ok(Some(*bitrate_bps))
+        }
+        Some(_) => trace_str!("Bitrate option \"b\" must be a positive integer"),
+        None => Ok(None),
+    }
+}
+
+// Everything needed to open an FFmpeg encoder, validated.
+#[derive(Clone, PartialEq, Debug)]
+pub struct EncoderConfig {
+    pub resolution: (u32, u32),
+    pub fps: u32,
+    pub codec_name: String,
+    pub pixel_format: VideoPixelFormat,
+    // Taken from the "b" context option. None: the codec default is used
+    pub bitrate_bps: Option<i64>,
+    // From VideoDesc::scene_change_bitrate_scale
+    pub scene_change_bitrate_scale: Option<f32>,
+    // From VideoDesc::max_gop, to be used as the GOP size of the codec context. None: no limit
+    pub max_gop: Option<u32>,
+    pub context_options: Vec<(String, FfmpegOptionValue)>,
+    // The preset is already applied
+    pub priv_data_options: Vec<(String, FfmpegOptionValue)>,
+    pub codec_open_options: Vec<(String, String)>,
+    pub frame_options: Vec<(String, FfmpegOptionValue)>,
+    pub hw_frames_context_options: Vec<(String, FfmpegOptionValue)>,
+}
+
+impl VideoDesc {
+    // Resolved once per connection, because the client needs it to reassemble the frames
+    pub fn resolve_frame_slice_count(
+        &self,
+        target_eye_resolution: (u32, u32),
+        fps: u32,
+        gpu_vendor: GpuVendor,
+    ) -> StrResult<u8> {
+        match self.frame_slice_count {
+            FrameSliceCount::Automatic => {
+                let bitrate_bps =
+                    codec_bitrate_bps(platform_encoder_codec_desc(&self.encoder, gpu_vendor))?;
+                let (eye_width, eye_height) = target_eye_resolution;
+
+                Ok(recommended_slice_count((eye_width * 2, eye_height), fps, bitrate_bps) as _)
+            }
+            FrameSliceCount::Manual(count) => Ok(count),
+        }
+    }
+
+    pub fn to_encoder_config(
+        &self,
+        target_resolution: (u32, u32),
+        fps: u32,
+        gpu_vendor: GpuVendor,
+    ) -> StrResult<EncoderConfig> {
+        self.encoder_config(&self.encoder, target_resolution, fps, gpu_vendor)
+    }
+
+    // None if the fallback encoder is disabled
+    pub fn to_fallback_encoder_config(
+        &self,
+        target_resolution: (u32, u32),
+        fps: u32,
+        gpu_vendor: GpuVendor,
+    ) -> StrResult<Option<EncoderConfig>> {
+        match &self.encoder_fallback {
+            Switch::Enabled(encoder_desc) => self
+                .encoder_config(encoder_desc, target_resolution, fps, gpu_vendor)
+                .map(Some),
+            Switch::Disabled => Ok(None),
+        }
+    }
+
+    fn encoder_config(
+        &self,
+        encoder_desc: &VideoEncoderDesc,
+        target_resolution: (u32, u32),
+        fps: u32,
+        gpu_vendor: GpuVendor,
+    ) -> StrResult<EncoderConfig> {
+        const TRACE_CONTEXT: &str = "Settings";
+
+        let (width, height) = target_resolution;
+        if width == 0 || height == 0 || width % 2 != 0 || height % 2 != 0 {
+            return trace_str!(
+                "Encoder resolution {}x{} must be non zero and even",
+                width,
+                height
+            );
+        }
+
+        if fps == 0 {
+            return trace_str!("Encoder frame rate must be non zero");
+        }
+
+        let codec_desc = platform_encoder_codec_desc(encoder_desc, gpu_vendor);
+        if codec_desc.codec_name.is_empty() {
+            return trace_str!("Encoder codec not set");
+        }
+
+        if self.pixel_format == VideoPixelFormat::P010
+            && !codec_supports_10_bit(&codec_desc.codec_name)
+        {
+            return trace_str!(
+                "Pixel format P010 is not supported by encoder {}",
+                codec_desc.codec_name
+            );
+        }
+
+        validate_codec_options(codec_desc)?;
+
+        let bitrate_bps = codec_bitrate_bps(codec_desc)?;
+
+        Ok(EncoderConfig {
+            resolution: target_resolution,
+            fps,
+            codec_name: codec_desc.codec_name.clone(),
+            pixel_format: self.pixel_format,
+            bitrate_bps,
+            scene_change_bitrate_scale: self.scene_change_bitrate_scale.clone().into_option(),
+            max_gop: Some(self.max_gop).filter(|max_gop| *max_gop > 0),
+            context_options: codec_desc.context_options.clone(),
+            priv_data_options: effective_priv_data_options(encoder_desc.preset, codec_desc),
+            codec_open_options: codec_desc.codec_open_options.clone(),
+            frame_options: codec_desc.frame_options.clone(),
+            hw_frames_context_options: codec_desc.hw_frames_context_options.clone(),
+        })
+    }
+}
+
+impl OpenvrDesc {
+    // Devices other than the HMD and the controllers are always enabled
+    pub fn is_tracked_device_enabled(&self, device_type: TrackedDeviceType) -> bool {
+        match device_type {
+            TrackedDeviceType::HMD => self.enable_hmd,
+            TrackedDeviceType::LeftController => self.enable_controllers[0],
+            TrackedDeviceType::RightController => self.enable_controllers[1],
+            _ => true,
+        }
+    }
+}
+
+pub fn validate_enabled_tracked_devices(openvr_desc: &OpenvrDesc) -> StrResult {
+    const TRACE_CONTEXT: &str = "Settings";
+
+    if !openvr_desc.enable_hmd && openvr_desc.enable_controllers == [false, false] {
+        return trace_str!("At least one between the HMD and the controllers must be enabled");
+    }
+
+    Ok(())
+}
+
+pub fn validate_controller_render_model(openvr_desc: &OpenvrDesc) -> StrResult {
+    const TRACE_CONTEXT: &str = "Settings";
+
+    for (index, (hand, device_type)) in [
+        ("Left", TrackedDeviceType::LeftController),
+        ("Right", TrackedDeviceType::RightController),
+    ]
+    .iter()
+    .enumerate()
+    {
+        let overridden = openvr_desc.tracked_devices.iter().any(|td| {
+            td.device_type == *device_type
+                && td
+                    .properties
+                    .iter()
+                    .any(|(name, _)| name == "Prop_RenderModelName_String")
+        });
+        if let Some(render_model) = &openvr_desc.controller_render_model[index] {
+            if openvr_desc.enable_controllers[index]
+                && render_model.trim().is_empty()
+                && !overridden
+            {
+                return trace_str!("{} controller render model name is empty", hand);
+            }
+        }
+    }
+
+    Ok(())
+}
+
 pub fn load_settings(path: &Path) -> StrResult<Settings> {
     const TRACE_CONTEXT: &str = "Settings";
-    trace_err!(serde_json::from_str(&trace_err!(fs::read_to_string(path))?))
+    let settings_json_str = fs::read_to_string(path).trace_ctx(TRACE_CONTEXT)?;
+    let mut settings_json: serde_json::Value =
+        serde_json::from_str(&settings_json_str).trace_ctx(TRACE_CONTEXT)?;
+
+    // The frame slice count used to be a plain number
+    if let Some(count) = settings_json.pointer_mut("/video/frame_slice_count") {
+        if count.is_u64() {
+            *count = serde_json::json!({ "Manual": count.take() });
+        }
+    }
+
+    let schema = settings_schema(settings_default());
+
+    // Settings files saved by a newer version can contain unknown enum variants
+    let mut replaced_paths = vec![];
+    replace_unknown_variants_json(&schema, &mut settings_json, "root", &mut replaced_paths);
+    if !replaced_paths.is_empty() {
+        warn!(
+            "Settings file contains options unknown to this version, using the defaults for: {}",
+            replaced_paths.join(", ")
+        );
+    }
+
+    // Settings files saved by an older version can miss the newer entries
+    let mut filled_paths = vec![];
+    fill_missing_json(&schema, &mut settings_json, "root", &mut filled_paths);
+    if !filled_paths.is_empty() {
+        warn!(
+            "Settings file is missing some entries, using the defaults for: {}",
+            filled_paths.join(", ")
+        );
+    }
+
+    // Bounds are enforced by the GUI only, a hand edited file can go past them
+    let mut bounds_errors = vec![];
+    validate_json(&schema, &settings_json, "root", &mut bounds_errors);
+    if !bounds_errors.is_empty() {
+        return trace_str!("Invalid settings: {}", bounds_errors.join(", "));
+    }
+
+    let mut settings: Settings = trace_err!(serde_json::from_value(settings_json))?;
+
+    settings.video.frame_size = validate_frame_size(settings.video.frame_size);
+    if let Some(frame_size) = &mut settings.vr_server.openvr.preferred_render_eye_resolution {
+        *frame_size = validate_frame_size(*frame_size);
+    }
+
+    validate_pixel_format(settings.video.pixel_format, &settings.video.encoder, None)?;
+
+    for codec_desc in &[
+        &settings.video.encoder.linux_windows_amd,
+        &settings.video.encoder.linux_windows_nvidia,
+        &settings.video.encoder.macos,
+        &settings.video.decoder.android,
+        &settings.video.decoder.windows,
+    ] {
+        validate_codec_options(codec_desc)?;
+    }
+
+    if let Switch::Enabled(encoder_fallback) = &settings.video.encoder_fallback {
+        validate_pixel_format(settings.video.pixel_format, encoder_fallback, None)?;
+
+        for codec_desc in &[
+            &encoder_fallback.linux_windows_amd,
+            &encoder_fallback.linux_windows_nvidia,
+            &encoder_fallback.macos,
+        ] {
+            validate_codec_options(codec_desc)?;
+        }
+    }
+
+    validate_enabled_tracked_devices(&settings.vr_server.openvr)?;
+    validate_controller_render_model(&settings.vr_server.openvr)?;
+
+    validate_max_packet_size(&settings.connection.config)?;
+
+    Ok(settings)
+}
+
+// Settings shipped with the driver, generated from settings_default()
+pub fn default_settings() -> Settings {
+    // unwrap never fails: the file is generated together with the schema
+    serde_json::from_str(include_str!("../../../../settings.json")).unwrap()
+}
+
+// The driver must start even if the settings file is missing or corrupted
+pub fn load_settings_or_default(path: &Path) -> Settings {
+    load_settings(path).unwrap_or_else(|e| {
+        warn!(
+            "Cannot load settings file {}, using the default settings. {}",
+            path.display(),
+            e
+        );
+        default_settings()
+    })
 }
 
 pub fn settings_default() -> SettingsDefault {
@@ -361,6 +1162,35 @@ pub fn settings_default() -> SettingsDefault {
         },
     };
 
+    let software_codec_desc = VideoCodecDescDefault {
+        codec_name: "libx264".into(),
+        context_options: DictionaryDefault {
+            key: "".into(),
+            value: default_ffmpeg_option_value.clone(),
+            default: vec![],
+        },
+        priv_data_options: DictionaryDefault {
+            key: "".into(),
+            value: default_ffmpeg_option_value.clone(),
+            default: vec![],
+        },
+        codec_open_options: DictionaryDefault {
+            key: "".into(),
+            value: "".into(),
+            default: vec![],
+        },
+        frame_options: DictionaryDefault {
+            key: "".into(),
+            value: default_ffmpeg_option_value.clone(),
+            default: vec![],
+        },
+        hw_frames_context_options: DictionaryDefault {
+            key: "".into(),
+            value: default_ffmpeg_option_value.clone(),
+            default: vec![],
+        },
+    };
+
     let default_pose = Pose {
         position: [0.; 3],
         orientation: [1., 0., 0., 0.],
@@ -374,6 +1204,8 @@ pub fn settings_default() -> SettingsDefault {
             },
             server_port: 9944,
             client_port: 9944,
+            mtu: 1400,
+            encrypt_data_channels: false,
             config: SocketConfigDefault {
                 idle_connection_timeout_ms: OptionalDefault {
                     set: true,
@@ -448,6 +1280,10 @@ pub fn settings_default() -> SettingsDefault {
                 variant: CompositionFilteringTypeDefaultVariant::Bilinear,
                 Lanczos: 2.5,
             },
+            sharpening: SwitchDefault {
+                enabled: false,
+                content: 0.5,
+            },
             foveated_rendering: SwitchDefault {
                 enabled: false,
                 content: FoveatedRenderingDescDefault {
@@ -456,8 +1292,25 @@ pub fn settings_default() -> SettingsDefault {
                     vertical_offset: 0.,
                 },
             },
-            frame_slice_count: 1,
+            frame_slice_count: FrameSliceCountDefault {
+                variant: FrameSliceCountDefaultVariant::Automatic,
+                Manual: 1,
+            },
+            progressive_slices: false,
+            max_gop: 0,
+            foveated_slice_bitrate: SwitchDefault {
+                enabled: false,
+                content: FoveatedSliceBitrateDescDefault {
+                    min_slice_share: 0.25,
+                },
+            },
+            pixel_format: VideoPixelFormatDefault {
+                variant: VideoPixelFormatDefaultVariant::NV12,
+            },
             encoder: VideoEncoderDescDefault {
+                preset: VideoEncoderPresetDefault {
+                    variant: VideoEncoderPresetDefaultVariant::UltraLowLatency,
+                },
                 linux_windows_amd: VideoCodecDescDefault {
                     codec_name: "".into(),
                     context_options: DictionaryDefault {
@@ -543,7 +1396,25 @@ pub fn settings_default() -> SettingsDefault {
                     },
                 },
             },
+            encoder_fallback: SwitchDefault {
+                enabled: false,
+                content: VideoEncoderDescDefault {
+                    preset: VideoEncoderPresetDefault {
+                        variant: VideoEncoderPresetDefaultVariant::UltraLowLatency,
+                    },
+                    linux_windows_amd: software_codec_desc.clone(),
+                    linux_windows_nvidia: software_codec_desc.clone(),
+                    macos: software_codec_desc,
+                },
+            },
+            scene_change_bitrate_scale: SwitchDefault {
+                enabled: false,
+                content: 0.5,
+            },
             decoder: VideoDecoderDescDefault {
+                reorder_window_frames: 3,
+                reorder_timeout_ms: 50,
+                max_decode_latency_ms: 100,
                 android: VideoCodecDescDefault {
                     codec_name: "".into(),
                     context_options: DictionaryDefault {
@@ -783,6 +1654,18 @@ pub fn settings_default() -> SettingsDefault {
                         },
                     ],
                 },
+                enable_hmd: true,
+                enable_controllers: [true, true],
+                controller_render_model: [
+                    OptionalDefault {
+                        set: false,
+                        content: "oculus_quest_controller_left".into(),
+                    },
+                    OptionalDefault {
+                        set: false,
+                        content: "oculus_quest_controller_right".into(),
+                    },
+                ],
                 block_standby: false,
                 server_idle_timeout_s: 60,
                 preferred_render_eye_resolution: OptionalDefault {
@@ -796,9 +1679,23 @@ pub fn settings_default() -> SettingsDefault {
                         },
                     },
                 },
+                refresh_rate_override: OptionalDefault {
+                    set: false,
+                    content: 72,
+                },
                 compositor_type: CompositorTypeDefault {
                     variant: CompositorTypeDefaultVariant::Custom,
                 },
+                hmd_motion_prediction: MotionPredictionDescDefault {
+                    enabled: true,
+                    velocity_smoothing: 0.,
+                },
+                hmd_neck_model: true,
+                controllers_motion_prediction: MotionPredictionDescDefault {
+                    enabled: true,
+                    velocity_smoothing: 0.3,
+                },
+                present_timeout_ms: 500,
             },
         },
         vr_client: VrClientDescDefault {
@@ -815,3 +1712,624 @@ pub fn settings_default() -> SettingsDefault {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn aligned_rounds_odd_dimensions_up() {
+        let odd = FrameSize::Absolute {
+            width: 1001,
+            height: 999,
+        };
+        assert!(matches!(
+            odd.aligned(),
+            FrameSize::Absolute {
+                width: 1002,
+                height: 1000
+            }
+        ));
+
+        let even = FrameSize::Absolute {
+            width: 1440,
+            height: 1600,
+        };
+        assert!(matches!(
+            even.aligned(),
+            FrameSize::Absolute {
+                width: 1440,
+                height: 1600
+            }
+        ));
+
+        assert!(matches!(
+            FrameSize::Scale(0.75).aligned(),
+            FrameSize::Scale(scale) if scale == 0.75
+        ));
+    }
+
+    #[test]
+    fn target_eye_resolution_is_even() {
+        assert_eq!(
+            FrameSize::Scale(1.).target_eye_resolution((1441, 1599)),
+            (1442, 1600)
+        );
+        assert_eq!(
+            FrameSize::Scale(0.5).target_eye_resolution((1440, 1600)),
+            (720, 800)
+        );
+        assert_eq!(
+            FrameSize::Absolute {
+                width: 721,
+                height: 801
+            }
+            .target_eye_resolution((1440, 1600)),
+            (722, 802)
+        );
+    }
+
+    #[test]
+    fn aspect_ratio_divergence() {
+        assert!(!aspect_ratio_diverges((720, 800), (1440, 1600)));
+        // Within MAX_ASPECT_RATIO_DIVERGENCE
+        assert!(!aspect_ratio_diverges((1441, 1600), (1440, 1600)));
+        assert!(aspect_ratio_diverges((1920, 1080), (1440, 1600)));
+    }
+
+    #[test]
+    fn aspect_ratio_divergence_is_reported() {
+        let (_, records) = logging::capture_logs(|| {
+            FrameSize::Absolute {
+                width: 1920,
+                height: 1080,
+            }
+            .target_eye_resolution((1440, 1600))
+        });
+        assert_eq!(
+            records,
+            vec![(
+                log::Level::Warn,
+                "Frame size 1920x1080 does not match the client native aspect ratio (1440x1600)"
+                    .to_owned()
+            )]
+        );
+
+        let (_, records) = logging::capture_logs(|| {
+            FrameSize::Absolute {
+                width: 720,
+                height: 800,
+            }
+            .target_eye_resolution((1440, 1600))
+        });
+        assert!(records.is_empty());
+    }
+
+    #[test]
+    fn openvr_prop_value_conversions() {
+        let values: Vec<(OpenvrPropValue, OpenvrPropValueType)> = vec![
+            (true.into(), OpenvrPropValueType::Bool),
+            ((-1_i32).into(), OpenvrPropValueType::Int32),
+            (1_u64.into(), OpenvrPropValueType::Uint64),
+            (0.5_f32.into(), OpenvrPropValueType::Float),
+            ("name".into(), OpenvrPropValueType::String),
+            (String::from("name").into(), OpenvrPropValueType::String),
+            ([0_f32, 1., 2.].into(), OpenvrPropValueType::Vector3),
+            (0.5_f64.into(), OpenvrPropValueType::Double),
+        ];
+        for (value, value_type) in values {
+            assert_eq!(value.value_type(), value_type);
+        }
+
+        assert!(matches!(
+            OpenvrPropValue::from("name"),
+            OpenvrPropValue::String(name) if name == "name"
+        ));
+        assert!(matches!(
+            OpenvrPropValue::from(-1_i32),
+            OpenvrPropValue::Int32(-1)
+        ));
+    }
+
+    fn encoder_desc(codec_name: &str) -> VideoEncoderDesc {
+        let mut encoder_desc = default_settings().video.encoder;
+        for codec_desc in &mut [
+            &mut encoder_desc.linux_windows_amd,
+            &mut encoder_desc.linux_windows_nvidia,
+            &mut encoder_desc.macos,
+        ] {
+            codec_desc.codec_name = codec_name.into();
+        }
+        encoder_desc
+    }
+
+    #[test]
+    fn ten_bit_codecs() {
+        assert!(codec_supports_10_bit("hevc_nvenc"));
+        assert!(codec_supports_10_bit("libx265"));
+        assert!(codec_supports_10_bit("av1_nvenc"));
+        assert!(!codec_supports_10_bit(""));
+        assert!(!codec_supports_10_bit("unknown"));
+        assert!(!codec_supports_10_bit("h264_nvenc"));
+        assert!(!codec_supports_10_bit("libx264"));
+    }
+
+    #[test]
+    fn gpu_vendor_from_pci_id() {
+        assert_eq!(GpuVendor::from_pci_vendor_id(0x1002), GpuVendor::Amd);
+        assert_eq!(GpuVendor::from_pci_vendor_id(0x10de), GpuVendor::Nvidia);
+        assert_eq!(GpuVendor::from_pci_vendor_id(0x8086), GpuVendor::Other);
+    }
+
+    #[test]
+    fn platform_codec_follows_gpu_vendor() {
+        let mut encoder_desc = encoder_desc("");
+        encoder_desc.linux_windows_amd.codec_name = "hevc_amf".into();
+        encoder_desc.linux_windows_nvidia.codec_name = "hevc_nvenc".into();
+        encoder_desc.macos.codec_name = "hevc_videotoolbox".into();
+
+        let codec_name =
+            |gpu_vendor| &platform_encoder_codec_desc(&encoder_desc, gpu_vendor).codec_name;
+        if cfg!(target_os = "macos") {
+            assert_eq!(codec_name(GpuVendor::Amd), "hevc_videotoolbox");
+        } else {
+            assert_eq!(codec_name(GpuVendor::Amd), "hevc_amf");
+            assert_eq!(codec_name(GpuVendor::Nvidia), "hevc_nvenc");
+            assert_eq!(codec_name(GpuVendor::Other), "hevc_nvenc");
+        }
+    }
+
+    #[test]
+    fn p010_requires_ten_bit_platform_codec() {
+        for &gpu_vendor in &[Some(GpuVendor::Amd), Some(GpuVendor::Nvidia), None] {
+            let validate = |pixel_format, codec_name| {
+                validate_pixel_format(pixel_format, &encoder_desc(codec_name), gpu_vendor)
+            };
+            assert!(validate(VideoPixelFormat::P010, "hevc_amf").is_ok());
+            assert!(validate(VideoPixelFormat::P010, "h264_amf").is_err());
+            assert!(validate(VideoPixelFormat::P010, "").is_err());
+            assert!(validate(VideoPixelFormat::NV12, "h264_amf").is_ok());
+        }
+    }
+
+    #[test]
+    fn p010_uses_the_codec_of_the_detected_gpu() {
+        // There is only one codec on macOS
+        if cfg!(target_os = "macos") {
+            return;
+        }
+
+        let mut encoder_desc = encoder_desc("hevc_amf");
+        encoder_desc.linux_windows_nvidia.codec_name = "h264_nvenc".into();
+
+        let validate =
+            |gpu_vendor| validate_pixel_format(VideoPixelFormat::P010, &encoder_desc, gpu_vendor);
+        assert!(validate(Some(GpuVendor::Amd)).is_ok());
+        assert!(validate(Some(GpuVendor::Nvidia)).is_err());
+        // The GPU is not known yet, but one of the codecs can be used
+        assert!(validate(None).is_ok());
+    }
+
+    #[test]
+    fn p010_ignores_other_platform_codecs() {
+        let mut encoder_desc = encoder_desc("hevc_nvenc");
+        if cfg!(target_os = "macos") {
+            encoder_desc.linux_windows_nvidia.codec_name = "h264_nvenc".into();
+        } else {
+            encoder_desc.macos.codec_name = "h264_videotoolbox".into();
+        }
+        encoder_desc.linux_windows_amd.codec_name = "h264_amf".into();
+
+        assert!(validate_pixel_format(
+            VideoPixelFormat::P010,
+            &encoder_desc,
+            Some(GpuVendor::Nvidia)
+        )
+        .is_ok());
+    }
+
+    fn load_settings_json(name: &str, settings_json: serde_json::Value) -> StrResult<Settings> {
+        let path = std::env::temp_dir().join(format!(
+            "bridgevr_test_{}_{}.json",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, settings_json.to_string()).unwrap();
+        let result = load_settings(&path);
+        fs::remove_file(&path).ok();
+
+        result
+    }
+
+    #[test]
+    fn missing_or_corrupted_settings_fall_back_to_defaults() {
+        let path =
+            std::env::temp_dir().join(format!("bridgevr_test_missing_{}.json", std::process::id()));
+        let (settings, records) = logging::capture_logs(|| load_settings_or_default(&path));
+        assert_eq!(
+            settings.connection.server_port,
+            default_settings().connection.server_port
+        );
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0, log::Level::Warn);
+        assert!(records[0].1.contains("using the default settings"));
+
+        let path = std::env::temp_dir().join(format!(
+            "bridgevr_test_corrupted_{}.json",
+            std::process::id()
+        ));
+        fs::write(&path, "{ \"connection\": ").unwrap();
+        let (settings, records) = logging::capture_logs(|| load_settings_or_default(&path));
+        fs::remove_file(&path).ok();
+        assert_eq!(
+            settings.connection.server_port,
+            default_settings().connection.server_port
+        );
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0, log::Level::Warn);
+    }
+
+    #[test]
+    fn load_rejects_values_out_of_bounds() {
+        let settings_json = serde_json::json!({ "connection": { "server_port": 80 } });
+        let error = load_settings_json("bounds", settings_json).err().unwrap();
+        assert!(error.contains("root.connection.server_port: value 80 out of range [1024, 65535]"));
+    }
+
+    fn codec_desc(
+        codec_name: &str,
+        priv_data_options: Vec<(String, FfmpegOptionValue)>,
+    ) -> VideoCodecDesc {
+        let mut codec_desc = default_settings().video.encoder.linux_windows_nvidia;
+        codec_desc.codec_name = codec_name.into();
+        codec_desc.priv_data_options = priv_data_options;
+        codec_desc
+    }
+
+    #[test]
+    fn preset_is_expanded_for_codec() {
+        use FfmpegOptionValue::*;
+
+        let options = effective_priv_data_options(
+            VideoEncoderPreset::UltraLowLatency,
+            &codec_desc("hevc_nvenc", vec![]),
+        );
+        assert_eq!(
+            options,
+            vec![
+                ("preset".to_owned(), String("llhp".into())),
+                ("rc".to_owned(), String("cbr".into())),
+                ("zerolatency".to_owned(), Int(1)),
+                ("delay".to_owned(), Int(0)),
+            ]
+        );
+
+        let options = effective_priv_data_options(
+            VideoEncoderPreset::Quality,
+            &codec_desc("h264_videotoolbox", vec![]),
+        );
+        assert_eq!(options, vec![("realtime".to_owned(), Int(0))]);
+    }
+
+    #[test]
+    fn explicit_options_override_preset() {
+        use FfmpegOptionValue::*;
+
+        let explicit_options = vec![
+            ("rc".to_owned(), String("vbr".into())),
+            ("gpu".to_owned(), Int(1)),
+        ];
+        let options = effective_priv_data_options(
+            VideoEncoderPreset::Balanced,
+            &codec_desc("h264_amf", explicit_options),
+        );
+        assert_eq!(
+            options,
+            vec![
+                ("usage".to_owned(), String("ultralowlatency".into())),
+                ("quality".to_owned(), String("balanced".into())),
+                ("rc".to_owned(), String("vbr".into())),
+                ("gpu".to_owned(), Int(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_codec_has_no_preset_options() {
+        let explicit_options = vec![("crf".to_owned(), FfmpegOptionValue::Int(20))];
+        let options = effective_priv_data_options(
+            VideoEncoderPreset::Balanced,
+            &codec_desc("libx264", explicit_options.clone()),
+        );
+        assert_eq!(options, explicit_options);
+    }
+
+    #[test]
+    fn settings_equality() {
+        let settings = default_settings();
+        assert!(settings == settings.clone());
+
+        let mut changed_settings = settings.clone();
+        changed_settings.video.encoder.macos.codec_name = "hevc_videotoolbox".into();
+        assert!(settings != changed_settings);
+    }
+
+    #[test]
+    fn zero_denominators_are_rejected() {
+        use FfmpegOptionValue::*;
+
+        let mut codec_desc = codec_desc("hevc_nvenc", vec![]);
+        codec_desc.context_options =
+            vec![("sample_aspect_ratio".into(), Rational { num: 1, den: 1 })];
+        assert!(validate_codec_options(&codec_desc).is_ok());
+
+        codec_desc.frame_options =
+            vec![("sample_aspect_ratio".into(), Rational { num: 1, den: 0 })];
+        let error = validate_codec_options(&codec_desc).unwrap_err();
+        assert!(error.contains("\"sample_aspect_ratio\" of codec \"hevc_nvenc\""));
+
+        codec_desc.frame_options = vec![];
+        codec_desc.hw_frames_context_options =
+            vec![("framerate".into(), VideoRate { num: 72, den: 0 })];
+        assert!(validate_codec_options(&codec_desc).is_err());
+    }
+
+    #[test]
+    fn sharpening_strength_is_bounded() {
+        let schema = settings_schema(settings_default());
+        let mut settings_json = settings_schema::schema_to_default_json(&schema);
+        assert_eq!(settings_json["video"]["sharpening"], "Disabled");
+
+        settings_json["video"]["sharpening"] = serde_json::json!({ "Enabled": 0.5 });
+        let mut errors = vec![];
+        validate_json(&schema, &settings_json, "root", &mut errors);
+        assert!(errors.is_empty());
+
+        settings_json["video"]["sharpening"] = serde_json::json!({ "Enabled": 1.5 });
+        validate_json(&schema, &settings_json, "root", &mut errors);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("root.video.sharpening:"));
+    }
+
+    fn video_desc(codec_name: &str) -> VideoDesc {
+        let mut video_desc = default_settings().video;
+        video_desc.encoder = encoder_desc(codec_name);
+        video_desc
+    }
+
+    #[test]
+    fn valid_encoder_config() {
+        let mut video_desc = video_desc("hevc_nvenc");
+        video_desc.pixel_format = VideoPixelFormat::P010;
+        for codec_desc in &mut [
+            &mut video_desc.encoder.linux_windows_nvidia,
+            &mut video_desc.encoder.macos,
+        ] {
+            codec_desc.context_options = vec![("b".into(), FfmpegOptionValue::Int(30_000_000))];
+        }
+
+        let config = video_desc
+            .to_encoder_config((1920, 1080), 72, GpuVendor::Nvidia)
+            .unwrap();
+        assert_eq!(config.resolution, (1920, 1080));
+        assert_eq!(config.fps, 72);
+        assert_eq!(config.codec_name, "hevc_nvenc");
+        assert_eq!(config.pixel_format, VideoPixelFormat::P010);
+        assert_eq!(config.bitrate_bps, Some(30_000_000));
+    }
+
+    #[test]
+    fn max_gop_zero_means_no_limit() {
+        let mut video_desc = video_desc("h264_nvenc");
+        let config = video_desc
+            .to_encoder_config((1920, 1080), 72, GpuVendor::Nvidia)
+            .unwrap();
+        assert_eq!(config.max_gop, None);
+
+        video_desc.max_gop = 120;
+        let config = video_desc
+            .to_encoder_config((1920, 1080), 72, GpuVendor::Nvidia)
+            .unwrap();
+        assert_eq!(config.max_gop, Some(120));
+    }
+
+    #[test]
+    fn invalid_encoder_config() {
+        let error = video_desc("")
+            .to_encoder_config((1920, 1080), 72, GpuVendor::Nvidia)
+            .unwrap_err();
+        assert!(error.contains("codec not set"));
+
+        let video_desc = video_desc("h264_nvenc");
+
+        let error = video_desc
+            .to_encoder_config((1921, 1080), 72, GpuVendor::Nvidia)
+            .unwrap_err();
+        assert!(error.contains("1921x1080 must be non zero and even"));
+        let error = video_desc
+            .to_encoder_config((0, 1080), 72, GpuVendor::Nvidia)
+            .unwrap_err();
+        assert!(error.contains("0x1080 must be non zero and even"));
+
+        let error = video_desc
+            .to_encoder_config((1920, 1080), 0, GpuVendor::Nvidia)
+            .unwrap_err();
+        assert!(error.contains("frame rate must be non zero"));
+
+        let mut p010_video_desc = video_desc.clone();
+        p010_video_desc.pixel_format = VideoPixelFormat::P010;
+        let error = p010_video_desc
+            .to_encoder_config((1920, 1080), 72, GpuVendor::Nvidia)
+            .unwrap_err();
+        assert!(error.contains("P010 is not supported by encoder h264_nvenc"));
+
+        let mut bitrate_video_desc = video_desc;
+        for codec_desc in &mut [
+            &mut bitrate_video_desc.encoder.linux_windows_nvidia,
+            &mut bitrate_video_desc.encoder.macos,
+        ] {
+            codec_desc.context_options = vec![("b".into(), FfmpegOptionValue::Int(0))];
+        }
+        let error = bitrate_video_desc
+            .to_encoder_config((1920, 1080), 72, GpuVendor::Nvidia)
+            .unwrap_err();
+        assert!(error.contains("\"b\" must be a positive integer"));
+    }
+
+    #[test]
+    fn tracked_devices_can_be_disabled() {
+        let mut openvr_desc = default_settings().vr_server.openvr;
+        openvr_desc.enable_hmd = false;
+        openvr_desc.enable_controllers = [true, false];
+
+        assert!(!openvr_desc.is_tracked_device_enabled(TrackedDeviceType::HMD));
+        assert!(openvr_desc.is_tracked_device_enabled(TrackedDeviceType::LeftController));
+        assert!(!openvr_desc.is_tracked_device_enabled(TrackedDeviceType::RightController));
+        assert!(openvr_desc.is_tracked_device_enabled(TrackedDeviceType::GenericTracker1));
+        assert!(validate_enabled_tracked_devices(&openvr_desc).is_ok());
+
+        openvr_desc.enable_controllers = [false, false];
+        assert!(validate_enabled_tracked_devices(&openvr_desc).is_err());
+
+        openvr_desc.enable_hmd = true;
+        assert!(validate_enabled_tracked_devices(&openvr_desc).is_ok());
+    }
+
+    #[test]
+    fn fallback_encoder_config() {
+        let mut video_desc = video_desc("hevc_nvenc");
+        video_desc.encoder_fallback = Switch::Disabled;
+        assert_eq!(
+            video_desc.to_fallback_encoder_config((1920, 1080), 72, GpuVendor::Nvidia),
+            Ok(None)
+        );
+
+        video_desc.encoder_fallback = Switch::Enabled(encoder_desc("libx264"));
+        let config = video_desc
+            .to_fallback_encoder_config((1920, 1080), 72, GpuVendor::Nvidia)
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.codec_name, "libx264");
+        assert_eq!(
+            video_desc
+                .to_encoder_config((1920, 1080), 72, GpuVendor::Nvidia)
+                .unwrap()
+                .codec_name,
+            "hevc_nvenc"
+        );
+
+        video_desc.pixel_format = VideoPixelFormat::P010;
+        assert!(video_desc
+            .to_fallback_encoder_config((1920, 1080), 72, GpuVendor::Nvidia)
+            .is_err());
+    }
+
+    #[test]
+    fn frame_slice_count_resolution() {
+        let mut video_desc = video_desc("hevc_nvenc");
+        video_desc.frame_slice_count = FrameSliceCount::Manual(5);
+        assert_eq!(
+            video_desc.resolve_frame_slice_count((1920, 1080), 90, GpuVendor::Nvidia),
+            Ok(5)
+        );
+
+        // The pixel rate of both eyes needs 3 slices, the bitrate is enough for 2
+        video_desc.frame_slice_count = FrameSliceCount::Automatic;
+        for codec_desc in &mut [
+            &mut video_desc.encoder.linux_windows_nvidia,
+            &mut video_desc.encoder.macos,
+        ] {
+            codec_desc.context_options = vec![("b".into(), FfmpegOptionValue::Int(16_000_000))];
+        }
+        assert_eq!(
+            video_desc.resolve_frame_slice_count((1920, 1080), 90, GpuVendor::Nvidia),
+            Ok(2)
+        );
+    }
+
+    #[test]
+    fn max_packet_size_validation() {
+        let mut socket_config = default_settings().connection.config;
+        socket_config.max_packet_size = None;
+        socket_config.max_fragments = None;
+        socket_config.fragment_size = None;
+        assert_eq!(
+            socket_config.effective_max_packet_size(),
+            DEFAULT_MAX_PACKET_SIZE_BYTES
+        );
+        assert!(validate_max_packet_size(&socket_config).is_ok());
+
+        socket_config.max_packet_size = Some(MIN_AUDIO_PACKET_SIZE_BYTES as u64 - 1);
+        assert!(validate_max_packet_size(&socket_config).is_err());
+
+        socket_config.max_packet_size = Some(4096);
+        socket_config.max_fragments = Some(4);
+        socket_config.fragment_size = Some(1024);
+        assert!(validate_max_packet_size(&socket_config).is_ok());
+
+        socket_config.max_fragments = Some(3);
+        assert!(validate_max_packet_size(&socket_config).is_err());
+    }
+
+    #[test]
+    fn empty_controller_render_model_is_rejected() {
+        let mut openvr_desc = default_settings().vr_server.openvr;
+        assert!(validate_controller_render_model(&openvr_desc).is_ok());
+
+        openvr_desc.controller_render_model[1] = Some(" ".into());
+        assert!(validate_controller_render_model(&openvr_desc).is_err());
+
+        // A disabled controller is not registered
+        openvr_desc.enable_controllers[1] = false;
+        assert!(validate_controller_render_model(&openvr_desc).is_ok());
+
+        openvr_desc.enable_controllers[1] = true;
+        for device_desc in &mut openvr_desc.tracked_devices {
+            if device_desc.device_type == TrackedDeviceType::RightController {
+                device_desc.properties.push((
+                    "Prop_RenderModelName_String".into(),
+                    OpenvrPropValue::String("vr_controller_vive_1_5".into()),
+                ));
+            }
+        }
+        assert!(validate_controller_render_model(&openvr_desc).is_ok());
+    }
+
+    const FOV: Fov = Fov {
+        left: 52.,
+        top: 48.,
+        right: 45.5,
+        bottom: 50.25,
+    };
+
+    #[test]
+    fn fov_approx_eq_checks_each_angle() {
+        let other = Fov {
+            bottom: 50.3,
+            ..FOV
+        };
+        assert!(FOV.approx_eq(&other, 0.1));
+        assert!(!FOV.approx_eq(&other, 0.01));
+
+        let other = Fov { left: 51.8, ..FOV };
+        assert!(!FOV.approx_eq(&other, 0.1));
+    }
+
+    #[test]
+    fn fov_key_rounds_angles() {
+        let close_fov = Fov {
+            right: 45.501,
+            ..FOV
+        };
+        assert_eq!(FovKey::from(FOV), FovKey::from(close_fov));
+
+        let different_fov = Fov { right: 45.6, ..FOV };
+        assert_ne!(FovKey::from(FOV), FovKey::from(different_fov));
+
+        let mut cache = HashMap::new();
+        cache.insert(FovKey::from(FOV), 1);
+        assert_eq!(cache.get(&FovKey::from(close_fov)), Some(&1));
+        assert_eq!(cache.get(&FovKey::from(different_fov)), None);
+    }
+}
@@ -0,0 +1,389 @@
+use crate::*;
+use chacha20poly1305::{
+    aead::{AeadInPlace, NewAead},
+    ChaCha20Poly1305, Nonce, Tag,
+};
+use parking_lot::Mutex;
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+const TRACE_CONTEXT: &str = "Encryption";
+
+const TAG_SIZE_BYTES: usize = 16;
+const COUNTER_SIZE_BYTES: usize = 8;
+
+const SERVER_TO_CLIENT: u8 = 0;
+const CLIENT_TO_SERVER: u8 = 1;
+
+// Packets delayed by more than this number of newer packets are rejected
+const REPLAY_WINDOW_SIZE: u64 = 1024;
+
+// Data channel packets are encrypted with ChaCha20-Poly1305, with a key derived from an X25519 key
+// exchange done during the handshake. The key is different for every connection. The handshake is
+// not authenticated: this protects from eavesdroppers but not from an active man in the middle.
+// Packet layout: stream id (authenticated but not encrypted) | ciphertext | tag | nonce counter
+pub const ENCRYPTION_OVERHEAD_BYTES: usize = TAG_SIZE_BYTES + COUNTER_SIZE_BYTES;
+
+pub type EncryptionPublicKey = [u8; 32];
+
+pub struct KeyExchange {
+    secret: EphemeralSecret,
+    public_key: PublicKey,
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::new(&mut OsRng);
+        let public_key = PublicKey::from(&secret);
+
+        Self { secret, public_key }
+    }
+
+    pub fn public_key(&self) -> EncryptionPublicKey {
+        *self.public_key.as_bytes()
+    }
+
+    pub fn into_cipher(self, peer_public_key: EncryptionPublicKey, is_server: bool) -> DataCipher {
+        let own_public_key = self.public_key();
+        let shared_secret = self
+            .secret
+            .diffie_hellman(&PublicKey::from(peer_public_key));
+
+        let (client_public_key, server_public_key) = if is_server {
+            (peer_public_key, own_public_key)
+        } else {
+            (own_public_key, peer_public_key)
+        };
+        let key = Sha256::new()
+            .chain(shared_secret.as_bytes())
+            .chain(client_public_key)
+            .chain(server_public_key)
+            .finalize();
+
+        let (send_direction, receive_direction) = if is_server {
+            (SERVER_TO_CLIENT, CLIENT_TO_SERVER)
+        } else {
+            (CLIENT_TO_SERVER, SERVER_TO_CLIENT)
+        };
+
+        DataCipher {
+            aead: ChaCha20Poly1305::new(&key),
+            send_direction,
+            receive_direction,
+            next_send_counter: AtomicU64::new(0),
+            replay_window: Mutex::new(ReplayWindow::default()),
+        }
+    }
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Nonce counters of the received packets that are still inside the window
+#[derive(Default)]
+struct ReplayWindow {
+    // None until the first packet is received
+    highest_counter: Option<u64>,
+    received_bits: [u64; (REPLAY_WINDOW_SIZE / 64) as usize],
+}
+
+impl ReplayWindow {
+    fn bit(counter: u64) -> (usize, u64) {
+        let index = counter % REPLAY_WINDOW_SIZE;
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+
+    fn is_new(&self, counter: u64) -> bool {
+        match self.highest_counter {
+            Some(highest) if counter <= highest => {
+                let (word, mask) = Self::bit(counter);
+                highest - counter < REPLAY_WINDOW_SIZE && self.received_bits[word] & mask == 0
+            }
+            _ => true,
+        }
+    }
+
+    // Must be called only after the packet has been authenticated, otherwise a forged counter
+    // could move the window
+    fn mark_received(&mut self, counter: u64) {
+        match self.highest_counter {
+            Some(highest) if counter <= highest => (),
+            Some(highest) if counter - highest < REPLAY_WINDOW_SIZE => {
+                // The bits of the skipped counters still belong to counters that left the window
+                for skipped_counter in highest + 1..counter {
+                    let (word, mask) = Self::bit(skipped_counter);
+                    self.received_bits[word] &= !mask;
+                }
+                self.highest_counter = Some(counter);
+            }
+            _ => {
+                self.received_bits = Default::default();
+                self.highest_counter = Some(counter);
+            }
+        }
+
+        let (word, mask) = Self::bit(counter);
+        self.received_bits[word] |= mask;
+    }
+}
+
+// Shared by all the streams of a connection, so that a nonce is never reused. Packets can be
+// decrypted out of order, as long as they are inside the replay window. Replayed packets are
+// rejected.
+pub struct DataCipher {
+    aead: ChaCha20Poly1305,
+    send_direction: u8,
+    receive_direction: u8,
+    next_send_counter: AtomicU64,
+    replay_window: Mutex<ReplayWindow>,
+}
+
+impl DataCipher {
+    fn nonce(direction: u8, counter: u64) -> Nonce {
+        let mut nonce = Nonce::default();
+        nonce[0] = direction;
+        nonce[4..].copy_from_slice(&counter.to_le_bytes());
+        nonce
+    }
+
+    // buffer: stream id followed by the serialized packet
+    pub fn encrypt(&self, buffer: &mut Vec<u8>) -> StrResult {
+        let counter = self.next_send_counter.fetch_add(1, Ordering::Relaxed);
+        let nonce = Self::nonce(self.send_direction, counter);
+
+        let (stream_id, payload) = trace_none!(buffer.split_first_mut(), "Empty packet")?;
+        let tag =
+            trace_err_dbg!(self
+                .aead
+                .encrypt_in_place_detached(&nonce, &[*stream_id], payload))?;
+
+        buffer.extend_from_slice(&tag);
+        buffer.extend_from_slice(&counter.to_le_bytes());
+
+        Ok(())
+    }
+
+    // The buffer is left with the stream id followed by the serialized packet
+    pub fn decrypt(&self, buffer: &mut Vec<u8>) -> StrResult {
+        if buffer.len() < 1 + ENCRYPTION_OVERHEAD_BYTES {
+            return trace_str!("Encrypted packet too short: {} bytes", buffer.len());
+        }
+
+        let counter_start = buffer.len() - COUNTER_SIZE_BYTES;
+        let tag_start = counter_start - TAG_SIZE_BYTES;
+
+        let mut counter_bytes = [0; COUNTER_SIZE_BYTES];
+        counter_bytes.copy_from_slice(&buffer[counter_start..]);
+        let counter = u64::from_le_bytes(counter_bytes);
+
+        let mut replay_window = self.replay_window.lock();
+        if !replay_window.is_new(counter) {
+            return trace_str!("Replayed or too old packet, counter: {}", counter);
+        }
+
+        let nonce = Self::nonce(self.receive_direction, counter);
+        let mut tag = Tag::default();
+        tag.copy_from_slice(&buffer[tag_start..counter_start]);
+
+        let (stream_id, payload) = buffer[..tag_start].split_at_mut(1);
+        trace_err_dbg!(self
+            .aead
+            .decrypt_in_place_detached(&nonce, stream_id, payload, &tag))?;
+
+        replay_window.mark_received(counter);
+        buffer.truncate(tag_start);
+
+        Ok(())
+    }
+}
+
+// Server side. Returns the public key to send to the client and the cipher, or None if encryption
+// is disabled. Fails instead of falling back to unencrypted data channels if the client does not
+// support encryption.
+pub fn negotiate_server_encryption(
+    encrypt_data_channels: bool,
+    client_public_key: Option<EncryptionPublicKey>,
+) -> StrResult<Option<(EncryptionPublicKey, DataCipher)>> {
+    if !encrypt_data_channels {
+        return Ok(None);
+    }
+
+    let client_public_key = trace_none!(
+        client_public_key,
+        "Data channel encryption is enabled but the client does not support it"
+    )?;
+    let key_exchange = KeyExchange::new();
+    let server_public_key = key_exchange.public_key();
+
+    Ok(Some((
+        server_public_key,
+        key_exchange.into_cipher(client_public_key, true),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher_pair() -> (DataCipher, DataCipher) {
+        let client_key_exchange = KeyExchange::new();
+        let server_key_exchange = KeyExchange::new();
+        let client_public_key = client_key_exchange.public_key();
+        let server_public_key = server_key_exchange.public_key();
+
+        (
+            client_key_exchange.into_cipher(server_public_key, false),
+            server_key_exchange.into_cipher(client_public_key, true),
+        )
+    }
+
+    fn packet(stream_id: u8) -> Vec<u8> {
+        vec![stream_id, 1, 2, 3, 4, 5]
+    }
+
+    #[test]
+    fn encrypt_decrypt_round_trip() {
+        let (client_cipher, server_cipher) = cipher_pair();
+
+        let mut buffer = packet(7);
+        server_cipher.encrypt(&mut buffer).unwrap();
+        assert_eq!(buffer.len(), packet(7).len() + ENCRYPTION_OVERHEAD_BYTES);
+        assert_eq!(buffer[0], 7);
+        assert_ne!(buffer[1..6], packet(7)[1..]);
+        client_cipher.decrypt(&mut buffer).unwrap();
+        assert_eq!(buffer, packet(7));
+
+        let mut buffer = packet(3);
+        client_cipher.encrypt(&mut buffer).unwrap();
+        server_cipher.decrypt(&mut buffer).unwrap();
+        assert_eq!(buffer, packet(3));
+    }
+
+    #[test]
+    fn packets_can_be_decrypted_out_of_order() {
+        let (client_cipher, server_cipher) = cipher_pair();
+
+        let mut buffers = vec![packet(0), packet(1)];
+        for buffer in &mut buffers {
+            server_cipher.encrypt(buffer).unwrap();
+        }
+        for buffer in buffers.iter_mut().rev() {
+            client_cipher.decrypt(buffer).unwrap();
+        }
+        assert_eq!(buffers, vec![packet(0), packet(1)]);
+    }
+
+    #[test]
+    fn replayed_packets_are_rejected() {
+        let (client_cipher, server_cipher) = cipher_pair();
+
+        let mut buffers = vec![packet(0), packet(1)];
+        for buffer in &mut buffers {
+            server_cipher.encrypt(buffer).unwrap();
+        }
+        let replayed_buffers = buffers.clone();
+
+        for buffer in buffers.iter_mut().rev() {
+            client_cipher.decrypt(buffer).unwrap();
+        }
+        for mut buffer in replayed_buffers {
+            assert!(client_cipher.decrypt(&mut buffer).is_err());
+        }
+    }
+
+    #[test]
+    fn packets_older_than_the_replay_window_are_rejected() {
+        let (client_cipher, server_cipher) = cipher_pair();
+
+        let mut buffers = (0..=REPLAY_WINDOW_SIZE)
+            .map(|_| {
+                let mut buffer = packet(0);
+                server_cipher.encrypt(&mut buffer).unwrap();
+                buffer
+            })
+            .collect::<Vec<_>>();
+
+        client_cipher
+            .decrypt(&mut buffers[REPLAY_WINDOW_SIZE as usize])
+            .unwrap();
+        client_cipher.decrypt(&mut buffers[1]).unwrap();
+        assert!(client_cipher.decrypt(&mut buffers[0]).is_err());
+    }
+
+    #[test]
+    fn rejected_packets_do_not_move_the_replay_window() {
+        let (client_cipher, server_cipher) = cipher_pair();
+
+        let mut buffer = packet(7);
+        server_cipher.encrypt(&mut buffer).unwrap();
+
+        // Forged counter far ahead
+        let mut forged_buffer = buffer.clone();
+        let counter_start = forged_buffer.len() - COUNTER_SIZE_BYTES;
+        forged_buffer[counter_start..].copy_from_slice(&(2 * REPLAY_WINDOW_SIZE).to_le_bytes());
+        assert!(client_cipher.decrypt(&mut forged_buffer).is_err());
+
+        client_cipher.decrypt(&mut buffer).unwrap();
+        assert_eq!(buffer, packet(7));
+    }
+
+    #[test]
+    fn tampered_packets_are_rejected() {
+        let (client_cipher, server_cipher) = cipher_pair();
+
+        let mut buffer = packet(7);
+        server_cipher.encrypt(&mut buffer).unwrap();
+
+        let mut tampered_stream_id = buffer.clone();
+        tampered_stream_id[0] = 8;
+        assert!(client_cipher.decrypt(&mut tampered_stream_id).is_err());
+
+        let mut tampered_payload = buffer.clone();
+        tampered_payload[1] ^= 1;
+        assert!(client_cipher.decrypt(&mut tampered_payload).is_err());
+
+        // Packets are not accepted in the direction they were sent
+        assert!(server_cipher.decrypt(&mut buffer).is_err());
+
+        let mut short_buffer = vec![0; ENCRYPTION_OVERHEAD_BYTES];
+        assert!(client_cipher.decrypt(&mut short_buffer).is_err());
+    }
+
+    #[test]
+    fn ciphers_of_different_connections_do_not_match() {
+        let (_, server_cipher) = cipher_pair();
+        let (client_cipher, _) = cipher_pair();
+
+        let mut buffer = packet(7);
+        server_cipher.encrypt(&mut buffer).unwrap();
+        assert!(client_cipher.decrypt(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn server_encryption_negotiation() {
+        let client_key_exchange = KeyExchange::new();
+        let client_public_key = client_key_exchange.public_key();
+
+        assert!(matches!(
+            negotiate_server_encryption(false, Some(client_public_key)),
+            Ok(None)
+        ));
+        assert!(negotiate_server_encryption(true, None).is_err());
+
+        let (server_public_key, server_cipher) =
+            negotiate_server_encryption(true, Some(client_public_key))
+                .unwrap()
+                .unwrap();
+        let client_cipher = client_key_exchange.into_cipher(server_public_key, false);
+
+        let mut buffer = packet(7);
+        server_cipher.encrypt(&mut buffer).unwrap();
+        client_cipher.decrypt(&mut buffer).unwrap();
+        assert_eq!(buffer, packet(7));
+    }
+}
@@ -1,8 +1,34 @@
 // AADT: Axis-aligned distorted transfer
 
-use crate::{data::*, graphics::*};
+use crate::{data::*, frame_slices::*, graphics::*, *};
 use std::sync::Arc;
 
+const TRACE_CONTEXT: &str = "FFR";
+
+// Number of samples per side used to integrate the foveation weight over a slice
+const SLICE_WEIGHT_SAMPLES: u32 = 16;
+
+// Resolution of the slice weights used to split the bitrate
+const FIXED_WEIGHT_SCALE: u64 = 1 << 20;
+
+// Non positive or non finite values would make the foveation weight degenerate or divide by zero.
+pub fn validate_ffr_desc(ffr_desc: FoveatedRenderingDesc) -> StrResult {
+    if !ffr_desc.strength.is_finite() || ffr_desc.strength <= 0. {
+        return trace_str!("Strength must be positive, got {}", ffr_desc.strength);
+    }
+    if !ffr_desc.shape_ratio.is_finite() || ffr_desc.shape_ratio <= 0. {
+        return trace_str!("Shape ratio must be positive, got {}", ffr_desc.shape_ratio);
+    }
+    if !(-1. ..=1.).contains(&ffr_desc.vertical_offset) {
+        return trace_str!(
+            "Vertical offset must be in [-1, 1], got {}",
+            ffr_desc.vertical_offset
+        );
+    }
+
+    Ok(())
+}
+
 pub fn ffr_compressed_eye_resolution(
     original_eye_resolution: (u32, u32),
     ffr_desc: FoveatedRenderingDesc,
@@ -21,3 +47,222 @@ pub fn ffr_compression_operation_descs(
 pub fn ffr_decompression_operation_descs() -> Vec<OperationDesc> {
     todo!()
 }
+
+// Relative importance of a point of an eye image, in normalized coordinates. The foveal region is an
+// ellipse centered in the eye image and shifted by `vertical_offset`.
+fn foveation_weight(ffr_desc: FoveatedRenderingDesc, u: f32, v: f32) -> f32 {
+    let x = (u - 0.5) / ffr_desc.shape_ratio;
+    let y = v - 0.5 - ffr_desc.vertical_offset;
+    1. / (1. + ffr_desc.strength * (x * x + y * y))
+}
+
+fn slice_foveation_weight(
+    ffr_desc: FoveatedRenderingDesc,
+    frame_resolution: (u32, u32),
+    slice_index: usize,
+    slices_desc: &SlicesDesc,
+) -> f32 {
+    let bounds = slice_bounds_to_texture_bounds(
+        frame_resolution,
+        get_slice_start(slice_index, slices_desc),
+        slices_desc.single_resolution,
+    );
+
+    let mut weight_sum = 0.;
+    for i in 0..SLICE_WEIGHT_SAMPLES {
+        for j in 0..SLICE_WEIGHT_SAMPLES {
+            let u = bounds.u_min
+                + (bounds.u_max - bounds.u_min) * (i as f32 + 0.5) / SLICE_WEIGHT_SAMPLES as f32;
+            let v = bounds.v_min
+                + (bounds.v_max - bounds.v_min) * (j as f32 + 0.5) / SLICE_WEIGHT_SAMPLES as f32;
+
+            // samples outside the frame (because of padding) are ignored
+            if u < 1. && v < 1. {
+                // the frame contains the two eyes side by side
+                let eye_u = (u * 2.).fract();
+                weight_sum += foveation_weight(ffr_desc, eye_u, v);
+            }
+        }
+    }
+
+    weight_sum
+}
+
+// Distribute `total_bitrate` across slices proportionally to their coverage of the foveal region.
+// Every slice gets at least `min_slice_share` of the equal share and the result sums to
+// `total_bitrate`. Returns an empty list if there are no slices.
+pub fn ffr_slice_bitrates(
+    total_bitrate: u32,
+    frame_resolution: (u32, u32),
+    slices_desc: &SlicesDesc,
+    ffr_desc: FoveatedRenderingDesc,
+    min_slice_share: f32,
+) -> Vec<u32> {
+    let slice_count = slices_desc.horizontal_count * slices_desc.vertical_count;
+    if slice_count == 0 {
+        return vec![];
+    }
+
+    let weights: Vec<_> = (0..slice_count)
+        .map(|idx| slice_foveation_weight(ffr_desc, frame_resolution, idx, slices_desc))
+        .collect();
+
+    // The shares are computed on fixed point weights, so that the truncations can only lose
+    // bitrate and never push the sum over `total_bitrate`
+    let max_weight = weights.iter().cloned().fold(0., f32::max);
+    let fixed_weights: Vec<u64> = if max_weight > 0. {
+        weights
+            .iter()
+            .map(|weight| (weight / max_weight * FIXED_WEIGHT_SCALE as f32) as u64)
+            .collect()
+    } else {
+        vec![1; slice_count]
+    };
+    let fixed_weights_sum: u64 = fixed_weights.iter().sum();
+
+    let total_bitrate = total_bitrate as u64;
+    let floor_bitrate =
+        (total_bitrate as f64 * min_slice_share.clamp(0., 1.) as f64) as u64 / slice_count as u64;
+    let distributable_bitrate = total_bitrate.saturating_sub(floor_bitrate * slice_count as u64);
+
+    let mut bitrates: Vec<_> = fixed_weights
+        .iter()
+        .map(|weight| floor_bitrate + distributable_bitrate * weight / fixed_weights_sum)
+        .collect();
+
+    // assign the remainder of the truncations to the most important slice
+    let remainder = total_bitrate.saturating_sub(bitrates.iter().sum());
+    if let Some((max_weight_idx, _)) = fixed_weights
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, weight)| **weight)
+    {
+        bitrates[max_weight_idx] += remainder;
+    }
+
+    bitrates.into_iter().map(|bitrate| bitrate as u32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_RESOLUTION: (u32, u32) = (2000, 1500);
+
+    fn ffr_desc() -> FoveatedRenderingDesc {
+        FoveatedRenderingDesc {
+            strength: 2.,
+            shape_ratio: 1.5,
+            vertical_offset: 0.,
+        }
+    }
+
+    // 1 column, 3 rows: the middle row covers the foveal region of both eyes
+    fn three_rows() -> SlicesDesc {
+        SlicesDesc {
+            single_resolution: (2000, 500),
+            horizontal_count: 1,
+            vertical_count: 3,
+        }
+    }
+
+    #[test]
+    fn ffr_desc_validation_bounds() {
+        for strength in &[-1., f32::INFINITY] {
+            let mut desc = ffr_desc();
+            desc.strength = *strength;
+            assert!(validate_ffr_desc(desc).is_err());
+        }
+
+        for shape_ratio in &[0., -1.5] {
+            let mut desc = ffr_desc();
+            desc.shape_ratio = *shape_ratio;
+            assert!(validate_ffr_desc(desc).is_err());
+        }
+
+        for vertical_offset in &[-1., 1.] {
+            let mut desc = ffr_desc();
+            desc.vertical_offset = *vertical_offset;
+            assert!(validate_ffr_desc(desc).is_ok());
+        }
+
+        let mut desc = ffr_desc();
+        desc.vertical_offset = -1.5;
+        let error = validate_ffr_desc(desc).unwrap_err();
+        assert!(error.contains("Vertical offset must be in [-1, 1], got -1.5"));
+    }
+
+    #[test]
+    fn slice_bitrates_sum_to_total() {
+        for &total_bitrate in &[30_000_000, 30_000_001, 7, (1 << 24) + 1, u32::MAX] {
+            let bitrates = ffr_slice_bitrates(
+                total_bitrate,
+                FRAME_RESOLUTION,
+                &three_rows(),
+                ffr_desc(),
+                0.3,
+            );
+            assert_eq!(bitrates.len(), 3);
+            assert_eq!(bitrates.iter().sum::<u32>(), total_bitrate);
+        }
+    }
+
+    #[test]
+    fn foveal_slice_gets_more_bitrate() {
+        let bitrates =
+            ffr_slice_bitrates(30_000_000, FRAME_RESOLUTION, &three_rows(), ffr_desc(), 0.3);
+        assert!(bitrates[1] > bitrates[0]);
+        assert!(bitrates[1] > bitrates[2]);
+    }
+
+    #[test]
+    fn slice_bitrates_respect_floor() {
+        let min_slice_share = 0.5;
+        let bitrates = ffr_slice_bitrates(
+            30_000_000,
+            FRAME_RESOLUTION,
+            &three_rows(),
+            ffr_desc(),
+            min_slice_share,
+        );
+        let floor_bitrate = (30_000_000. / 3. * min_slice_share) as u32;
+        assert!(bitrates.iter().all(|&bitrate| bitrate >= floor_bitrate));
+    }
+
+    #[test]
+    fn full_floor_gives_equal_shares() {
+        let bitrates =
+            ffr_slice_bitrates(30_000_000, FRAME_RESOLUTION, &three_rows(), ffr_desc(), 1.);
+        assert_eq!(bitrates, vec![10_000_000; 3]);
+    }
+
+    #[test]
+    fn large_bitrates_do_not_overflow() {
+        for &min_slice_share in &[0., 0.3, 1.] {
+            let bitrates = ffr_slice_bitrates(
+                u32::MAX,
+                FRAME_RESOLUTION,
+                &three_rows(),
+                ffr_desc(),
+                min_slice_share,
+            );
+            assert_eq!(
+                bitrates.iter().map(|&bitrate| bitrate as u64).sum::<u64>(),
+                u32::MAX as u64
+            );
+        }
+    }
+
+    #[test]
+    fn no_slices_get_no_bitrate() {
+        let slices_desc = SlicesDesc {
+            single_resolution: (2000, 1500),
+            horizontal_count: 0,
+            vertical_count: 1,
+        };
+        assert!(
+            ffr_slice_bitrates(30_000_000, FRAME_RESOLUTION, &slices_desc, ffr_desc(), 0.3)
+                .is_empty()
+        );
+    }
+}
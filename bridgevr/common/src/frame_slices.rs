@@ -40,6 +40,35 @@ pub fn slices_desc_from_count(count: usize, (frame_width, frame_height): (u32, u
     best_slices_desc
 }
 
+// Pixel rate that a single encoder sustains with low latency
+const PIXEL_RATE_PER_SLICE: u64 = 1920 * 1080 * 60;
+
+// With less bitrate per slice, the overhead of the slice (headers, prediction that does not cross
+// the slice borders) becomes significant
+const MIN_SLICE_BITRATE_BPS: i64 = 8_000_000;
+
+// Each slice uses its own encoder session. Some consumer GPUs support only 3 concurrent sessions
+pub const MAX_ENCODE_SESSIONS: usize = 3;
+
+// The frame rate and resolution (both eyes) set the number of slices needed to encode each frame in
+// time, the total bitrate (if known) limits it so that every slice gets enough bitrate.
+pub fn recommended_slice_count(
+    frame_resolution: (u32, u32),
+    fps: u32,
+    bitrate_bps: Option<i64>,
+) -> usize {
+    let (frame_width, frame_height) = frame_resolution;
+    let pixel_rate = frame_width as u64 * frame_height as u64 * fps as u64;
+    let throughput_count = pixel_rate.div_ceil(PIXEL_RATE_PER_SLICE) as usize;
+    let bitrate_count = bitrate_bps.map_or(usize::MAX, |bitrate_bps| {
+        (bitrate_bps / MIN_SLICE_BITRATE_BPS) as usize
+    });
+
+    throughput_count
+        .min(bitrate_count)
+        .clamp(1, MAX_ENCODE_SESSIONS)
+}
+
 // slices are indexed left to right first, then top to bottom
 pub fn get_slice_start(slice_index: usize, slice_desc: &SlicesDesc) -> (u32, u32) {
     let (single_width, single_height) = slice_desc.single_resolution;
@@ -69,3 +98,31 @@ pub fn slice_bounds_to_texture_bounds(
         v_max,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slice_count_follows_pixel_rate() {
+        assert_eq!(recommended_slice_count((1920, 1080), 60, None), 1);
+        assert_eq!(recommended_slice_count((1920, 1080), 61, None), 2);
+        assert_eq!(recommended_slice_count((16, 16), 60, None), 1);
+        assert_eq!(
+            recommended_slice_count((3840, 2160), 90, None),
+            MAX_ENCODE_SESSIONS
+        );
+    }
+
+    #[test]
+    fn slice_count_is_limited_by_bitrate() {
+        assert_eq!(
+            recommended_slice_count((3840, 2160), 90, Some(2 * MIN_SLICE_BITRATE_BPS)),
+            2
+        );
+        assert_eq!(
+            recommended_slice_count((3840, 2160), 90, Some(MIN_SLICE_BITRATE_BPS / 2)),
+            1
+        );
+    }
+}
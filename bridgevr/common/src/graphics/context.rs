@@ -1,8 +1,12 @@
-use crate::{data::BVR_NAME, StrResult};
+use crate::{
+    data::{GpuVendor, BVR_NAME},
+    StrResult,
+};
 pub use gfx_hal::format::Format;
 use gfx_hal::{adapter::MemoryType, prelude::*, queue::QueueGroup, *};
 use log::debug;
-use std::sync::Arc;
+use parking_lot::Mutex;
+use std::{iter, sync::Arc, time::Duration};
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use gfx_backend_vulkan as back;
@@ -15,6 +19,9 @@ use gfx_backend_metal as back;
 
 pub(super) const TRACE_CONTEXT: &str = "Graphics";
 
+// Used when destroying resources
+pub(super) const RELEASE_WAIT_TIMEOUT: Duration = Duration::from_millis(500);
+
 type InstanceImpl = <back::Backend as gfx_hal::Backend>::Instance;
 type PhysicalDeviceImpl = <back::Backend as gfx_hal::Backend>::PhysicalDevice;
 type DeviceImpl = <back::Backend as gfx_hal::Backend>::Device;
@@ -22,6 +29,8 @@ pub(super) type MemoryImpl = <back::Backend as gfx_hal::Backend>::Memory;
 pub(super) type BufferImpl = <back::Backend as gfx_hal::Backend>::Buffer;
 pub(super) type ImageImpl = <back::Backend as gfx_hal::Backend>::Image;
 pub(super) type ImageViewImpl = <back::Backend as gfx_hal::Backend>::ImageView;
+pub(super) type QueryPoolImpl = <back::Backend as gfx_hal::Backend>::QueryPool;
+pub(super) type CommandBufferImpl = <back::Backend as gfx_hal::Backend>::CommandBuffer;
 
 #[cfg(windows)]
 macro_rules! addr_of {
@@ -30,18 +39,33 @@ macro_rules! addr_of {
     };
 }
 
+// Returns None if the native format cannot be imported as a shared texture.
 #[cfg(any(target_os = "linux", target_os = "android"))]
-pub fn format_from_native(vulkan_format: u32) -> Format {
-    todo!();
+pub fn format_from_native(vulkan_format: u32) -> Option<Format> {
+    use ash::vk;
+    match vk::Format::from_raw(vulkan_format as _) {
+        vk::Format::R8G8B8A8_UNORM => Some(Format::Rgba8Unorm),
+        vk::Format::R8G8B8A8_SRGB => Some(Format::Rgba8Srgb),
+        vk::Format::B8G8R8A8_UNORM => Some(Format::Bgra8Unorm),
+        vk::Format::B8G8R8A8_SRGB => Some(Format::Bgra8Srgb),
+        vk::Format::A2B10G10R10_UNORM_PACK32 => Some(Format::A2b10g10r10Unorm),
+        vk::Format::R16G16B16A16_SFLOAT => Some(Format::Rgba16Sfloat),
+        _ => None,
+    }
 }
 
+// Returns None if the native format cannot be imported as a shared texture.
 #[cfg(windows)]
-pub fn format_from_native(dxgi_format: u32) -> Format {
+pub fn format_from_native(dxgi_format: u32) -> Option<Format> {
     use winapi::shared::dxgiformat::*;
     match dxgi_format {
-        DXGI_FORMAT_R8G8B8A8_UNORM => Format::Rgba8Unorm,
-        DXGI_FORMAT_R8G8B8A8_UNORM_SRGB => Format::Rgba8Srgb,
-        _ => Format::Rgba8Unorm,
+        DXGI_FORMAT_R8G8B8A8_UNORM => Some(Format::Rgba8Unorm),
+        DXGI_FORMAT_R8G8B8A8_UNORM_SRGB => Some(Format::Rgba8Srgb),
+        DXGI_FORMAT_B8G8R8A8_UNORM => Some(Format::Bgra8Unorm),
+        DXGI_FORMAT_B8G8R8A8_UNORM_SRGB => Some(Format::Bgra8Srgb),
+        DXGI_FORMAT_R10G10B10A2_UNORM => Some(Format::A2b10g10r10Unorm),
+        DXGI_FORMAT_R16G16B16A16_FLOAT => Some(Format::Rgba16Sfloat),
+        _ => None,
     }
 }
 
@@ -63,9 +87,10 @@ pub struct GraphicsContext {
     instance: InstanceImpl,
     physical_device: PhysicalDeviceImpl,
     pub(super) device: DeviceImpl,
-    pub(super) queue_group: QueueGroup<back::Backend>,
+    pub(super) queue_group: Mutex<QueueGroup<back::Backend>>,
     pub(super) memory_types: Vec<MemoryType>,
     pub(super) limits: Limits,
+    gpu_vendor: GpuVendor,
 }
 
 impl GraphicsContext {
@@ -80,6 +105,7 @@ impl GraphicsContext {
             debug!("{}: {:?}", i, adapter.info);
         }
         let adapter = adapters.remove(adapter_index);
+        let gpu_vendor = GpuVendor::from_pci_vendor_id(adapter.info.vendor);
         let physical_device = adapter.physical_device;
         let memory_types = physical_device.memory_properties().memory_types;
         let limits = physical_device.limits();
@@ -100,12 +126,17 @@ impl GraphicsContext {
             instance,
             physical_device,
             device,
-            queue_group,
+            queue_group: Mutex::new(queue_group),
             memory_types,
             limits,
+            gpu_vendor,
         })
     }
 
+    pub fn gpu_vendor(&self) -> GpuVendor {
+        self.gpu_vendor
+    }
+
     #[cfg(target_os = "linux")]
     pub fn from_vulkan_ptrs(
         instance_ptr: u64,
@@ -168,6 +199,54 @@ impl GraphicsContext {
     pub fn queue_family_index(&self) -> u32 {
         todo!()
     }
+
+    // Wait for the GPU to finish the work submitted until now
+    pub fn wait_idle(&self, timeout: Duration) -> StrResult {
+        release_after_completion(self, timeout, || ())
+    }
+}
+
+impl SubmittedWork for GraphicsContext {
+    // An empty submission with a fence is signaled after all the previous submissions to the queue
+    fn wait_completion(&self, timeout: Duration) -> StrResult<bool> {
+        let fence = trace_err_dbg!(self.device.create_fence(false))?;
+        unsafe {
+            self.queue_group.lock().queues[0]
+                .submit_without_semaphores(iter::empty::<&CommandBufferImpl>(), Some(&fence));
+            let completed =
+                trace_err_dbg!(self.device.wait_for_fence(&fence, timeout.as_nanos() as _))?;
+
+            // A fence still in use by the queue cannot be destroyed, it is leaked instead
+            if completed {
+                self.device.destroy_fence(fence);
+            }
+
+            Ok(completed)
+        }
+    }
+}
+
+// Work submitted to the GPU, that must complete before the resources it uses are destroyed
+pub trait SubmittedWork {
+    // Returns false if the work is still running after timeout
+    fn wait_completion(&self, timeout: Duration) -> StrResult<bool>;
+}
+
+// Call release only after the submitted work has completed. The wait is bounded by timeout, so a
+// hung GPU cannot block the shutdown. On timeout or error release is not called and the resources
+// are leaked, because destroying them while in use can trip the validation layers or crash the
+// driver.
+pub fn release_after_completion(
+    work: &impl SubmittedWork,
+    timeout: Duration,
+    release: impl FnOnce(),
+) -> StrResult {
+    if work.wait_completion(timeout)? {
+        release();
+        Ok(())
+    } else {
+        trace_str!("GPU still busy after {:?}", timeout)
+    }
 }
 
 // impl Drop for Graphics2DAbstractionLayer {
@@ -180,3 +259,54 @@ impl GraphicsContext {
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    struct MockSubmittedWork {
+        result: StrResult<bool>,
+        events: RefCell<Vec<&'static str>>,
+    }
+
+    impl MockSubmittedWork {
+        fn new(result: StrResult<bool>) -> Self {
+            Self {
+                result,
+                events: RefCell::new(vec![]),
+            }
+        }
+    }
+
+    impl SubmittedWork for MockSubmittedWork {
+        fn wait_completion(&self, _: Duration) -> StrResult<bool> {
+            self.events.borrow_mut().push("wait");
+            self.result.clone()
+        }
+    }
+
+    #[test]
+    fn resources_are_released_after_completion() {
+        let work = MockSubmittedWork::new(Ok(true));
+        let result = release_after_completion(&work, RELEASE_WAIT_TIMEOUT, || {
+            work.events.borrow_mut().push("release")
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(*work.events.borrow(), vec!["wait", "release"]);
+    }
+
+    #[test]
+    fn resources_in_use_are_not_released() {
+        for result in vec![Ok(false), Err("device lost".into())] {
+            let work = MockSubmittedWork::new(result);
+            let result = release_after_completion(&work, RELEASE_WAIT_TIMEOUT, || {
+                work.events.borrow_mut().push("release")
+            });
+
+            assert!(result.is_err());
+            assert_eq!(*work.events.borrow(), vec!["wait"]);
+        }
+    }
+}
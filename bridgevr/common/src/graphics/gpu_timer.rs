@@ -0,0 +1,159 @@
+use super::context::*;
+use crate::StrResult;
+use gfx_hal::{prelude::*, pso::PipelineStage, query};
+use std::{mem::ManuallyDrop, ptr, slice, sync::Arc};
+
+const BEGIN_QUERY_ID: query::Id = 0;
+const END_QUERY_ID: query::Id = 1;
+
+// Source of the GPU timestamps written around a stage.
+pub trait TimestampQueryProvider {
+    // Nanoseconds per timestamp tick. None if the backend does not support timestamp queries.
+    fn timestamp_period_ns(&self) -> Option<f32>;
+
+    // Begin and end ticks of the last execution.
+    fn read_timestamps(&self) -> StrResult<(u64, u64)>;
+}
+
+pub fn measured_gpu_ms(query_provider: &impl TimestampQueryProvider) -> StrResult<Option<f32>> {
+    if let Some(period_ns) = query_provider.timestamp_period_ns() {
+        let (begin_ticks, end_ticks) = query_provider.read_timestamps()?;
+        let elapsed_ns = end_ticks.saturating_sub(begin_ticks) as f64 * period_ns as f64;
+        Ok(Some((elapsed_ns / 1_000_000_f64) as f32))
+    } else {
+        Ok(None)
+    }
+}
+
+pub struct GpuTimer {
+    graphics: Arc<GraphicsContext>,
+    query_pool: Option<ManuallyDrop<QueryPoolImpl>>,
+}
+
+impl GpuTimer {
+    pub fn new(graphics: Arc<GraphicsContext>) -> StrResult<Self> {
+        let query_pool = if graphics.limits.timestamp_compute_and_graphics {
+            Some(ManuallyDrop::new(trace_err!(unsafe {
+                graphics.device.create_query_pool(query::Type::Timestamp, 2)
+            })?))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            graphics,
+            query_pool,
+        })
+    }
+
+    // Must be recorded before the timed commands. No-op if timestamp queries are not supported.
+    pub unsafe fn write_begin(&self, command_buffer: &mut CommandBufferImpl) {
+        if let Some(query_pool) = &self.query_pool {
+            command_buffer.reset_query_pool(query_pool, BEGIN_QUERY_ID..END_QUERY_ID + 1);
+            command_buffer.write_timestamp(
+                PipelineStage::TOP_OF_PIPE,
+                query::Query {
+                    pool: query_pool,
+                    id: BEGIN_QUERY_ID,
+                },
+            );
+        }
+    }
+
+    // Must be recorded after the timed commands. No-op if timestamp queries are not supported.
+    pub unsafe fn write_end(&self, command_buffer: &mut CommandBufferImpl) {
+        if let Some(query_pool) = &self.query_pool {
+            command_buffer.write_timestamp(
+                PipelineStage::BOTTOM_OF_PIPE,
+                query::Query {
+                    pool: query_pool,
+                    id: END_QUERY_ID,
+                },
+            );
+        }
+    }
+}
+
+impl TimestampQueryProvider for GpuTimer {
+    fn timestamp_period_ns(&self) -> Option<f32> {
+        self.query_pool
+            .as_ref()
+            .map(|_| self.graphics.limits.timestamp_period)
+    }
+
+    fn read_timestamps(&self) -> StrResult<(u64, u64)> {
+        let query_pool = trace_none!(self.query_pool.as_ref())?;
+
+        let mut ticks = [0_u64; 2];
+        trace_err!(unsafe {
+            self.graphics.device.get_query_pool_results(
+                query_pool,
+                BEGIN_QUERY_ID..END_QUERY_ID + 1,
+                slice::from_raw_parts_mut(ticks.as_mut_ptr() as *mut u8, 16),
+                8,
+                query::ResultFlags::BITS_64 | query::ResultFlags::WAIT,
+            )
+        })?;
+
+        Ok((ticks[0], ticks[1]))
+    }
+}
+
+impl Drop for GpuTimer {
+    fn drop(&mut self) {
+        if let Some(query_pool) = &self.query_pool {
+            unsafe {
+                self.graphics
+                    .device
+                    .destroy_query_pool(ManuallyDrop::into_inner(ptr::read(query_pool)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockQueryProvider {
+        timestamp_period_ns: Option<f32>,
+        timestamps: (u64, u64),
+    }
+
+    impl TimestampQueryProvider for MockQueryProvider {
+        fn timestamp_period_ns(&self) -> Option<f32> {
+            self.timestamp_period_ns
+        }
+
+        fn read_timestamps(&self) -> StrResult<(u64, u64)> {
+            Ok(self.timestamps)
+        }
+    }
+
+    #[test]
+    fn ticks_are_converted_to_ms() {
+        let query_provider = MockQueryProvider {
+            timestamp_period_ns: Some(2.),
+            timestamps: (1_000, 1_501_000),
+        };
+        assert_eq!(measured_gpu_ms(&query_provider).unwrap(), Some(3.));
+    }
+
+    #[test]
+    fn unsupported_timestamps_give_no_measure() {
+        let query_provider = MockQueryProvider {
+            timestamp_period_ns: None,
+            timestamps: (0, 1_000),
+        };
+        assert_eq!(measured_gpu_ms(&query_provider).unwrap(), None);
+    }
+
+    #[test]
+    fn wrapped_timestamps_give_zero() {
+        let query_provider = MockQueryProvider {
+            timestamp_period_ns: Some(1.),
+            timestamps: (1_000, 0),
+        };
+        assert_eq!(measured_gpu_ms(&query_provider).unwrap(), Some(0.));
+    }
+}
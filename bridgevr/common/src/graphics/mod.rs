@@ -1,4 +1,5 @@
 mod context;
+mod gpu_timer;
 mod texture;
 mod uniform_buffer;
 
@@ -7,6 +8,7 @@ use gfx_hal::{command::CommandBuffer, command::*, image::*, pool::*, prelude::*,
 use std::{mem::ManuallyDrop, sync::Arc};
 
 pub use context::*;
+pub use gpu_timer::*;
 pub use texture::*;
 pub use uniform_buffer::*;
 
@@ -29,6 +31,7 @@ pub enum OperationDesc {
 
 pub struct OperationBuffer {
     graphics: Arc<GraphicsContext>,
+    gpu_timer: GpuTimer,
     // command_pool: ManuallyDrop<CommandPool<back::Backend, Graphics>>,
     // command_buffer: ManuallyDrop<CommandBuffer<back::Backend, Graphics, MultiShot>>,
     // render_passes: Vec<<back::Backend as gfx_hal::Backend>::RenderPass>,
@@ -45,7 +48,7 @@ impl OperationBuffer {
 
         let mut command_pool = ManuallyDrop::new(trace_err!(unsafe {
             dev.create_command_pool(
-                graphics.queue_group.family,
+                graphics.queue_group.lock().family,
                 CommandPoolCreateFlags::RESET_INDIVIDUAL,
             )
         })?);
@@ -53,6 +56,9 @@ impl OperationBuffer {
         let mut command_buffer = unsafe { command_pool.allocate_one(command::Level::Primary) };
         unsafe { command_buffer.begin_primary(CommandBufferFlags::EMPTY) };
 
+        let gpu_timer = GpuTimer::new(graphics.clone())?;
+        unsafe { gpu_timer.write_begin(&mut command_buffer) };
+
         for op_desc in operation_descs {
             match op_desc {
                 OperationDesc::Rendering {
@@ -70,6 +76,8 @@ impl OperationBuffer {
             }
         }
 
+        unsafe { gpu_timer.write_end(&mut command_buffer) };
+
         // let bindings = [
         //     DescriptorSetLayoutBinding {
         //         binding: 0,
@@ -189,6 +197,7 @@ impl OperationBuffer {
 
         // Self {
         //     graphics,
+        //     gpu_timer,
         //     command_pool,
         //     command_buffer,
         //     fences,
@@ -200,11 +209,16 @@ impl OperationBuffer {
     pub fn execute(&self) {
         todo!();
     }
+
+    // Timestamps of the last execution, used to measure the GPU time spent on this buffer.
+    pub fn gpu_timer(&self) -> &GpuTimer {
+        &self.gpu_timer
+    }
 }
 
 // impl Drop for OperationBuffer {
 //     fn drop(&mut self) {
-//         if self.graphics.device.wait_idle().is_ok() {
+//         if self.graphics.wait_idle(RELEASE_WAIT_TIMEOUT).is_ok() {
 //             unsafe {
 //                 // for f in self.fences.drain(..) {
 //                 //     self.graphics.device.destroy_fence(f);
@@ -1,6 +1,7 @@
 use super::context::*;
 use crate::StrResult;
 use gfx_hal::{format::*, image::*, memory, prelude::*, *};
+use log::warn;
 use std::{
     mem::ManuallyDrop,
     sync::{atomic::*, Arc},
@@ -194,17 +195,21 @@ impl PartialEq for Texture {
 
 impl Drop for Texture {
     fn drop(&mut self) {
-        unsafe {
+        let graphics = self.graphics.clone();
+        let result = release_after_completion(&*graphics, RELEASE_WAIT_TIMEOUT, || unsafe {
             ManuallyDrop::drop(&mut self.image_view);
             ManuallyDrop::drop(&mut self.image_memory);
             ManuallyDrop::drop(&mut self.image_handle);
+        });
+        if let Err(e) = result {
+            warn!("Texture not released: {}", e);
         }
     }
 }
 
 // impl Drop for Texture {
 //     fn drop(&mut self) {
-//         if self.graphics.device.wait_idle().is_ok() {
+//         if self.graphics.wait_idle(RELEASE_WAIT_TIMEOUT).is_ok() {
 //             unsafe {
 //                 self.graphics
 //                     .device
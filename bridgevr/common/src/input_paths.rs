@@ -1,4 +1,8 @@
 use crate::data::*;
+use log::warn;
+use std::time::*;
+
+const OUT_OF_RANGE_WARNING_INTERVAL: Duration = Duration::from_secs(5);
 
 pub enum InputValue {
     Boolean(bool),
@@ -7,9 +11,229 @@ pub enum InputValue {
     Skeletal(),
 }
 
-pub fn input_device_data_to_str_value_map(
+type AnalogAxis = (&'static str, fn(f32) -> InputValue);
+
+// Client paths of a device. Bits of digital_buttons are matched against the digital input of the
+// device. The paths are mapped to OpenVR components by OpenvrTrackedDeviceDesc::input_mapping, so
+// a device that fits this layout is added with a new profile, without touching the input handling.
+pub struct DeviceProfile {
+    pub analog_axes: &'static [AnalogAxis],
+    pub digital_buttons: &'static [(&'static str, u32)],
+}
+
+impl DeviceProfile {
+    // analog_values are in the order of analog_axes
+    pub fn input_values(
+        &self,
+        analog_values: &[f32],
+        digital_input: u32,
+    ) -> Vec<(&'static str, InputValue)> {
+        let analog_inputs = self
+            .analog_axes
+            .iter()
+            .zip(analog_values)
+            .map(|((path, to_input_value), value)| (*path, to_input_value(*value)));
+        let digital_inputs = self
+            .digital_buttons
+            .iter()
+            .map(|(path, mask)| (*path, InputValue::Boolean(digital_input & mask != 0)));
+
+        analog_inputs.chain(digital_inputs).collect()
+    }
+}
+
+pub const GAMEPAD_PROFILE: DeviceProfile = DeviceProfile {
+    analog_axes: &[
+        ("/gamepad/left/joystick/x", InputValue::NormalizedTwoSided),
+        ("/gamepad/left/joystick/y", InputValue::NormalizedTwoSided),
+        ("/gamepad/right/joystick/x", InputValue::NormalizedTwoSided),
+        ("/gamepad/right/joystick/y", InputValue::NormalizedTwoSided),
+        (
+            "/gamepad/left/trigger/value",
+            InputValue::NormalizedOneSided,
+        ),
+        (
+            "/gamepad/right/trigger/value",
+            InputValue::NormalizedOneSided,
+        ),
+    ],
+    digital_buttons: &[
+        ("/gamepad/a/click", GamepadDigitalInput::A.bits() as _),
+        ("/gamepad/b/click", GamepadDigitalInput::B.bits() as _),
+        ("/gamepad/x/click", GamepadDigitalInput::X.bits() as _),
+        ("/gamepad/y/click", GamepadDigitalInput::Y.bits() as _),
+        (
+            "/gamepad/dpad/left/click",
+            GamepadDigitalInput::DPAD_LEFT.bits() as _,
+        ),
+        (
+            "/gamepad/dpad/right/click",
+            GamepadDigitalInput::DPAD_RIGHT.bits() as _,
+        ),
+        (
+            "/gamepad/dpad/up/click",
+            GamepadDigitalInput::DPAD_UP.bits() as _,
+        ),
+        (
+            "/gamepad/dpad/down/click",
+            GamepadDigitalInput::DPAD_DOWN.bits() as _,
+        ),
+        (
+            "/gamepad/left/joystick/click",
+            GamepadDigitalInput::JOYSTICK_LEFT_CLICK.bits() as _,
+        ),
+        (
+            "/gamepad/right/joystick/click",
+            GamepadDigitalInput::JOYSTICK_RIGHT_CLICK.bits() as _,
+        ),
+        (
+            "/gamepad/left/shoulder/click",
+            GamepadDigitalInput::SHOULDER_LEFT.bits() as _,
+        ),
+        (
+            "/gamepad/right/shoulder/click",
+            GamepadDigitalInput::SHOULDER_RIGHT.bits() as _,
+        ),
+        ("/gamepad/menu/click", GamepadDigitalInput::MENU.bits() as _),
+        ("/gamepad/view/click", GamepadDigitalInput::VIEW.bits() as _),
+        ("/gamepad/home/click", GamepadDigitalInput::HOME.bits() as _),
+    ],
+};
+
+pub const OCULUS_TOUCH_PROFILE: DeviceProfile = DeviceProfile {
+    analog_axes: &[
+        (
+            "/oculus_touch/left/joystick/x",
+            InputValue::NormalizedTwoSided,
+        ),
+        (
+            "/oculus_touch/left/joystick/y",
+            InputValue::NormalizedTwoSided,
+        ),
+        (
+            "/oculus_touch/right/joystick/x",
+            InputValue::NormalizedTwoSided,
+        ),
+        (
+            "/oculus_touch/right/joystick/y",
+            InputValue::NormalizedTwoSided,
+        ),
+        (
+            "/oculus_touch/left/trigger/value",
+            InputValue::NormalizedOneSided,
+        ),
+        (
+            "/oculus_touch/right/trigger/value",
+            InputValue::NormalizedOneSided,
+        ),
+        (
+            "/oculus_touch/left/grip/value",
+            InputValue::NormalizedOneSided,
+        ),
+        (
+            "/oculus_touch/right/grip/value",
+            InputValue::NormalizedOneSided,
+        ),
+    ],
+    digital_buttons: &[
+        (
+            "/oculus_touch/a/click",
+            OculusTouchDigitalInput::A_CLICK.bits() as _,
+        ),
+        (
+            "/oculus_touch/a/touch",
+            OculusTouchDigitalInput::A_TOUCH.bits() as _,
+        ),
+        (
+            "/oculus_touch/b/click",
+            OculusTouchDigitalInput::B_CLICK.bits() as _,
+        ),
+        (
+            "/oculus_touch/b/touch",
+            OculusTouchDigitalInput::B_TOUCH.bits() as _,
+        ),
+        (
+            "/oculus_touch/x/click",
+            OculusTouchDigitalInput::X_CLICK.bits() as _,
+        ),
+        (
+            "/oculus_touch/x/touch",
+            OculusTouchDigitalInput::X_TOUCH.bits() as _,
+        ),
+        (
+            "/oculus_touch/y/click",
+            OculusTouchDigitalInput::Y_CLICK.bits() as _,
+        ),
+        (
+            "/oculus_touch/y/touch",
+            OculusTouchDigitalInput::Y_TOUCH.bits() as _,
+        ),
+        (
+            "/oculus_touch/left/joystick/click",
+            OculusTouchDigitalInput::THUMBSTICK_LEFT_CLICK.bits() as _,
+        ),
+        (
+            "/oculus_touch/left/joystick/touch",
+            OculusTouchDigitalInput::THUMBSTICK_LEFT_TOUCH.bits() as _,
+        ),
+        (
+            "/oculus_touch/right/joystick/click",
+            OculusTouchDigitalInput::THUMBSTICK_RIGHT_CLICK.bits() as _,
+        ),
+        (
+            "/oculus_touch/right/joystick/touch",
+            OculusTouchDigitalInput::THUMBSTICK_RIGHT_TOUCH.bits() as _,
+        ),
+        (
+            "/oculus_touch/left/trigger/touch",
+            OculusTouchDigitalInput::TRIGGER_LEFT_TOUCH.bits() as _,
+        ),
+        (
+            "/oculus_touch/right/trigger/touch",
+            OculusTouchDigitalInput::TRIGGER_RIGHT_TOUCH.bits() as _,
+        ),
+        (
+            "/oculus_touch/menu/click",
+            OculusTouchDigitalInput::MENU.bits() as _,
+        ),
+        (
+            "/oculus_touch/home/click",
+            OculusTouchDigitalInput::HOME.bits() as _,
+        ),
+    ],
+};
+
+pub const OCULUS_GO_PROFILE: DeviceProfile = DeviceProfile {
+    analog_axes: &[
+        ("/oculus_go/trigger/value", InputValue::NormalizedOneSided),
+        ("/oculus_go/touchpad/x", InputValue::NormalizedTwoSided),
+        ("/oculus_go/touchpad/y", InputValue::NormalizedTwoSided),
+    ],
+    digital_buttons: &[
+        (
+            "/oculus_go/touchpad/click",
+            OculusGoDigitalInput::TOUCHPAD_CLICK.bits() as _,
+        ),
+        (
+            "/oculus_go/touchpad/touch",
+            OculusGoDigitalInput::TOUCHPAD_TOUCH.bits() as _,
+        ),
+        (
+            "/oculus_go/back/click",
+            OculusGoDigitalInput::BACK.bits() as _,
+        ),
+        (
+            "/oculus_go/home/click",
+            OculusGoDigitalInput::HOME.bits() as _,
+        ),
+    ],
+};
+
+// Built-in profile of the device, with its analog values in the order of the profile axes and its
+// digital input. None for devices without buttons or axes, like tracked hands
+pub fn device_profile_and_values(
     input_device_data: &InputDeviceData,
-) -> Vec<(&str, InputValue)> {
+) -> Option<(&'static DeviceProfile, Vec<f32>, u32)> {
     match input_device_data {
         InputDeviceData::Gamepad {
             thumbstick_left_horizontal,
@@ -19,96 +243,18 @@ pub fn input_device_data_to_str_value_map(
             trigger_left,
             trigger_right,
             digital_input,
-        } => vec![
-            (
-                "/gamepad/left/joystick/x",
-                InputValue::NormalizedTwoSided(*thumbstick_left_horizontal),
-            ),
-            (
-                "/gamepad/left/joystick/y",
-                InputValue::NormalizedTwoSided(*thumbstick_left_vertical),
-            ),
-            (
-                "/gamepad/right/joystick/x",
-                InputValue::NormalizedTwoSided(*thumbstick_right_horizontal),
-            ),
-            (
-                "/gamepad/right/joystick/y",
-                InputValue::NormalizedTwoSided(*thumbstick_right_vertical),
-            ),
-            (
-                "/gamepad/left/trigger/value",
-                InputValue::NormalizedOneSided(*trigger_left),
-            ),
-            (
-                "/gamepad/right/trigger/value",
-                InputValue::NormalizedOneSided(*trigger_right),
-            ),
-            (
-                "/gamepad/a/click",
-                InputValue::Boolean(digital_input.contains(GamepadDigitalInput::A)),
-            ),
-            (
-                "/gamepad/b/click",
-                InputValue::Boolean(digital_input.contains(GamepadDigitalInput::B)),
-            ),
-            (
-                "/gamepad/x/click",
-                InputValue::Boolean(digital_input.contains(GamepadDigitalInput::X)),
-            ),
-            (
-                "/gamepad/y/click",
-                InputValue::Boolean(digital_input.contains(GamepadDigitalInput::Y)),
-            ),
-            (
-                "/gamepad/dpad/left/click",
-                InputValue::Boolean(digital_input.contains(GamepadDigitalInput::DPAD_LEFT)),
-            ),
-            (
-                "/gamepad/dpad/right/click",
-                InputValue::Boolean(digital_input.contains(GamepadDigitalInput::DPAD_RIGHT)),
-            ),
-            (
-                "/gamepad/dpad/up/click",
-                InputValue::Boolean(digital_input.contains(GamepadDigitalInput::DPAD_UP)),
-            ),
-            (
-                "/gamepad/dpad/down/click",
-                InputValue::Boolean(digital_input.contains(GamepadDigitalInput::DPAD_DOWN)),
-            ),
-            (
-                "/gamepad/left/joystick/click",
-                InputValue::Boolean(
-                    digital_input.contains(GamepadDigitalInput::JOYSTICK_LEFT_CLICK),
-                ),
-            ),
-            (
-                "/gamepad/right/joystick/click",
-                InputValue::Boolean(
-                    digital_input.contains(GamepadDigitalInput::JOYSTICK_RIGHT_CLICK),
-                ),
-            ),
-            (
-                "/gamepad/left/shoulder/click",
-                InputValue::Boolean(digital_input.contains(GamepadDigitalInput::SHOULDER_LEFT)),
-            ),
-            (
-                "/gamepad/right/shoulder/click",
-                InputValue::Boolean(digital_input.contains(GamepadDigitalInput::SHOULDER_RIGHT)),
-            ),
-            (
-                "/gamepad/menu/click",
-                InputValue::Boolean(digital_input.contains(GamepadDigitalInput::MENU)),
-            ),
-            (
-                "/gamepad/view/click",
-                InputValue::Boolean(digital_input.contains(GamepadDigitalInput::VIEW)),
-            ),
-            (
-                "/gamepad/home/click",
-                InputValue::Boolean(digital_input.contains(GamepadDigitalInput::HOME)),
-            ),
-        ],
+        } => Some((
+            &GAMEPAD_PROFILE,
+            vec![
+                *thumbstick_left_horizontal,
+                *thumbstick_left_vertical,
+                *thumbstick_right_horizontal,
+                *thumbstick_right_vertical,
+                *trigger_left,
+                *trigger_right,
+            ],
+            digital_input.bits() as _,
+        )),
         InputDeviceData::OculusTouchPair {
             thumbstick_left_horizontal,
             thumbstick_left_vertical,
@@ -119,151 +265,222 @@ pub fn input_device_data_to_str_value_map(
             grip_left,
             grip_right,
             digital_input,
-        } => vec![
-            (
-                "/oculus_touch/left/joystick/x",
-                InputValue::NormalizedTwoSided(*thumbstick_left_horizontal),
-            ),
-            (
-                "/oculus_touch/left/joystick/y",
-                InputValue::NormalizedTwoSided(*thumbstick_left_vertical),
-            ),
-            (
-                "/oculus_touch/right/joystick/x",
-                InputValue::NormalizedTwoSided(*thumbstick_right_horizontal),
-            ),
-            (
-                "/oculus_touch/right/joystick/y",
-                InputValue::NormalizedTwoSided(*thumbstick_right_vertical),
-            ),
-            (
-                "/oculus_touch/left/trigger/value",
-                InputValue::NormalizedOneSided(*trigger_left),
-            ),
-            (
-                "/oculus_touch/right/trigger/value",
-                InputValue::NormalizedOneSided(*trigger_right),
-            ),
-            (
-                "/oculus_touch/left/grip/value",
-                InputValue::NormalizedOneSided(*grip_left),
-            ),
-            (
-                "/oculus_touch/right/grip/value",
-                InputValue::NormalizedOneSided(*grip_right),
-            ),
-            (
-                "/oculus_touch/a/click",
-                InputValue::Boolean(digital_input.contains(OculusTouchDigitalInput::A_CLICK)),
-            ),
-            (
-                "/oculus_touch/a/touch",
-                InputValue::Boolean(digital_input.contains(OculusTouchDigitalInput::A_TOUCH)),
-            ),
-            (
-                "/oculus_touch/b/click",
-                InputValue::Boolean(digital_input.contains(OculusTouchDigitalInput::B_CLICK)),
-            ),
-            (
-                "/oculus_touch/b/touch",
-                InputValue::Boolean(digital_input.contains(OculusTouchDigitalInput::B_TOUCH)),
-            ),
-            (
-                "/oculus_touch/x/click",
-                InputValue::Boolean(digital_input.contains(OculusTouchDigitalInput::X_CLICK)),
-            ),
-            (
-                "/oculus_touch/x/touch",
-                InputValue::Boolean(digital_input.contains(OculusTouchDigitalInput::X_TOUCH)),
-            ),
-            (
-                "/oculus_touch/y/click",
-                InputValue::Boolean(digital_input.contains(OculusTouchDigitalInput::Y_CLICK)),
-            ),
-            (
-                "/oculus_touch/y/touch",
-                InputValue::Boolean(digital_input.contains(OculusTouchDigitalInput::Y_TOUCH)),
-            ),
-            (
-                "/oculus_touch/left/joystick/click",
-                InputValue::Boolean(
-                    digital_input.contains(OculusTouchDigitalInput::THUMBSTICK_LEFT_CLICK),
-                ),
-            ),
-            (
-                "/oculus_touch/left/joystick/touch",
-                InputValue::Boolean(
-                    digital_input.contains(OculusTouchDigitalInput::THUMBSTICK_LEFT_TOUCH),
-                ),
-            ),
-            (
-                "/oculus_touch/right/joystick/click",
-                InputValue::Boolean(
-                    digital_input.contains(OculusTouchDigitalInput::THUMBSTICK_RIGHT_CLICK),
-                ),
-            ),
-            (
-                "/oculus_touch/right/joystick/touch",
-                InputValue::Boolean(
-                    digital_input.contains(OculusTouchDigitalInput::THUMBSTICK_RIGHT_TOUCH),
-                ),
-            ),
-            (
-                "/oculus_touch/left/trigger/touch",
-                InputValue::Boolean(
-                    digital_input.contains(OculusTouchDigitalInput::TRIGGER_LEFT_TOUCH),
-                ),
-            ),
-            (
-                "/oculus_touch/right/trigger/touch",
-                InputValue::Boolean(
-                    digital_input.contains(OculusTouchDigitalInput::TRIGGER_RIGHT_TOUCH),
-                ),
-            ),
-            (
-                "/oculus_touch/menu/click",
-                InputValue::Boolean(digital_input.contains(OculusTouchDigitalInput::MENU)),
-            ),
-            (
-                "/oculus_touch/home/click",
-                InputValue::Boolean(digital_input.contains(OculusTouchDigitalInput::HOME)),
-            ),
-        ],
+        } => Some((
+            &OCULUS_TOUCH_PROFILE,
+            vec![
+                *thumbstick_left_horizontal,
+                *thumbstick_left_vertical,
+                *thumbstick_right_horizontal,
+                *thumbstick_right_vertical,
+                *trigger_left,
+                *trigger_right,
+                *grip_left,
+                *grip_right,
+            ],
+            digital_input.bits() as _,
+        )),
         InputDeviceData::OculusGoController {
             trigger,
             touchpad_horizontal,
             touchpad_vertical,
             digital_input,
-        } => vec![
-            (
-                "/oculus_go/trigger/value",
-                InputValue::NormalizedOneSided(*trigger),
-            ),
-            (
-                "/oculus_go/touchpad/x",
-                InputValue::NormalizedTwoSided(*touchpad_horizontal),
-            ),
-            (
-                "/oculus_go/touchpad/y",
-                InputValue::NormalizedTwoSided(*touchpad_vertical),
-            ),
-            (
-                "/oculus_go/touchpad/click",
-                InputValue::Boolean(digital_input.contains(OculusGoDigitalInput::TOUCHPAD_CLICK)),
-            ),
-            (
-                "/oculus_go/touchpad/touch",
-                InputValue::Boolean(digital_input.contains(OculusGoDigitalInput::TOUCHPAD_TOUCH)),
-            ),
-            (
-                "/oculus_go/back/click",
-                InputValue::Boolean(digital_input.contains(OculusGoDigitalInput::BACK)),
-            ),
-            (
-                "/oculus_go/home/click",
-                InputValue::Boolean(digital_input.contains(OculusGoDigitalInput::HOME)),
-            ),
-        ],
-        _ => todo!(),
+        } => Some((
+            &OCULUS_GO_PROFILE,
+            vec![*trigger, *touchpad_horizontal, *touchpad_vertical],
+            digital_input.bits() as _,
+        )),
+        InputDeviceData::OculusHands(_) => None,
+    }
+}
+
+pub fn input_device_data_to_str_value_map(
+    input_device_data: &InputDeviceData,
+) -> Vec<(&'static str, InputValue)> {
+    match device_profile_and_values(input_device_data) {
+        Some((profile, analog_values, digital_input)) => {
+            profile.input_values(&analog_values, digital_input)
+        }
+        None => vec![],
+    }
+}
+
+fn input_type_range(input_type: &OpenvrInputType) -> Option<(f32, f32)> {
+    match input_type {
+        OpenvrInputType::NormalizedOneSided => Some((0., 1.)),
+        OpenvrInputType::NormalizedTwoSided => Some((-1., 1.)),
+        OpenvrInputType::Boolean | OpenvrInputType::Skeletal => None,
+    }
+}
+
+// Clamps analog values to the range declared in the input mapping, so a miscalibrated client axis
+// cannot push values out of range. Warnings are throttled.
+pub struct InputRangeEnforcer {
+    last_warning_time: Option<Instant>,
+}
+
+impl InputRangeEnforcer {
+    pub fn new() -> Self {
+        Self {
+            last_warning_time: None,
+        }
+    }
+
+    pub fn enforce(&mut self, path: &str, value: f32, input_type: &OpenvrInputType) -> f32 {
+        if let Some((min, max)) = input_type_range(input_type) {
+            if value < min || value > max || value.is_nan() {
+                let should_warn = self
+                    .last_warning_time
+                    .map(|time| time.elapsed() > OUT_OF_RANGE_WARNING_INTERVAL)
+                    .unwrap_or(true);
+                if should_warn {
+                    warn!("Input {} out of range [{}, {}]: {}", path, min, max, value);
+                    self.last_warning_time = Some(Instant::now());
+                }
+
+                return if value.is_nan() {
+                    0.
+                } else {
+                    value.clamp(min, max)
+                };
+            }
+        }
+
+        value
+    }
+}
+
+impl Default for InputRangeEnforcer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn analog_values_are_clamped() {
+        let mut enforcer = InputRangeEnforcer::new();
+        let one_sided = OpenvrInputType::NormalizedOneSided;
+        let two_sided = OpenvrInputType::NormalizedTwoSided;
+
+        assert_eq!(
+            enforcer.enforce("/input/trigger/value", 0.5, &one_sided),
+            0.5
+        );
+        assert_eq!(
+            enforcer.enforce("/input/trigger/value", 1.2, &one_sided),
+            1.
+        );
+        assert_eq!(
+            enforcer.enforce("/input/trigger/value", -0.1, &one_sided),
+            0.
+        );
+        assert_eq!(enforcer.enforce("/input/joystick/x", -1.5, &two_sided), -1.);
+        assert_eq!(
+            enforcer.enforce("/input/joystick/x", -0.5, &two_sided),
+            -0.5
+        );
+    }
+
+    #[test]
+    fn nan_becomes_zero() {
+        let mut enforcer = InputRangeEnforcer::new();
+        let value = enforcer.enforce(
+            "/input/joystick/x",
+            f32::NAN,
+            &OpenvrInputType::NormalizedTwoSided,
+        );
+        assert_eq!(value, 0.);
+    }
+
+    #[test]
+    fn boolean_and_skeletal_values_are_not_clamped() {
+        let mut enforcer = InputRangeEnforcer::new();
+        assert_eq!(
+            enforcer.enforce("/input/a/click", 2., &OpenvrInputType::Boolean),
+            2.
+        );
+        assert_eq!(
+            enforcer.enforce("/input/skeleton", -3., &OpenvrInputType::Skeletal),
+            -3.
+        );
+    }
+
+    fn oculus_go_data() -> InputDeviceData {
+        InputDeviceData::OculusGoController {
+            trigger: 0.25,
+            touchpad_horizontal: -0.5,
+            touchpad_vertical: 0.75,
+            digital_input: OculusGoDigitalInput::TOUCHPAD_CLICK,
+        }
+    }
+
+    #[test]
+    fn profiles_have_one_value_per_axis() {
+        let devices_data = vec![
+            InputDeviceData::Gamepad {
+                thumbstick_left_horizontal: 0.,
+                thumbstick_left_vertical: 0.,
+                thumbstick_right_horizontal: 0.,
+                thumbstick_right_vertical: 0.,
+                trigger_left: 0.,
+                trigger_right: 0.,
+                digital_input: GamepadDigitalInput::empty(),
+            },
+            InputDeviceData::OculusTouchPair {
+                thumbstick_left_horizontal: 0.,
+                thumbstick_left_vertical: 0.,
+                thumbstick_right_horizontal: 0.,
+                thumbstick_right_vertical: 0.,
+                trigger_left: 0.,
+                trigger_right: 0.,
+                grip_left: 0.,
+                grip_right: 0.,
+                digital_input: OculusTouchDigitalInput::empty(),
+            },
+            oculus_go_data(),
+        ];
+
+        for device_data in &devices_data {
+            let (profile, analog_values, _) = device_profile_and_values(device_data).unwrap();
+            assert_eq!(profile.analog_axes.len(), analog_values.len());
+        }
+    }
+
+    #[test]
+    fn profile_maps_values_to_paths() {
+        let values = input_device_data_to_str_value_map(&oculus_go_data());
+        let value = |path| {
+            values
+                .iter()
+                .find(|(value_path, _)| *value_path == path)
+                .map(|(_, value)| value)
+        };
+
+        assert!(matches!(
+            value("/oculus_go/trigger/value"),
+            Some(InputValue::NormalizedOneSided(v)) if *v == 0.25
+        ));
+        assert!(matches!(
+            value("/oculus_go/touchpad/x"),
+            Some(InputValue::NormalizedTwoSided(v)) if *v == -0.5
+        ));
+        assert!(matches!(
+            value("/oculus_go/touchpad/click"),
+            Some(InputValue::Boolean(true))
+        ));
+        assert!(matches!(
+            value("/oculus_go/touchpad/touch"),
+            Some(InputValue::Boolean(false))
+        ));
+    }
+
+    #[test]
+    fn hands_have_no_profile() {
+        let hands_data = InputDeviceData::OculusHands([vec![], vec![]]);
+        assert!(device_profile_and_values(&hands_data).is_none());
+        assert!(input_device_data_to_str_value_map(&hands_data).is_empty());
     }
 }
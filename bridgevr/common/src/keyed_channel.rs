@@ -0,0 +1,124 @@
+use crate::*;
+use parking_lot::{Condvar, Mutex};
+use std::{collections::BTreeMap, sync::Arc, time::*};
+
+const TRACE_CONTEXT: &str = "Keyed channel";
+
+struct KeyedBuffer<K, V> {
+    values: BTreeMap<K, V>,
+    // Values with a key lower than this are stale and are dropped
+    min_key: Option<K>,
+}
+
+struct KeyedChannel<K, V> {
+    buffer: Mutex<KeyedBuffer<K, V>>,
+    condvar: Condvar,
+}
+
+pub struct KeyedProducer<K, V> {
+    channel: Arc<KeyedChannel<K, V>>,
+}
+
+impl<K: Ord + Copy, V> KeyedProducer<K, V> {
+    // Values can be sent in any order. A value with an already used key replaces the old one.
+    pub fn send(&self, key: K, value: V) {
+        let mut buffer = self.channel.buffer.lock();
+        if matches!(buffer.min_key, Some(min_key) if key < min_key) {
+            return;
+        }
+        buffer.values.insert(key, value);
+        self.channel.condvar.notify_one();
+    }
+}
+
+pub struct KeyedConsumer<K, V> {
+    channel: Arc<KeyedChannel<K, V>>,
+    timeout: Duration,
+}
+
+impl<K: Ord + Copy, V> KeyedConsumer<K, V> {
+    // Wait for the value with the given key. The values with lower keys are dropped, even on
+    // timeout, so keys must be requested in increasing order.
+    pub fn recv(&self, key: K) -> StrResult<V> {
+        let deadline = Instant::now() + self.timeout;
+        let mut buffer = self.channel.buffer.lock();
+        buffer.min_key = Some(key);
+        buffer.values = buffer.values.split_off(&key);
+
+        loop {
+            if let Some(value) = buffer.values.remove(&key) {
+                return Ok(value);
+            }
+            let wait_result = self.channel.condvar.wait_until(&mut buffer, deadline);
+            if wait_result.timed_out() {
+                return trace_str!("Timeout");
+            }
+        }
+    }
+}
+
+// Reorder values sent from another thread. The key is for example an audio buffer index or a
+// (frame index, slice index) pair.
+pub fn keyed_channel_split<K: Ord + Copy, V>(
+    timeout: Duration,
+) -> (KeyedProducer<K, V>, KeyedConsumer<K, V>) {
+    let channel = Arc::new(KeyedChannel {
+        buffer: Mutex::new(KeyedBuffer {
+            values: BTreeMap::new(),
+            min_key: None,
+        }),
+        condvar: Condvar::new(),
+    });
+
+    (
+        KeyedProducer {
+            channel: channel.clone(),
+        },
+        KeyedConsumer { channel, timeout },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn values_are_received_by_key() {
+        let (producer, consumer) = keyed_channel_split(TIMEOUT);
+        producer.send(2, "b");
+        producer.send(1, "a");
+        producer.send(3, "x");
+        producer.send(3, "c");
+
+        assert_eq!(consumer.recv(1), Ok("a"));
+        assert_eq!(consumer.recv(2), Ok("b"));
+        assert_eq!(consumer.recv(3), Ok("c"));
+    }
+
+    #[test]
+    fn recv_waits_for_value() {
+        let (producer, consumer) = keyed_channel_split(Duration::from_secs(5));
+        let producer_thread = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            producer.send((0, 1), 10);
+        });
+
+        assert_eq!(consumer.recv((0, 1)), Ok(10));
+        producer_thread.join().unwrap();
+    }
+
+    #[test]
+    fn lower_keys_are_dropped_on_timeout() {
+        let (producer, consumer) = keyed_channel_split(TIMEOUT);
+        producer.send(1, "a");
+
+        assert!(consumer.recv(2).is_err());
+        producer.send(1, "late");
+        producer.send(2, "b");
+        assert_eq!(consumer.recv(2), Ok("b"));
+        assert!(consumer.recv(1).is_err());
+    }
+}
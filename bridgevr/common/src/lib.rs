@@ -1,14 +1,18 @@
 #[macro_use]
 pub mod logging;
-pub use logging::StrResult;
+pub use logging::{ResultExt, StrResult};
 
 pub mod audio;
 pub mod data;
+pub mod encryption;
 pub mod event_timing;
 pub mod ffr;
 pub mod frame_slices;
 pub mod graphics;
 pub mod input_paths;
+pub mod keyed_channel;
+pub mod prelude;
 pub mod sockets;
 pub mod thread_loop;
 pub mod timeout_map;
+pub mod video_packets;
@@ -1,26 +1,124 @@
+use parking_lot::Mutex;
+use std::{cell::Cell, collections::VecDeque, fmt::Display, panic::Location, sync::Once, thread};
+
 pub type StrResult<T = ()> = Result<T, String>;
 
 fn default_show_error_fn(_: &str) {}
 pub static mut _SHOW_ERROR_CB: fn(&str) = default_show_error_fn;
 
+static INSTALL_PANIC_HOOK: Once = Once::new();
+
+thread_local! {
+    static IN_PANIC_HOOK: Cell<bool> = const { Cell::new(false) };
+}
+
+// The panic is logged before the default hook runs, because SteamVR does not show stderr. A panic
+// inside the hook on the same thread (for example in a logger) skips logging and runs only the
+// default hook. The hook is installed only once, later calls replace only the error callback.
 pub fn set_show_error_fn(cb: fn(&str)) {
     unsafe { _SHOW_ERROR_CB = cb };
-    std::panic::set_hook(Box::new(|panic_info| {
-        let message = panic_info
-            .payload()
-            .downcast_ref::<&str>()
-            .unwrap_or(&"Unavailable");
+    INSTALL_PANIC_HOOK.call_once(install_panic_hook);
+}
+
+fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |panic_info| {
+        if IN_PANIC_HOOK.with(|in_panic_hook| in_panic_hook.replace(true)) {
+            default_hook(panic_info);
+            return;
+        }
+
+        let payload = panic_info.payload();
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            message
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.as_str()
+        } else {
+            "Unavailable"
+        };
+        let location = panic_info
+            .location()
+            .map(|location| format!("{}:{}", location.file(), location.line()))
+            .unwrap_or_else(|| "Unknown".into());
 
         let err_str = format!(
-            "BridgeVR panicked.\nMessage: {:?}\nBacktrace:\n{:?}",
+            "BridgeVR panicked in thread {:?} at {}.\nMessage: {:?}\nBacktrace:\n{:?}",
+            thread::current().name().unwrap_or("unnamed"),
+            location,
             message,
             backtrace::Backtrace::new()
         );
         log::error!("{}", err_str);
+        log::logger().flush();
+
+        default_hook(panic_info);
+
         unsafe { _SHOW_ERROR_CB(&err_str) };
+        IN_PANIC_HOOK.with(|in_panic_hook| in_panic_hook.set(false));
     }))
 }
 
+#[derive(Clone, Debug)]
+pub struct LogEntry {
+    pub level: log::Level,
+    pub target: String,
+    // Already formatted by the logging backend
+    pub message: String,
+}
+
+// In-memory sink for the most recent log records, so that they can be shown without reading the log
+// file. The oldest records are evicted when the capacity is reached.
+pub struct LogRingBuffer {
+    entries: Mutex<VecDeque<LogEntry>>,
+    capacity: usize,
+}
+
+impl LogRingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn push(&self, record: &log::Record) {
+        let mut entries = self.entries.lock();
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(LogEntry {
+            level: record.level(),
+            target: record.target().to_owned(),
+            message: record.args().to_string(),
+        });
+    }
+
+    // Oldest first
+    pub fn snapshot(&self) -> Vec<LogEntry> {
+        self.entries.lock().iter().cloned().collect()
+    }
+
+    // Appends the last warnings and errors to an error message, to give it some context when it is
+    // shown to the user. The message itself is usually the last error logged, so it is skipped.
+    pub fn append_recent_warnings(&self, message: &str, max_count: usize) -> String {
+        let entries = self.entries.lock();
+        let mut warnings = entries
+            .iter()
+            .rev()
+            .filter(|entry| entry.level <= log::Level::Warn && entry.message != message)
+            .take(max_count)
+            .map(|entry| format!("[{}] {}", entry.level, entry.message))
+            .collect::<Vec<_>>();
+
+        if warnings.is_empty() {
+            message.to_owned()
+        } else {
+            warnings.reverse();
+            format!("{}\n\nRecent warnings:\n{}", message, warnings.join("\n"))
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! trace_str {
     ($expect_fmt:expr $(, $args:expr)*) => {
@@ -62,6 +160,28 @@ macro_rules! trace_none {
     };
 }
 
+// Method form of trace_err!, for call chains. The error string is the same as with trace_err!, with
+// the location of the trace_ctx() call.
+pub trait ResultExt<T> {
+    fn trace_ctx(self, trace_context: &str) -> StrResult<T>;
+}
+
+impl<T, E: Display> ResultExt<T> for Result<T, E> {
+    #[track_caller]
+    fn trace_ctx(self, trace_context: &str) -> StrResult<T> {
+        let location = Location::caller();
+        self.map_err(|e| {
+            format!(
+                "[{}] At {}:{}:\n{}",
+                trace_context,
+                location.file(),
+                location.line(),
+                e
+            )
+        })
+    }
+}
+
 #[macro_export]
 macro_rules! show_err {
     ($res:expr) => {
@@ -71,3 +191,143 @@ macro_rules! show_err {
         })
     };
 }
+
+// Records the level and message of the log records of the calling thread while f runs. Tests run in
+// parallel on different threads, so each test only sees its own records.
+#[cfg(test)]
+pub(crate) fn capture_logs<T>(f: impl FnOnce() -> T) -> (T, Vec<(log::Level, String)>) {
+    use std::{cell::RefCell, sync::Once};
+
+    thread_local! {
+        static CAPTURED_RECORDS: RefCell<Option<Vec<(log::Level, String)>>> =
+            const { RefCell::new(None) };
+    }
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED_RECORDS.with(|records| {
+                if let Some(records) = &mut *records.borrow_mut() {
+                    records.push((record.level(), record.args().to_string()));
+                }
+            })
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE_LOGGER: CaptureLogger = CaptureLogger;
+    static INIT_CAPTURE_LOGGER: Once = Once::new();
+
+    INIT_CAPTURE_LOGGER.call_once(|| {
+        log::set_logger(&CAPTURE_LOGGER).ok();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+
+    CAPTURED_RECORDS.with(|records| *records.borrow_mut() = Some(vec![]));
+    let result = f();
+    let records = CAPTURED_RECORDS.with(|records| records.borrow_mut().take());
+
+    (result, records.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{Level, Record};
+
+    fn push_message(buffer: &LogRingBuffer, level: Level, index: usize) {
+        buffer.push(
+            &Record::builder()
+                .args(format_args!("message {}", index))
+                .level(level)
+                .target("test")
+                .build(),
+        );
+    }
+
+    #[test]
+    fn records_are_formatted() {
+        let buffer = LogRingBuffer::new(4);
+        push_message(&buffer, Level::Warn, 0);
+
+        let entries = buffer.snapshot();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].level, Level::Warn);
+        assert_eq!(entries[0].target, "test");
+        assert_eq!(entries[0].message, "message 0");
+    }
+
+    #[test]
+    fn oldest_records_are_evicted_at_capacity() {
+        let buffer = LogRingBuffer::new(3);
+        for index in 0..5 {
+            push_message(&buffer, Level::Info, index);
+        }
+
+        let messages = buffer
+            .snapshot()
+            .into_iter()
+            .map(|entry| entry.message)
+            .collect::<Vec<_>>();
+        assert_eq!(messages, vec!["message 2", "message 3", "message 4"]);
+    }
+
+    #[test]
+    fn recent_warnings_are_appended_to_errors() {
+        let buffer = LogRingBuffer::new(10);
+        assert_eq!(buffer.append_recent_warnings("crash", 2), "crash");
+
+        push_message(&buffer, Level::Warn, 0);
+        push_message(&buffer, Level::Info, 1);
+        push_message(&buffer, Level::Error, 2);
+        push_message(&buffer, Level::Warn, 3);
+        push_message(&buffer, Level::Error, 4);
+        assert_eq!(
+            buffer.append_recent_warnings("message 4", 2),
+            "message 4\n\nRecent warnings:\n[ERROR] message 2\n[WARN] message 3"
+        );
+    }
+
+    #[test]
+    fn panics_in_other_threads_are_logged() {
+        fn show_error(_: &str) {}
+        // Calling it again only replaces the error callback
+        set_show_error_fn(show_error);
+        set_show_error_fn(show_error);
+
+        let records = thread::spawn(|| {
+            capture_logs(|| std::panic::catch_unwind(|| panic!("test panic")).ok()).1
+        })
+        .join()
+        .unwrap();
+
+        let errors = records
+            .iter()
+            .filter(|(level, message)| *level == Level::Error && message.contains("test panic"))
+            .count();
+        assert_eq!(errors, 1);
+    }
+
+    #[test]
+    fn error_context_includes_caller_location() {
+        let line = line!() + 1;
+        let result: StrResult<()> = Err("inner error").trace_ctx("Test context");
+
+        assert_eq!(
+            result.unwrap_err(),
+            format!("[Test context] At {}:{}:\ninner error", file!(), line)
+        );
+    }
+
+    #[test]
+    fn ok_value_is_kept() {
+        let result: Result<u32, String> = Ok(3);
+        assert_eq!(result.trace_ctx("Test context"), Ok(3));
+    }
+}
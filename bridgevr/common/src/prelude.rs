@@ -0,0 +1,42 @@
+// Items used by most BridgeVR crates. Import them with `use bridgevr_common::prelude::*;` instead of
+// globbing whole modules. Everything else is imported from its module.
+
+pub use crate::{
+    data::{load_settings, Fov, FovKey, Pose, SessionDesc, Settings, TrackedDeviceType},
+    logging::{ResultExt, StrResult},
+    show_err, trace_err, trace_err_dbg, trace_none, trace_str,
+};
+pub use settings_schema::Switch;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_CONTEXT: &str = "Prelude";
+
+    fn parse_port(text: &str) -> StrResult<u16> {
+        trace_err!(text.parse::<u16>(), "Invalid port")
+    }
+
+    #[test]
+    fn error_helpers_are_exported() {
+        assert_eq!(parse_port("9944"), Ok(9944));
+
+        let err = parse_port("port").unwrap_err();
+        assert!(err.starts_with("[Prelude] At "));
+        assert!(err.contains("Invalid port:\n"));
+
+        let missing: StrResult<u16> = trace_none!(None, "No port");
+        assert!(missing.unwrap_err().ends_with(", No port"));
+    }
+
+    #[test]
+    fn data_types_are_exported() {
+        let head_pose = Switch::Enabled(Pose {
+            position: [0., 1.6, 0.],
+            orientation: [0., 0., 0., 1.],
+        });
+        assert!(matches!(head_pose, Switch::Enabled(Pose { position, .. }) if position[1] == 1.6));
+        assert_eq!(TrackedDeviceType::HMD as i32, 0);
+    }
+}
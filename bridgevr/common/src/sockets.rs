@@ -1,26 +1,50 @@
-// use crate::{data::*, thread_loop::ThreadLoop, *};
+use crate::{data::*, *};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use log::*;
+use semver::{Version, VersionReq};
+use serde::{de::DeserializeOwned, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    net::*,
+    thread,
+    time::*,
+};
+// use crate::{encryption::*, thread_loop::ThreadLoop};
 // use laminar::{Config, LinkConditioner, Packet, Socket, SocketEvent};
-// use log::*;
 // use parking_lot::Mutex;
 // use serde::{de::*, *};
 // use std::{
 //     cmp::*,
 //     collections::*,
-//     net::*,
 //     sync::{mpsc::*, Arc},
-//     time::*,
 // };
 
-// const TRACE_CONTEXT: &str = "Sockets";
+const TRACE_CONTEXT: &str = "Sockets";
 
-// pub const MAX_HANDSHAKE_PACKET_SIZE_BYTES: usize = 4_000;
+pub const MAX_HANDSHAKE_PACKET_SIZE_BYTES: usize = 4_000;
 
-// const LOCAL_IP: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
-// const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 123);
+const LOCAL_IP: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 123);
 
-// const HANDSHAKE_PORT: u16 = 9943;
+const HANDSHAKE_PORT: u16 = 9943;
 
-// const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+const LISTENER_BACKLOG: i32 = 128;
+
+// Control channel messages larger than this are compressed
+const CONTROL_COMPRESSION_THRESHOLD_BYTES: usize = 1024;
+
+const CONTROL_MESSAGE_UNCOMPRESSED: u8 = 0;
+const CONTROL_MESSAGE_DEFLATE: u8 = 1;
+
+// Larger sizes are treated as a corrupted stream instead of being allocated
+const MAX_CONTROL_FRAME_SIZE_BYTES: usize = 16 * 1024 * 1024;
+
+const SEND_MAX_ATTEMPTS: usize = 3;
+const SEND_RETRY_INTERVAL: Duration = Duration::from_millis(20);
 
 // #[derive(Serialize, Deserialize)]
 // pub enum StreamType {
@@ -45,48 +69,227 @@
 //     }
 // }
 
-// pub fn search_client(
-//     client_ip: Option<String>,
-//     timeout: Duration,
-// ) -> StrResult<(IpAddr, ClientHandshakePacket)> {
-//     let deadline = Instant::now() + timeout;
-
-//     let listener = trace_err!(UdpSocket::bind(SocketAddr::new(LOCAL_IP, HANDSHAKE_PORT)))?;
-//     trace_err!(listener.join_multicast_v4(&MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED))?;
-//     trace_err!(listener.set_read_timeout(Some(HANDSHAKE_TIMEOUT)))?;
-
-//     let maybe_target_client_ip = match client_ip {
-//         Some(ip_str) => Some(trace_err!(ip_str.parse::<IpAddr>(), "Client IP")?),
-//         None => None,
-//     };
-
-//     let mut packet_buffer = [0u8; MAX_HANDSHAKE_PACKET_SIZE_BYTES];
-//     let mut try_find_client = || -> Result<(IpAddr, ClientHandshakePacket), ()> {
-//         let (hanshake_packet_size, address) = listener
-//             .recv_from(&mut packet_buffer)
-//             .map_err(|e| debug!("No handshake packet received: {}", e))?;
-
-//         if let Some(ip) = maybe_target_client_ip {
-//             if address.ip() != ip {
-//                 info!("Found client with wrong IP");
-//                 return Err(());
-//             }
-//         }
-
-//         let client_handshake_packet = bincode::deserialize(&packet_buffer[..hanshake_packet_size])
-//             .map_err(|e| warn!("Received handshake packet: {}", e))?;
-
-//         Ok((address.ip(), client_handshake_packet))
-//     };
-
-//     loop {
-//         if let Ok(pair) = try_find_client() {
-//             break Ok(pair);
-//         } else if Instant::now() > deadline {
-//             break Err("No valid client found".into());
-//         }
-//     }
-// }
+// Abstraction over the socket used for client discovery. Tests can implement this to feed crafted
+// handshake packets without touching the network.
+pub trait DiscoveryTransport {
+    // Blocks for at most HANDSHAKE_TIMEOUT (or the timeout chosen by the implementor)
+    fn recv(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    fn send(&mut self, buffer: &[u8], address: SocketAddr) -> io::Result<usize>;
+}
+
+impl DiscoveryTransport for UdpSocket {
+    fn recv(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.recv_from(buffer)
+    }
+
+    fn send(&mut self, buffer: &[u8], address: SocketAddr) -> io::Result<usize> {
+        self.send_to(buffer, address)
+    }
+}
+
+fn is_client_version_compatible(version: &str) -> bool {
+    // unwrap never fails
+    let version_req = VersionReq::parse(BVR_CLIENT_VERSION_REQ).unwrap();
+    Version::parse(version)
+        .map(|version| version_req.matches(&version))
+        .unwrap_or(false)
+}
+
+// Without client_ip, the first compatible client is returned as soon as it is found. With client_ip,
+// that client is returned alone as soon as it is found. If it is not found within timeout, all the
+// other clients found are returned instead, one per IP with its last handshake packet, sorted by IP
+// so that the choice of the caller is deterministic.
+pub fn search_clients_with_transport(
+    transport: &mut impl DiscoveryTransport,
+    client_ip: Option<String>,
+    timeout: Duration,
+) -> StrResult<Vec<(IpAddr, ClientHandshakePacket)>> {
+    let deadline = Instant::now() + timeout;
+
+    let maybe_target_client_ip = match client_ip {
+        Some(ip_str) => Some(trace_err!(ip_str.parse::<IpAddr>(), "Client IP")?),
+        None => None,
+    };
+
+    let mut packet_buffer = [0u8; MAX_HANDSHAKE_PACKET_SIZE_BYTES];
+    let mut try_find_client = || -> Result<(IpAddr, ClientHandshakePacket), ()> {
+        let (hanshake_packet_size, address) = transport
+            .recv(&mut packet_buffer)
+            .map_err(|e| debug!("No handshake packet received: {}", e))?;
+
+        let client_handshake_packet: ClientHandshakePacket =
+            bincode::deserialize(&packet_buffer[..hanshake_packet_size])
+                .map_err(|e| warn!("Received handshake packet: {}", e))?;
+
+        if !is_client_version_compatible(&client_handshake_packet.version) {
+            warn!(
+                "Expected client version {}, found {}",
+                BVR_CLIENT_VERSION_REQ, client_handshake_packet.version
+            );
+            return Err(());
+        }
+
+        Ok((address.ip(), client_handshake_packet))
+    };
+
+    let mut clients = BTreeMap::new();
+    loop {
+        if let Ok((ip, client_handshake_packet)) = try_find_client() {
+            if maybe_target_client_ip.is_none() || maybe_target_client_ip == Some(ip) {
+                return Ok(vec![(ip, client_handshake_packet)]);
+            }
+            clients.insert(ip, client_handshake_packet);
+        }
+        if Instant::now() > deadline {
+            break;
+        }
+    }
+
+    if clients.is_empty() {
+        return trace_str!("No valid client found");
+    }
+    if let Some(ip) = maybe_target_client_ip {
+        warn!("Client {} not found, other clients are available", ip);
+    }
+    for (ip, handshake_packet) in &clients {
+        info!("Found client {} ({})", handshake_packet.bridgevr_name, ip);
+    }
+
+    Ok(clients.into_iter().collect())
+}
+
+pub fn search_clients(
+    client_ip: Option<String>,
+    timeout: Duration,
+) -> StrResult<Vec<(IpAddr, ClientHandshakePacket)>> {
+    let mut listener = trace_err!(UdpSocket::bind(SocketAddr::new(LOCAL_IP, HANDSHAKE_PORT)))?;
+    trace_err!(listener.join_multicast_v4(&MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED))?;
+    trace_err!(listener.set_read_timeout(Some(HANDSHAKE_TIMEOUT)))?;
+
+    search_clients_with_transport(&mut listener, client_ip, timeout)
+}
+
+// On a quick reconnection the listener port can still be held by the previous connection in
+// TIME_WAIT state. SO_REUSEADDR is set only on unix, where it still fails if another socket is
+// listening on the same port. On Windows it would allow stealing ports in use, and binding over
+// TIME_WAIT already succeeds without it.
+pub fn bind_reusable_tcp_listener(address: SocketAddr) -> StrResult<TcpListener> {
+    let domain = if address.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = trace_err!(Socket::new(domain, Type::stream(), Some(Protocol::tcp())))?;
+
+    #[cfg(unix)]
+    trace_err!(socket.set_reuse_address(true))?;
+
+    match socket.bind(&address.into()) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            return trace_str!("Port {} is in use by another application", address.port());
+        }
+        Err(e) => return trace_err!(Err(e)),
+    }
+    trace_err!(socket.listen(LISTENER_BACKLOG))?;
+
+    Ok(socket.into_tcp_listener())
+}
+
+// Serialize a control channel message, prefixed by a byte that tells if it is compressed. Use only
+// if the peer supports compression (ClientHandshakePacket::supports_control_compression). Send the
+// result with write_control_frame(), or use write_control_message().
+pub fn encode_control_message<T: Serialize>(message: &T) -> StrResult<Vec<u8>> {
+    let payload = trace_err!(bincode::serialize(message))?;
+    if payload.len() > CONTROL_COMPRESSION_THRESHOLD_BYTES {
+        let mut encoder = DeflateEncoder::new(vec![CONTROL_MESSAGE_DEFLATE], Compression::fast());
+        trace_err!(encoder.write_all(&payload))?;
+        trace_err!(encoder.finish())
+    } else {
+        let mut buffer = vec![CONTROL_MESSAGE_UNCOMPRESSED];
+        buffer.extend(payload);
+        Ok(buffer)
+    }
+}
+
+pub fn decode_control_message<T: DeserializeOwned>(buffer: &[u8]) -> StrResult<T> {
+    match buffer.split_first() {
+        Some((&CONTROL_MESSAGE_UNCOMPRESSED, payload)) => trace_err!(bincode::deserialize(payload)),
+        Some((&CONTROL_MESSAGE_DEFLATE, payload)) => {
+            trace_err!(bincode::deserialize_from(DeflateDecoder::new(payload)))
+        }
+        Some((encoding, _)) => trace_str!("Unknown control message encoding {}", encoding),
+        None => trace_str!("Empty control message"),
+    }
+}
+
+// Control channel messages are prefixed by their size (u32, little endian), so that the receiver can
+// split the TCP byte stream back into messages, whatever the segmentation.
+pub fn write_control_frame(writer: &mut impl Write, payload: &[u8]) -> StrResult {
+    if payload.len() > MAX_CONTROL_FRAME_SIZE_BYTES {
+        return trace_str!("Control message too big: {} bytes", payload.len());
+    }
+
+    trace_err!(writer.write_all(&(payload.len() as u32).to_le_bytes()))?;
+    trace_err!(writer.write_all(payload))
+}
+
+// Blocks until a whole frame is read. A TCP read can return part of a frame or more than one frame:
+// only the bytes of this frame are consumed.
+pub fn read_control_frame(reader: &mut impl Read) -> StrResult<Vec<u8>> {
+    let mut size_bytes = [0; 4];
+    trace_err!(reader.read_exact(&mut size_bytes))?;
+    let size = u32::from_le_bytes(size_bytes) as usize;
+    if size > MAX_CONTROL_FRAME_SIZE_BYTES {
+        return trace_str!("Invalid control message size: {} bytes", size);
+    }
+
+    let mut payload = vec![0; size];
+    trace_err!(reader.read_exact(&mut payload))?;
+
+    Ok(payload)
+}
+
+// Send a control channel message in the format understood by the peer. Peers that support
+// compression (ClientHandshakePacket::supports_control_compression) get a length prefixed frame.
+// Older peers get the plain bincode message, that they read until the connection is closed.
+pub fn write_control_message<T: Serialize>(
+    writer: &mut impl Write,
+    message: &T,
+    peer_supports_compression: bool,
+) -> StrResult {
+    if peer_supports_compression {
+        write_control_frame(writer, &encode_control_message(message)?)
+    } else {
+        trace_err!(bincode::serialize_into(writer, message))
+    }
+}
+
+// Receive a message sent with write_control_message() to a peer that supports compression
+pub fn read_control_message<T: DeserializeOwned>(reader: &mut impl Read) -> StrResult<T> {
+    decode_control_message(&read_control_frame(reader)?)
+}
+
+// Retry a send that can fail transiently. Failed attempts are logged at debug level, the final
+// failure as a warning. Returns the error of the last attempt.
+pub fn send_with_retry(mut send: impl FnMut() -> StrResult) -> StrResult {
+    let mut attempt = 1;
+    loop {
+        match send() {
+            Ok(()) => break Ok(()),
+            Err(e) if attempt < SEND_MAX_ATTEMPTS => {
+                debug!("Send attempt {} failed: {}", attempt, e);
+                thread::sleep(SEND_RETRY_INTERVAL);
+                attempt += 1;
+            }
+            Err(e) => {
+                warn!("Send failed after {} attempts: {}", attempt, e);
+                break Err(e);
+            }
+        }
+    }
+}
 
 // pub enum SendMode {
 //     UnreliableUnordered,
@@ -95,11 +298,13 @@
 //     ReliableOrdered,
 // }
 
+// #[derive(Clone)]
 // pub struct PacketEnqueuer {
 //     peer_address: SocketAddr,
 //     stream_id: u8,
 //     send_mode: SendMode,
 //     packet_sender: crossbeam_channel::Sender<Packet>,
+//     data_cipher: Option<Arc<DataCipher>>,
 // }
 
 // impl PacketEnqueuer {
@@ -110,6 +315,9 @@
 //         let mut buffer = vec![self.stream_id];
 //         // <&mut Vec>::write() appends the writtend data
 //         trace_err!(bincode::serialize_into(&mut buffer, packet))?;
+//         if let Some(data_cipher) = &self.data_cipher {
+//             data_cipher.encrypt(&mut buffer)?;
+//         }
 
 //         // todo: use const generics when stabilized
 //         let packet = match self.send_mode {
@@ -167,6 +375,7 @@
 //     receive_thread: ThreadLoop,
 //     receive_buffer_enqueuers: Arc<Mutex<HashMap<u8, Sender<Vec<u8>>>>>,
 //     return_buffer_enqueuer: Sender<Vec<u8>>,
+//     data_cipher: Option<Arc<DataCipher>>,
 // }
 
 // impl ConnectionManager {
@@ -213,9 +422,11 @@
 //         local_address: SocketAddr,
 //         peer_address: SocketAddr,
 //         socket_config: SocketConfig,
+//         data_cipher: Option<DataCipher>,
 //         mut timeout_callback: impl FnMut() + Send + 'static,
 //     ) -> StrResult<Self> {
 //         let config = Self::create_config(socket_config);
+//         let data_cipher = data_cipher.map(Arc::new);
 //         let mut socket = trace_err!(
 //             Socket::bind_with_config(local_address, config),
 //             "Handshake failed"
@@ -226,6 +437,7 @@
 //         let receive_buffer_enqueuers = Arc::new(Mutex::new(HashMap::<_, Sender<_>>::new()));
 //         let receive_thread = thread_loop::spawn("Socket receiver loop", {
 //             let receive_buffer_enqueuers = receive_buffer_enqueuers.clone();
+//             let data_cipher = data_cipher.clone();
 //             move || {
 //                 let mut buffer = if let Ok(mut buffer) = return_buffer_dequeuer.try_recv() {
 //                     buffer.clear();
@@ -239,6 +451,12 @@
 //                         let payload = packet.payload();
 //                         let stream_id = payload[0];
 //                         buffer.extend(&payload[0..]);
+//                         if let Some(data_cipher) = &data_cipher {
+//                             if let Err(e) = data_cipher.decrypt(&mut buffer) {
+//                                 debug!("Discarded packet: {}", e);
+//                                 return;
+//                             }
+//                         }
 //                         if let Some(enqueuer) = receive_buffer_enqueuers.lock().get(&stream_id) {
 //                             enqueuer.send(buffer).ok();
 //                         }
@@ -257,6 +475,7 @@
 //             receive_thread,
 //             receive_buffer_enqueuers,
 //             return_buffer_enqueuer,
+//             data_cipher,
 //         })
 //     }
 
@@ -271,6 +490,7 @@
 //             stream_id: stream_type.into(),
 //             send_mode,
 //             packet_sender,
+//             data_cipher: self.data_cipher.clone(),
 //         }
 //     }
 
@@ -304,6 +524,10 @@
 //         found_client_ip: IpAddr,
 //         socket_config: SocketConfig,
 //         handshake_packet: ServerHandshakePacket,
+//         // from ClientHandshakePacket::supports_control_compression
+//         client_supports_compression: bool,
+//         // from negotiate_server_encryption()
+//         data_cipher: Option<DataCipher>,
 //         timeout_callback: impl FnMut() + Send + 'static,
 //     ) -> StrResult<Self> {
 //         let handshake_server_address = SocketAddr::new(LOCAL_IP, HANDSHAKE_PORT);
@@ -312,12 +536,16 @@
 //             handshake_packet.settings.connection.client_port,
 //         );
 
-//         let hanshake_sender = trace_err!(
+//         let mut hanshake_sender = trace_err!(
 //             TcpStream::connect(handshake_server_address),
 //             "Handshake failed"
 //         )?;
 
-//         trace_err!(bincode::serialize_into(hanshake_sender, &handshake_packet))?;
+//         write_control_message(
+//             &mut hanshake_sender,
+//             &handshake_packet,
+//             client_supports_compression,
+//         )?;
 //         // hanshake_sender dropped here. Close TCP connection because it can interfere with Laminar
 
 //         let server_address =
@@ -326,6 +554,7 @@
 //             server_address,
 //             client_address,
 //             socket_config,
+//             data_cipher,
 //             timeout_callback,
 //         )
 //     }
@@ -338,9 +567,13 @@
 //         trace_err!(multicaster.join_multicast_v4(&MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED))?;
 //         trace_err!(multicaster.set_write_timeout(Some(HANDSHAKE_TIMEOUT)))?;
 
-//         let listener = trace_err!(TcpListener::bind(SocketAddr::new(LOCAL_IP, HANDSHAKE_PORT)))?;
+//         let listener = bind_reusable_tcp_listener(SocketAddr::new(LOCAL_IP, HANDSHAKE_PORT))?;
 //         trace_err!(listener.set_nonblocking(true))?;
 
+//         // The server decides if the data channels are encrypted
+//         let key_exchange = KeyExchange::new();
+//         handshake_packet.encryption_public_key = Some(key_exchange.public_key());
+
 //         let client_hanshake_packet = trace_err!(bincode::serialize(&handshake_packet))?;
 
 //         let try_handshake = || -> Result<(IpAddr, ServerHandshakePacket), ()> {
@@ -352,7 +585,7 @@
 //                 .map_err(|err| debug!("Handshake packet multicast: {}", err))?;
 
 //             let accept_deadline = Instant::now() + HANDSHAKE_TIMEOUT;
-//             let (handshake_receiver, address) = loop {
+//             let (mut handshake_receiver, address) = loop {
 //                 if let Ok(pair) = listener.accept() {
 //                     break pair;
 //                 } else if Instant::now() > accept_deadline {
@@ -363,8 +596,9 @@
 //                 .set_nonblocking(false)
 //                 .map_err(|err| warn!("Control socket: {}", err))?;
 
-//             let server_handshake_packet = bincode::deserialize_from(handshake_receiver)
-//                 .map_err(|err| warn!("Handshake packet receive: {}", err))?;
+//             let server_handshake_packet: ServerHandshakePacket =
+//                 read_control_message(&mut handshake_receiver)
+//                     .map_err(|err| warn!("Handshake packet receive: {}", err))?;
 //             // handshake_receiver dropped here. Close TCP connection because it can interfere with
 //             // Laminar
 
@@ -386,10 +620,15 @@
 //             server_handshake_packet.settings.connection.server_port,
 //         );
 
+//         let data_cipher = server_handshake_packet
+//             .encryption_public_key
+//             .map(|server_public_key| key_exchange.into_cipher(server_public_key, false));
+
 //         let connection_manager = Self::create_connection_manager(
 //             client_address,
 //             server_address,
 //             server_handshake_packet.settings.connection.config.clone(),
+//             data_cipher,
 //             timeout_callback,
 //         )?;
 
@@ -400,3 +639,381 @@
 //         self.receive_thread.request_stop();
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::io::Cursor;
+    use std::net::TcpStream;
+
+    // Returns the queued packets in order, then times out
+    struct MockTransport {
+        packets: VecDeque<(Vec<u8>, SocketAddr)>,
+    }
+
+    impl MockTransport {
+        fn new(packets: Vec<(Vec<u8>, &str)>) -> Self {
+            Self {
+                packets: packets
+                    .into_iter()
+                    .map(|(packet, address)| (packet, address.parse().unwrap()))
+                    .collect(),
+            }
+        }
+    }
+
+    impl DiscoveryTransport for MockTransport {
+        fn recv(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            let (packet, address) = self
+                .packets
+                .pop_front()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+            buffer[..packet.len()].copy_from_slice(&packet);
+            Ok((packet.len(), address))
+        }
+
+        fn send(&mut self, buffer: &[u8], _: SocketAddr) -> io::Result<usize> {
+            Ok(buffer.len())
+        }
+    }
+
+    fn handshake_packet(bridgevr_name: &str, version: &str) -> Vec<u8> {
+        let fov = Fov {
+            left: 45.,
+            top: 45.,
+            right: 45.,
+            bottom: 45.,
+        };
+        bincode::serialize(&ClientHandshakePacket {
+            bridgevr_name: bridgevr_name.into(),
+            version: version.into(),
+            native_eye_resolution: (1440, 1600),
+            fov: [fov; 2],
+            fps: 72,
+            max_video_encoder_instances: 1,
+            available_audio_player_sample_rates: vec![],
+            preferred_audio_player_sample_rates: 48000,
+            available_microphone_sample_rates: vec![],
+            preferred_microphone_sample_rates: vec![],
+            supports_control_compression: true,
+            encryption_public_key: None,
+        })
+        .unwrap()
+    }
+
+    const SEARCH_TIMEOUT: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn client_version_compatibility() {
+        assert!(is_client_version_compatible("0.0.1"));
+        assert!(!is_client_version_compatible("0.0.0-alpha.1"));
+        assert!(!is_client_version_compatible("1.0.0"));
+        assert!(!is_client_version_compatible("not a version"));
+    }
+
+    #[test]
+    fn search_finds_client() {
+        let mut transport = MockTransport::new(vec![(
+            handshake_packet("client", "0.0.1"),
+            "192.168.1.2:9943",
+        )]);
+
+        let clients = search_clients_with_transport(&mut transport, None, SEARCH_TIMEOUT).unwrap();
+
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].0, "192.168.1.2".parse::<IpAddr>().unwrap());
+        assert_eq!(clients[0].1.bridgevr_name, "client");
+    }
+
+    #[test]
+    fn search_skips_malformed_packets() {
+        let mut transport = MockTransport::new(vec![
+            (vec![0xff; 3], "192.168.1.2:9943"),
+            (handshake_packet("client", "0.0.1"), "192.168.1.3:9943"),
+        ]);
+
+        let clients = search_clients_with_transport(&mut transport, None, SEARCH_TIMEOUT).unwrap();
+        assert_eq!(clients[0].0, "192.168.1.3".parse::<IpAddr>().unwrap());
+
+        let mut transport = MockTransport::new(vec![(vec![0xff; 3], "192.168.1.2:9943")]);
+        assert!(search_clients_with_transport(&mut transport, None, SEARCH_TIMEOUT).is_err());
+    }
+
+    #[test]
+    fn search_skips_incompatible_clients() {
+        let mut transport = MockTransport::new(vec![
+            (handshake_packet("too new", "1.0.0"), "192.168.1.2:9943"),
+            (
+                handshake_packet("too old", "0.0.0-alpha.1"),
+                "192.168.1.3:9943",
+            ),
+            (handshake_packet("client", "0.0.1"), "192.168.1.4:9943"),
+        ]);
+
+        let clients = search_clients_with_transport(&mut transport, None, SEARCH_TIMEOUT).unwrap();
+        assert_eq!(clients[0].1.bridgevr_name, "client");
+
+        let mut transport = MockTransport::new(vec![(
+            handshake_packet("too old", "0.0.0-alpha.1"),
+            "192.168.1.2:9943",
+        )]);
+        assert!(search_clients_with_transport(&mut transport, None, SEARCH_TIMEOUT).is_err());
+    }
+
+    #[test]
+    fn search_rejects_invalid_client_ip() {
+        let mut transport = MockTransport::new(vec![]);
+
+        assert!(search_clients_with_transport(
+            &mut transport,
+            Some("not an ip".into()),
+            SEARCH_TIMEOUT
+        )
+        .is_err());
+    }
+
+    fn localhost(port: u16) -> SocketAddr {
+        (Ipv4Addr::LOCALHOST, port).into()
+    }
+
+    #[test]
+    fn listener_port_in_use_is_reported() {
+        let listener = bind_reusable_tcp_listener(localhost(0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let error = bind_reusable_tcp_listener(localhost(port)).err().unwrap();
+        assert!(error.contains("in use"));
+    }
+
+    // The server closes first, so its side of the connection stays in TIME_WAIT
+    #[cfg(unix)]
+    #[test]
+    fn listener_rebinds_over_time_wait() {
+        let listener = bind_reusable_tcp_listener(localhost(0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let client = TcpStream::connect(localhost(port)).unwrap();
+        let (server_stream, _) = listener.accept().unwrap();
+        drop(server_stream);
+        drop(listener);
+        drop(client);
+
+        assert!(bind_reusable_tcp_listener(localhost(port)).is_ok());
+    }
+
+    #[test]
+    fn send_is_retried_until_success() {
+        let mut attempts = 0;
+        let result = send_with_retry(|| {
+            attempts += 1;
+            if attempts < SEND_MAX_ATTEMPTS {
+                Err(format!("attempt {}", attempts))
+            } else {
+                Ok(())
+            }
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(attempts, SEND_MAX_ATTEMPTS);
+    }
+
+    #[test]
+    fn last_send_error_is_returned() {
+        let mut attempts = 0;
+        let result = send_with_retry(|| {
+            attempts += 1;
+            Err(format!("attempt {}", attempts))
+        });
+
+        assert_eq!(result, Err(format!("attempt {}", SEND_MAX_ATTEMPTS)));
+        assert_eq!(attempts, SEND_MAX_ATTEMPTS);
+    }
+
+    #[test]
+    fn only_the_final_send_failure_is_a_warning() {
+        let mut attempts = 0;
+        let (_, records) = logging::capture_logs(|| {
+            send_with_retry(|| {
+                attempts += 1;
+                if attempts < SEND_MAX_ATTEMPTS {
+                    Err("transient".into())
+                } else {
+                    Ok(())
+                }
+            })
+        });
+        assert!(records.iter().all(|(level, _)| *level == Level::Debug));
+
+        let (_, records) = logging::capture_logs(|| send_with_retry(|| Err("broken pipe".into())));
+        let warnings = records
+            .into_iter()
+            .filter(|(level, _)| *level == Level::Warn)
+            .map(|(_, message)| message)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            warnings,
+            vec![format!(
+                "Send failed after {} attempts: broken pipe",
+                SEND_MAX_ATTEMPTS
+            )]
+        );
+    }
+
+    #[test]
+    fn small_control_message_is_not_compressed() {
+        let message = "small message".to_owned();
+        let buffer = encode_control_message(&message).unwrap();
+
+        assert_eq!(buffer[0], CONTROL_MESSAGE_UNCOMPRESSED);
+        assert_eq!(decode_control_message::<String>(&buffer).unwrap(), message);
+    }
+
+    #[test]
+    fn large_control_message_is_compressed() {
+        let message = vec![7_u8; CONTROL_COMPRESSION_THRESHOLD_BYTES * 4];
+        let buffer = encode_control_message(&message).unwrap();
+
+        assert_eq!(buffer[0], CONTROL_MESSAGE_DEFLATE);
+        assert!(buffer.len() < message.len());
+        assert_eq!(decode_control_message::<Vec<u8>>(&buffer).unwrap(), message);
+    }
+
+    #[test]
+    fn invalid_control_message_encoding_is_rejected() {
+        assert!(decode_control_message::<String>(&[]).is_err());
+        assert!(decode_control_message::<String>(&[2, 0, 0, 0]).is_err());
+    }
+
+    // Returns one byte per read, like a TCP stream that splits every segment
+    struct OneByteReader(Cursor<Vec<u8>>);
+
+    impl Read for OneByteReader {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let len = buffer.len().min(1);
+            self.0.read(&mut buffer[..len])
+        }
+    }
+
+    #[test]
+    fn control_frames_are_split_back() {
+        let mut stream = vec![];
+        write_control_frame(&mut stream, b"first").unwrap();
+        write_control_frame(&mut stream, b"").unwrap();
+        write_control_frame(&mut stream, b"third").unwrap();
+
+        let mut reader = Cursor::new(stream.clone());
+        assert_eq!(read_control_frame(&mut reader).unwrap(), b"first");
+        assert_eq!(read_control_frame(&mut reader).unwrap(), b"");
+        assert_eq!(read_control_frame(&mut reader).unwrap(), b"third");
+        assert!(read_control_frame(&mut reader).is_err());
+
+        let mut reader = OneByteReader(Cursor::new(stream));
+        assert_eq!(read_control_frame(&mut reader).unwrap(), b"first");
+        assert_eq!(read_control_frame(&mut reader).unwrap(), b"");
+        assert_eq!(read_control_frame(&mut reader).unwrap(), b"third");
+    }
+
+    #[test]
+    fn invalid_control_frames_are_rejected() {
+        let mut stream = vec![];
+        write_control_frame(&mut stream, b"truncated").unwrap();
+        stream.pop();
+        assert!(read_control_frame(&mut Cursor::new(stream)).is_err());
+
+        let size_bytes = (MAX_CONTROL_FRAME_SIZE_BYTES as u32 + 1).to_le_bytes();
+        assert!(read_control_frame(&mut Cursor::new(size_bytes.to_vec())).is_err());
+
+        let mut stream = vec![];
+        let payload = vec![0; MAX_CONTROL_FRAME_SIZE_BYTES + 1];
+        assert!(write_control_frame(&mut stream, &payload).is_err());
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn control_messages_round_trip_with_new_peers() {
+        let message = vec![7_u32; 1000];
+
+        let mut stream = vec![];
+        write_control_message(&mut stream, &message, true).unwrap();
+        write_control_message(&mut stream, &"second".to_owned(), true).unwrap();
+
+        let mut reader = Cursor::new(stream);
+        assert_eq!(
+            read_control_message::<Vec<u32>>(&mut reader).unwrap(),
+            message
+        );
+        assert_eq!(
+            read_control_message::<String>(&mut reader).unwrap(),
+            "second"
+        );
+    }
+
+    #[test]
+    fn control_messages_are_not_framed_for_old_peers() {
+        let message = vec![7_u32; 1000];
+
+        let mut stream = vec![];
+        write_control_message(&mut stream, &message, false).unwrap();
+
+        // Old peers read the plain bincode message until the connection is closed
+        assert_eq!(bincode::deserialize::<Vec<u32>>(&stream).unwrap(), message);
+    }
+
+    fn discovered_clients() -> MockTransport {
+        MockTransport::new(vec![
+            (handshake_packet("c", "0.0.1"), "192.168.1.30:9943"),
+            (handshake_packet("a", "0.0.1"), "192.168.1.4:9943"),
+            (handshake_packet("b", "0.0.1"), "192.168.1.20:9943"),
+            (handshake_packet("a_restarted", "0.0.1"), "192.168.1.4:9944"),
+        ])
+    }
+
+    #[test]
+    fn search_without_client_ip_returns_first_client() {
+        let timeout = Duration::from_secs(10);
+        let start_time = Instant::now();
+        let clients =
+            search_clients_with_transport(&mut discovered_clients(), None, timeout).unwrap();
+
+        assert!(start_time.elapsed() < timeout);
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].1.bridgevr_name, "c");
+    }
+
+    #[test]
+    fn search_returns_all_clients_sorted_by_ip() {
+        // The configured client is not found
+        let clients = search_clients_with_transport(
+            &mut discovered_clients(),
+            Some("192.168.1.99".into()),
+            SEARCH_TIMEOUT,
+        )
+        .unwrap();
+
+        let found: Vec<_> = clients
+            .iter()
+            .map(|(ip, packet)| (ip.to_string(), packet.bridgevr_name.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("192.168.1.4".to_owned(), "a_restarted"),
+                ("192.168.1.20".to_owned(), "b"),
+                ("192.168.1.30".to_owned(), "c"),
+            ]
+        );
+    }
+
+    #[test]
+    fn search_prefers_configured_client_ip() {
+        let clients = search_clients_with_transport(
+            &mut discovered_clients(),
+            Some("192.168.1.20".into()),
+            SEARCH_TIMEOUT,
+        )
+        .unwrap();
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].1.bridgevr_name, "b");
+    }
+}
@@ -1,12 +1,15 @@
 use std::collections::VecDeque;
 use std::time::*;
 
+#[derive(Clone)]
 struct TimedEntry<K, V> {
     key: K,
     value: V,
     timestamp: Instant,
 }
 
+// Clone is available only if K and V are Clone
+#[derive(Clone)]
 pub struct TimeoutMap<K, V> {
     // By not using an HashMap I avoid deriving Eq, Hash, Copy and Clone for K
     // A VecDeque is used because elements are inserted an removed almost always as FIFO.
@@ -36,29 +39,41 @@ impl<K, V> TimeoutMap<K, V> {
             .map(|TimedEntry { key, value, .. }| (key, value))
     }
 
-    pub fn remove_expired(&mut self) -> Vec<V> {
-        let max_time = Instant::now() - self.timeout;
-
-        let idx_to_be_removed: Vec<_> = self
-            .buffer
-            .iter()
-            .enumerate()
-            .filter(|(_, entry)| entry.timestamp > max_time)
-            .map(|(i, _)| i)
-            .collect();
+    // Returns expired entries in FIFO order
+    pub fn drain_expired_with_keys(&mut self) -> Vec<(K, V)> {
+        let max_time = if let Some(time) = Instant::now().checked_sub(self.timeout) {
+            time
+        } else {
+            return vec![];
+        };
 
+        // Entries are inserted with increasing timestamps, so the expired ones are at the front.
         let mut expired = vec![];
-        for idx in idx_to_be_removed {
-            if let Some(entry) = self.buffer.remove(idx) {
-                expired.push(entry.value)
+        while let Some(entry) = self.buffer.front() {
+            if entry.timestamp > max_time {
+                break;
+            }
+            if let Some(TimedEntry { key, value, .. }) = self.buffer.pop_front() {
+                expired.push((key, value));
             }
         }
 
         expired
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
-        self.buffer.iter().map(|entry| (&entry.key, &entry.value))
+    pub fn remove_expired(&mut self) -> Vec<V> {
+        self.drain_expired_with_keys()
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    // Key, value and insertion time of each entry, in insertion order. Nothing is removed, not even
+    // the expired entries.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V, Instant)> {
+        self.buffer
+            .iter()
+            .map(|entry| (&entry.key, &entry.value, entry.timestamp))
     }
 
     pub fn keys(&self) -> impl Iterator<Item = &K> {
@@ -72,9 +87,21 @@ impl<K, V> TimeoutMap<K, V> {
     pub fn is_empty(&self) -> bool {
         self.buffer.is_empty()
     }
+
+    // Remove all entries, the timeout is kept
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
 }
 
 impl<K: PartialEq, V> TimeoutMap<K, V> {
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.buffer
+            .iter_mut()
+            .find(|entry| entry.key == *key)
+            .map(|entry| &mut entry.value)
+    }
+
     pub fn remove(&mut self, key: &K) -> Option<(K, V)> {
         // front to back iterator
         let entry = self
@@ -92,3 +119,84 @@ impl<K: PartialEq, V> TimeoutMap<K, V> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn expired_keys_are_drained_in_fifo_order() {
+        let mut map = TimeoutMap::new(Duration::from_millis(50));
+        map.insert(3, "c");
+        map.insert(1, "a");
+        map.insert(2, "b");
+        thread::sleep(Duration::from_millis(60));
+        map.insert(4, "d");
+
+        assert_eq!(
+            map.drain_expired_with_keys(),
+            vec![(3, "c"), (1, "a"), (2, "b")]
+        );
+        assert_eq!(map.keys().collect::<Vec<_>>(), vec![&4]);
+    }
+
+    #[test]
+    fn nothing_expires_before_timeout() {
+        let mut map = TimeoutMap::new(Duration::from_secs(3600));
+        map.insert(1, "a");
+        map.insert(2, "b");
+
+        assert!(map.drain_expired_with_keys().is_empty());
+        assert!(map.remove_expired().is_empty());
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn remove_expired_returns_values_in_fifo_order() {
+        let mut map = TimeoutMap::new(Duration::from_secs(0));
+        map.insert(2, "b");
+        map.insert(1, "a");
+
+        assert_eq!(map.remove_expired(), vec!["b", "a"]);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn clear_keeps_timeout() {
+        let mut map = TimeoutMap::new(Duration::from_secs(0));
+        map.insert(1, "a");
+        map.clear();
+        assert!(map.is_empty());
+
+        map.insert(2, "b");
+        assert_eq!(map.remove_expired(), vec!["b"]);
+    }
+
+    #[test]
+    fn clone_is_independent() {
+        let mut map = TimeoutMap::new(Duration::from_secs(3600));
+        map.insert(1, "a");
+
+        let mut cloned_map = map.clone();
+        cloned_map.insert(2, "b");
+        assert_eq!(map.len(), 1);
+        assert_eq!(cloned_map.keys().collect::<Vec<_>>(), vec![&1, &2]);
+    }
+
+    #[test]
+    fn iter_keeps_insertion_order_and_expired_entries() {
+        let mut map = TimeoutMap::new(Duration::from_millis(0));
+        let before_insert = Instant::now();
+        map.insert(2, "b");
+        map.insert(1, "a");
+        thread::sleep(Duration::from_millis(10));
+
+        let entries = map.iter().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].0, entries[0].1), (&2, &"b"));
+        assert_eq!((entries[1].0, entries[1].1), (&1, &"a"));
+        assert!(entries[0].2 >= before_insert && entries[0].2 <= entries[1].2);
+        assert_eq!(map.len(), 2);
+    }
+}
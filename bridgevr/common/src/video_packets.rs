@@ -0,0 +1,547 @@
+use crate::{data::*, timeout_map::TimeoutMap, *};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    time::Duration,
+};
+
+const TRACE_CONTEXT: &str = "Video packets";
+
+// Conservative estimate of the bytes added to each sub NAL by the IP/UDP headers, the network
+// protocol and the other VideoPacket fields.
+const VIDEO_PACKET_OVERHEAD_BYTES: usize = 128;
+
+// Consecutive frames dropped by LateFrameFilter before a keyframe is requested
+const LATE_FRAMES_BEFORE_KEYFRAME_REQUEST: usize = 3;
+
+// Split a NAL into packets that fit in the MTU. sub_nal_index and sub_nal_count are used by
+// VideoPacketReassembler to rebuild the NAL.
+pub fn packetize_nal(
+    nal_index: u64,
+    hmd_pose: Pose,
+    server_timestamp_ns: u64,
+    nal: &[u8],
+    mtu: u16,
+) -> StrResult<Vec<VideoPacket<'_>>> {
+    let max_sub_nal_size = trace_none!(
+        (mtu as usize).checked_sub(VIDEO_PACKET_OVERHEAD_BYTES),
+        "MTU too small"
+    )?;
+
+    let sub_nals: Vec<_> = if nal.is_empty() {
+        vec![nal]
+    } else {
+        nal.chunks(max_sub_nal_size).collect()
+    };
+
+    if sub_nals.len() > u8::MAX as usize {
+        return trace_str!(
+            "NAL of {} bytes too big for MTU {}: {} packets needed",
+            nal.len(),
+            mtu,
+            sub_nals.len()
+        );
+    }
+    let sub_nal_count = sub_nals.len() as u8;
+
+    Ok(sub_nals
+        .into_iter()
+        .enumerate()
+        .map(|(idx, sub_nal)| VideoPacket {
+            nal_index,
+            sub_nal_index: idx as _,
+            sub_nal_count,
+            hmd_pose,
+            server_timestamp_ns,
+            sub_nal,
+        })
+        .collect())
+}
+
+// Time from encode to receive. clock_offset_ns is the client clock minus the server clock, as
+// measured during the handshake. Returns None if the result is negative, which happens when the
+// clocks drifted since the handshake.
+pub fn video_latency(
+    server_timestamp_ns: u64,
+    client_receive_time_ns: u64,
+    clock_offset_ns: i64,
+) -> Option<Duration> {
+    let latency_ns =
+        client_receive_time_ns as i128 - server_timestamp_ns as i128 - clock_offset_ns as i128;
+    u64::try_from(latency_ns).ok().map(Duration::from_nanos)
+}
+
+struct PartialNal {
+    sub_nals: Vec<Option<Vec<u8>>>,
+    missing_count: usize,
+    hmd_pose: Pose,
+    server_timestamp_ns: u64,
+}
+
+pub struct VideoPacketReassembler {
+    partial_nals: TimeoutMap<u64, PartialNal>,
+}
+
+impl VideoPacketReassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            partial_nals: TimeoutMap::new(timeout),
+        }
+    }
+
+    // Returns NAL index, pose, server timestamp and NAL data when the last missing sub NAL is
+    // received.
+    pub fn push(&mut self, packet: &VideoPacket) -> Option<(u64, Pose, u64, Vec<u8>)> {
+        let sub_nal_count = packet.sub_nal_count as usize;
+        let sub_nal_index = packet.sub_nal_index as usize;
+        if sub_nal_index >= sub_nal_count {
+            return None;
+        }
+
+        if self.partial_nals.get_mut(&packet.nal_index).is_none() {
+            self.partial_nals.insert(
+                packet.nal_index,
+                PartialNal {
+                    sub_nals: vec![None; sub_nal_count],
+                    missing_count: sub_nal_count,
+                    hmd_pose: packet.hmd_pose,
+                    server_timestamp_ns: packet.server_timestamp_ns,
+                },
+            );
+        }
+
+        // unwrap never fails
+        let partial_nal = self.partial_nals.get_mut(&packet.nal_index).unwrap();
+        if let Some(slot @ None) = partial_nal.sub_nals.get_mut(sub_nal_index) {
+            *slot = Some(packet.sub_nal.to_vec());
+            partial_nal.missing_count -= 1;
+        }
+
+        if partial_nal.missing_count == 0 {
+            self.partial_nals
+                .remove(&packet.nal_index)
+                .map(|(nal_index, partial_nal)| {
+                    let nal = partial_nal
+                        .sub_nals
+                        .into_iter()
+                        .flatten()
+                        .flatten()
+                        .collect();
+                    (
+                        nal_index,
+                        partial_nal.hmd_pose,
+                        partial_nal.server_timestamp_ns,
+                        nal,
+                    )
+                })
+        } else {
+            None
+        }
+    }
+
+    // Returns the indices of the NALs that could not be completed before timeout.
+    pub fn drain_expired(&mut self) -> Vec<u64> {
+        self.partial_nals
+            .drain_expired_with_keys()
+            .into_iter()
+            .map(|(nal_index, _)| nal_index)
+            .collect()
+    }
+}
+
+struct PartialFrame {
+    slice_nals: Vec<Option<Vec<u8>>>,
+    missing_count: usize,
+}
+
+// Collects the NALs of all slices of a frame and releases complete frames in frame index order.
+// A frame is dropped if it is not complete before the timeout or if a frame window_frames ahead
+// has been received.
+pub struct FrameReorderBuffer {
+    slice_count: usize,
+    window_frames: u64,
+    next_frame_index: Option<u64>,
+    newest_frame_index: u64,
+    partial_frames: TimeoutMap<u64, PartialFrame>,
+    complete_frames: BTreeMap<u64, Vec<Vec<u8>>>,
+    // Frames that expired before next_frame_index reached them
+    lost_frames: BTreeSet<u64>,
+    dropped_count: usize,
+}
+
+impl FrameReorderBuffer {
+    pub fn new(slice_count: usize, decoder_desc: &VideoDecoderDesc) -> Self {
+        Self {
+            slice_count,
+            window_frames: decoder_desc.reorder_window_frames.max(1) as _,
+            next_frame_index: None,
+            newest_frame_index: 0,
+            partial_frames: TimeoutMap::new(Duration::from_millis(decoder_desc.reorder_timeout_ms)),
+            complete_frames: BTreeMap::new(),
+            lost_frames: BTreeSet::new(),
+            dropped_count: 0,
+        }
+    }
+
+    pub fn push(&mut self, frame_index: u64, slice_idx: usize, nal: Vec<u8>) {
+        let next_frame_index = *self.next_frame_index.get_or_insert(frame_index);
+        if frame_index < next_frame_index
+            || slice_idx >= self.slice_count
+            || self.lost_frames.contains(&frame_index)
+            || self.complete_frames.contains_key(&frame_index)
+        {
+            return;
+        }
+        self.newest_frame_index = self.newest_frame_index.max(frame_index);
+
+        if self.partial_frames.get_mut(&frame_index).is_none() {
+            self.partial_frames.insert(
+                frame_index,
+                PartialFrame {
+                    slice_nals: vec![None; self.slice_count],
+                    missing_count: self.slice_count,
+                },
+            );
+        }
+
+        // unwrap never fails
+        let partial_frame = self.partial_frames.get_mut(&frame_index).unwrap();
+        if let Some(slot @ None) = partial_frame.slice_nals.get_mut(slice_idx) {
+            *slot = Some(nal);
+            partial_frame.missing_count -= 1;
+        }
+
+        if partial_frame.missing_count == 0 {
+            if let Some((_, partial_frame)) = self.partial_frames.remove(&frame_index) {
+                let slice_nals = partial_frame.slice_nals.into_iter().flatten().collect();
+                self.complete_frames.insert(frame_index, slice_nals);
+            }
+        }
+    }
+
+    // Returns the frame index and the NALs of each slice of the next frame, if ready.
+    pub fn pop(&mut self) -> Option<(u64, Vec<Vec<u8>>)> {
+        for (frame_index, _) in self.partial_frames.drain_expired_with_keys() {
+            self.lost_frames.insert(frame_index);
+        }
+
+        loop {
+            let next_frame_index = self.next_frame_index?;
+
+            if let Some(slice_nals) = self.complete_frames.remove(&next_frame_index) {
+                self.skip_to(next_frame_index + 1);
+                return Some((next_frame_index, slice_nals));
+            }
+
+            let lost = self.lost_frames.contains(&next_frame_index);
+            let window_exceeded = self.newest_frame_index >= next_frame_index + self.window_frames;
+            if !lost && !window_exceeded {
+                return None;
+            }
+
+            self.partial_frames.remove(&next_frame_index);
+            self.dropped_count += 1;
+            self.skip_to(next_frame_index + 1);
+        }
+    }
+
+    fn skip_to(&mut self, frame_index: u64) {
+        self.next_frame_index = Some(frame_index);
+        self.lost_frames = self.lost_frames.split_off(&frame_index);
+    }
+
+    // Number of frames dropped since the last call
+    pub fn take_dropped_count(&mut self) -> usize {
+        let dropped_count = self.dropped_count;
+        self.dropped_count = 0;
+        dropped_count
+    }
+
+    // Used on reconnection
+    pub fn clear(&mut self) {
+        self.next_frame_index = None;
+        self.newest_frame_index = 0;
+        self.partial_frames.clear();
+        self.complete_frames.clear();
+        self.lost_frames.clear();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LateFrameAction {
+    Decode,
+    Drop,
+    // The decoder fell behind: drop the frame and request a keyframe to catch up
+    DropAndRequestKeyframe,
+}
+
+// Drops the frames that are already older than max_decode_latency_ms when they are ready to be
+// decoded, because they would only add latency.
+pub struct LateFrameFilter {
+    max_latency: Duration,
+    consecutive_late_count: usize,
+    dropped_count: usize,
+}
+
+impl LateFrameFilter {
+    pub fn new(decoder_desc: &VideoDecoderDesc) -> Self {
+        Self {
+            max_latency: Duration::from_millis(decoder_desc.max_decode_latency_ms),
+            consecutive_late_count: 0,
+            dropped_count: 0,
+        }
+    }
+
+    // clock_offset_ns: see video_latency(). Frames with a negative latency (because of clock drift)
+    // are always decoded.
+    pub fn check(
+        &mut self,
+        server_timestamp_ns: u64,
+        client_time_ns: u64,
+        clock_offset_ns: i64,
+    ) -> LateFrameAction {
+        match video_latency(server_timestamp_ns, client_time_ns, clock_offset_ns) {
+            Some(latency) if latency > self.max_latency => {
+                self.dropped_count += 1;
+                self.consecutive_late_count += 1;
+
+                // Request only once per run of late frames
+                if self.consecutive_late_count == LATE_FRAMES_BEFORE_KEYFRAME_REQUEST {
+                    LateFrameAction::DropAndRequestKeyframe
+                } else {
+                    LateFrameAction::Drop
+                }
+            }
+            _ => {
+                self.consecutive_late_count = 0;
+                LateFrameAction::Decode
+            }
+        }
+    }
+
+    // Number of frames dropped since the last call
+    pub fn take_dropped_count(&mut self) -> usize {
+        let dropped_count = self.dropped_count;
+        self.dropped_count = 0;
+        dropped_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use settings_schema::schema_to_default_json;
+
+    // 100 bytes per sub NAL
+    const MTU: u16 = VIDEO_PACKET_OVERHEAD_BYTES as u16 + 100;
+
+    fn pose() -> Pose {
+        Pose {
+            position: [0., 1.6, 0.],
+            orientation: [1., 0., 0., 0.],
+        }
+    }
+
+    fn nal(size: usize) -> Vec<u8> {
+        (0..size).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn nal_is_split_to_fit_mtu() {
+        let nal = nal(250);
+        let packets = packetize_nal(7, pose(), 42, &nal, MTU).unwrap();
+
+        assert_eq!(packets.len(), 3);
+        for (idx, packet) in packets.iter().enumerate() {
+            assert_eq!(packet.nal_index, 7);
+            assert_eq!(packet.sub_nal_index as usize, idx);
+            assert_eq!(packet.sub_nal_count, 3);
+            assert_eq!(packet.server_timestamp_ns, 42);
+        }
+        assert_eq!(packets[2].sub_nal.len(), 50);
+    }
+
+    #[test]
+    fn empty_nal_is_sent_as_one_packet() {
+        let packets = packetize_nal(0, pose(), 0, &[], MTU).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].sub_nal_count, 1);
+    }
+
+    #[test]
+    fn packetize_rejects_small_mtu_and_big_nal() {
+        assert!(packetize_nal(0, pose(), 0, &nal(10), 100).is_err());
+        assert!(packetize_nal(0, pose(), 0, &nal(100 * 256), MTU).is_err());
+    }
+
+    #[test]
+    fn nal_is_reassembled_out_of_order() {
+        let nal = nal(250);
+        let packets = packetize_nal(7, pose(), 42, &nal, MTU).unwrap();
+        let mut reassembler = VideoPacketReassembler::new(Duration::from_secs(1));
+
+        assert!(reassembler.push(&packets[2]).is_none());
+        assert!(reassembler.push(&packets[0]).is_none());
+        // duplicates are ignored
+        assert!(reassembler.push(&packets[0]).is_none());
+
+        let (nal_index, hmd_pose, server_timestamp_ns, reassembled_nal) =
+            reassembler.push(&packets[1]).unwrap();
+        assert_eq!(nal_index, 7);
+        assert_eq!(hmd_pose.position, pose().position);
+        assert_eq!(server_timestamp_ns, 42);
+        assert_eq!(reassembled_nal, nal);
+    }
+
+    #[test]
+    fn incomplete_nal_expires() {
+        let nal = nal(250);
+        let packets = packetize_nal(7, pose(), 0, &nal, MTU).unwrap();
+        let mut reassembler = VideoPacketReassembler::new(Duration::from_secs(0));
+
+        assert!(reassembler.push(&packets[0]).is_none());
+        assert_eq!(reassembler.drain_expired(), vec![7]);
+        assert!(reassembler.drain_expired().is_empty());
+    }
+
+    #[test]
+    fn latency_accounts_for_clock_offset() {
+        // client clock 1s ahead of the server
+        let clock_offset_ns = 1_000_000_000;
+        assert_eq!(
+            video_latency(5_000_000, 1_015_000_000, clock_offset_ns),
+            Some(Duration::from_millis(10))
+        );
+        assert_eq!(
+            video_latency(995_000_000, 5_000_000, -clock_offset_ns),
+            Some(Duration::from_millis(10))
+        );
+    }
+
+    #[test]
+    fn negative_latency_is_discarded() {
+        assert_eq!(video_latency(20_000_000, 10_000_000, 0), None);
+    }
+
+    fn decoder_desc(reorder_window_frames: u32, reorder_timeout_ms: u64) -> VideoDecoderDesc {
+        let default_json = schema_to_default_json(&settings_schema(settings_default()));
+        let mut decoder_desc: VideoDecoderDesc =
+            serde_json::from_value(default_json["video"]["decoder"].clone()).unwrap();
+        decoder_desc.reorder_window_frames = reorder_window_frames;
+        decoder_desc.reorder_timeout_ms = reorder_timeout_ms;
+        decoder_desc.max_decode_latency_ms = 50;
+        decoder_desc
+    }
+
+    fn push_frame(buffer: &mut FrameReorderBuffer, frame_index: u64) {
+        buffer.push(frame_index, 1, vec![frame_index as u8, 1]);
+        buffer.push(frame_index, 0, vec![frame_index as u8, 0]);
+    }
+
+    #[test]
+    fn frames_are_released_in_order() {
+        let mut buffer = FrameReorderBuffer::new(2, &decoder_desc(4, 1000));
+        buffer.push(0, 0, vec![0, 0]);
+        push_frame(&mut buffer, 1);
+        assert_eq!(buffer.pop(), None);
+
+        buffer.push(0, 1, vec![0, 1]);
+        assert_eq!(buffer.pop(), Some((0, vec![vec![0, 0], vec![0, 1]])));
+        assert_eq!(buffer.pop(), Some((1, vec![vec![1, 0], vec![1, 1]])));
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.take_dropped_count(), 0);
+    }
+
+    #[test]
+    fn incomplete_frame_is_dropped_past_window() {
+        let mut buffer = FrameReorderBuffer::new(2, &decoder_desc(2, 1000));
+        buffer.push(0, 0, vec![0, 0]);
+        push_frame(&mut buffer, 1);
+        assert_eq!(buffer.pop(), None);
+
+        push_frame(&mut buffer, 2);
+        assert_eq!(buffer.pop().map(|(frame_index, _)| frame_index), Some(1));
+        assert_eq!(buffer.take_dropped_count(), 1);
+
+        // late slices of a dropped frame are ignored
+        buffer.push(0, 1, vec![0, 1]);
+        assert_eq!(buffer.pop().map(|(frame_index, _)| frame_index), Some(2));
+    }
+
+    #[test]
+    fn expired_frame_is_dropped() {
+        let mut buffer = FrameReorderBuffer::new(2, &decoder_desc(4, 0));
+        buffer.push(0, 0, vec![0, 0]);
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.take_dropped_count(), 1);
+
+        push_frame(&mut buffer, 1);
+        assert_eq!(buffer.pop().map(|(frame_index, _)| frame_index), Some(1));
+    }
+
+    #[test]
+    fn clear_restarts_from_next_frame() {
+        let mut buffer = FrameReorderBuffer::new(2, &decoder_desc(4, 1000));
+        push_frame(&mut buffer, 5);
+        buffer.clear();
+
+        push_frame(&mut buffer, 0);
+        assert_eq!(buffer.pop().map(|(frame_index, _)| frame_index), Some(0));
+    }
+
+    #[test]
+    fn frames_older_than_first_are_ignored() {
+        let mut buffer = FrameReorderBuffer::new(2, &decoder_desc(4, 1000));
+        push_frame(&mut buffer, 1);
+        push_frame(&mut buffer, 0);
+        assert_eq!(buffer.pop().map(|(frame_index, _)| frame_index), Some(1));
+        assert_eq!(buffer.pop(), None);
+    }
+
+    const MS: u64 = 1_000_000;
+
+    #[test]
+    fn late_frames_are_dropped() {
+        let mut filter = LateFrameFilter::new(&decoder_desc(0, 0));
+
+        assert_eq!(filter.check(0, 50 * MS, 0), LateFrameAction::Decode);
+        assert_eq!(filter.check(0, 51 * MS, 0), LateFrameAction::Drop);
+        // Negative latency
+        assert_eq!(filter.check(10 * MS, 5 * MS, 0), LateFrameAction::Decode);
+        // The client clock is 10ms behind, then 20ms ahead
+        assert_eq!(
+            filter.check(0, 60 * MS, -10 * MS as i64),
+            LateFrameAction::Drop
+        );
+        assert_eq!(
+            filter.check(0, 60 * MS, 20 * MS as i64),
+            LateFrameAction::Decode
+        );
+
+        assert_eq!(filter.take_dropped_count(), 2);
+        assert_eq!(filter.take_dropped_count(), 0);
+    }
+
+    #[test]
+    fn keyframe_is_requested_once_per_run_of_late_frames() {
+        let mut filter = LateFrameFilter::new(&decoder_desc(0, 0));
+
+        let mut actions = (0..LATE_FRAMES_BEFORE_KEYFRAME_REQUEST + 1)
+            .map(|_| filter.check(0, 100 * MS, 0))
+            .collect::<Vec<_>>();
+        assert_eq!(actions.pop(), Some(LateFrameAction::Drop));
+        assert_eq!(actions.pop(), Some(LateFrameAction::DropAndRequestKeyframe));
+        assert!(actions
+            .iter()
+            .all(|action| *action == LateFrameAction::Drop));
+
+        assert_eq!(filter.check(0, 0, 0), LateFrameAction::Decode);
+        let actions = (0..LATE_FRAMES_BEFORE_KEYFRAME_REQUEST)
+            .map(|_| filter.check(0, 100 * MS, 0))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions.last(),
+            Some(&LateFrameAction::DropAndRequestKeyframe)
+        );
+    }
+}
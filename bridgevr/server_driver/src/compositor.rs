@@ -1,6 +1,6 @@
 // #![allow(clippy::type_complexity)]
 
-// use crate::video_encoder::aligned_resolution;
+// use crate::{statistics::*, video_encoder::aligned_resolution};
 // use bridgevr_common::{
 //     data::*,
 //     ffr::*,
@@ -25,6 +25,7 @@
 // const COPY_EYE_LAYERS_NEAREST_SHADER_STR: &str = ""; // todo
 // const COPY_EYE_LAYERS_BILINEAR_SHADER_STR: &str = ""; // todo
 // const COPY_EYE_LAYERS_LANCZOS_SHADER_STR: &str = ""; // todo
+// const SHARPENING_SHADER_STR: &str = ""; // todo: contrast adaptive sharpening
 
 // fn get_copy_eye_layers_operation_desc(
 //     input_textures: [Arc<Texture>; 2],
@@ -47,11 +48,27 @@
 //     }
 // }
 
+// fn get_sharpening_operation_desc(
+//     input_texture: Arc<Texture>,
+//     strength_uniform_buffer: Arc<UniformBuffer>,
+//     output_texture: Arc<Texture>,
+// ) -> OperationDesc {
+//     OperationDesc::Rendering {
+//         input_textures: vec![input_texture],
+//         uniform_buffer: Some(strength_uniform_buffer),
+//         shader: SHARPENING_SHADER_STR.to_owned(),
+//         output_textures: vec![output_texture],
+//         alpha: false,
+//     }
+// }
+
 // pub struct FrameSlice {
 //     pub frame_index: u64,
 //     pub texture: Arc<Texture>,
 //     pub pose: Pose,
 //     pub force_idr: bool,
+//     // See scene_change_rate_control()
+//     pub scene_change: bool,
 // }
 
 // pub struct PresentData {
@@ -59,6 +76,8 @@
 //     pub layers: Vec<([(Arc<Texture>, TextureBounds); 2], Pose)>,
 //     pub sync_texture: Arc<Texture>,
 //     pub force_idr_slice_idxs: Vec<usize>,
+//     // Set by the runtime if it knows that a new scene starts with this frame
+//     pub scene_change: bool,
 // }
 
 // // TS is a texture auxiliary storage. For OpenVR this is VRVulkanTextureData_t
@@ -160,10 +179,21 @@
 //     }
 // }
 
+// // Format of the textures handed to the encoders. Chroma subsampling is done by the encoder.
+// fn output_format(pixel_format: VideoPixelFormat) -> Format {
+//     match pixel_format {
+//         VideoPixelFormat::NV12 | VideoPixelFormat::RGBA => Format::Rgba8Unorm,
+//         VideoPixelFormat::P010 => Format::A2b10g10r10Unorm,
+//     }
+// }
+
 // pub struct CompositorDesc {
 //     pub target_eye_resolution: (u32, u32),
 //     pub filter_type: CompositionFilteringType,
+//     // Sharpening strength. None disables the pass
+//     pub sharpening: Option<f32>,
 //     pub ffr_desc: Option<data::FoveatedRenderingDesc>,
+//     pub pixel_format: VideoPixelFormat,
 // }
 
 // pub struct Compositor {
@@ -179,11 +209,14 @@
 //         present_done_notif_sender: Sender<()>,
 //         slice_senders: Vec<Sender<FrameSlice>>,
 //         slice_encoded_notif_receivers: Vec<Receiver<()>>,
+//         statistics: Arc<Mutex<Statistics>>,
 //     ) -> StrResult<Self> {
 //         let CompositorDesc {
 //             target_eye_resolution,
 //             filter_type,
+//             sharpening,
 //             ffr_desc,
+//             pixel_format,
 //         } = compositor_desc;
 
 //         let composition_texture = Arc::new(Texture::new(
@@ -195,10 +228,34 @@
 
 //         let mut rendering_operation_descs = vec![];
 
+//         // The sharpening pass is skipped entirely when disabled
+//         let sharpened_texture = if let Some(strength) = sharpening {
+//             let sharpened_texture = Arc::new(Texture::new(
+//                 graphics.clone(),
+//                 target_eye_resolution,
+//                 Format::Rgba8Unorm,
+//                 1,
+//             )?);
+
+//             let strength_uniform_buffer = Arc::new(UniformBuffer::new::<f32>(graphics.clone())?);
+//             strength_uniform_buffer.write(&strength.clamp(0., 1.))?;
+
+//             rendering_operation_descs.push(get_sharpening_operation_desc(
+//                 composition_texture.clone(),
+//                 strength_uniform_buffer,
+//                 sharpened_texture.clone(),
+//             ));
+
+//             sharpened_texture
+//         } else {
+//             composition_texture.clone()
+//         };
+
 //         let compressed_eye_resolution;
 //         let compressed_texture;
 //         match ffr_desc {
 //             Some(ffr_desc) => {
+//                 validate_ffr_desc(ffr_desc)?;
 //                 compressed_eye_resolution =
 //                     ffr_compressed_eye_resolution(target_eye_resolution, ffr_desc);
 //                 compressed_texture = Arc::new(Texture::new(
@@ -209,7 +266,7 @@
 //                 )?);
 
 //                 let ffr_operation_descs = ffr_compression_operation_descs(
-//                     composition_texture.clone(),
+//                     sharpened_texture,
 //                     target_eye_resolution,
 //                     compressed_eye_resolution,
 //                 );
@@ -218,7 +275,7 @@
 //             }
 //             None => {
 //                 compressed_eye_resolution = target_eye_resolution;
-//                 compressed_texture = composition_texture.clone();
+//                 compressed_texture = sharpened_texture;
 //             }
 //         }
 
@@ -233,7 +290,7 @@
 //             let slice_texture = Arc::new(Texture::new(
 //                 graphics.clone(),
 //                 encoder_resolution,
-//                 Format::Rgba8Unorm,
+//                 output_format(pixel_format),
 //                 1,
 //             )?);
 
@@ -273,6 +330,11 @@
 //                 .find(|(l, _)| *l == current_layers_textures)
 //                 .map(|(_, bufs)| bufs);
 
+//             // A new set of layers (for example an overlay that appears) usually changes most of
+//             // the frame. The first frame is a keyframe anyway
+//             let scene_change = present_data.scene_change
+//                 || (maybe_layers_buffers.is_none() && !layers_buffers_history.is_empty());
+
 //             let (composition_operation_buffer, uniform_buffers) = if let Some(bufs) =
 //                 maybe_layers_buffers
 //             {
@@ -321,6 +383,19 @@
 
 //             rendering_operation_buffer.execute();
 
+//             {
+//                 let mut statistics = statistics.lock();
+//                 statistics.report_frame();
+//                 statistics.report_gpu_stage(
+//                     GpuStage::Composition,
+//                     composition_operation_buffer.gpu_timer(),
+//                 )?;
+//                 statistics.report_gpu_stage(
+//                     GpuStage::EncoderUpload,
+//                     rendering_operation_buffer.gpu_timer(),
+//                 )?;
+//             }
+
 //             // Improvement: use pose to do reprojection
 //             let pose = present_data.layers[0].1;
 
@@ -330,6 +405,7 @@
 //                     texture: slice_textures[idx].clone(),
 //                     pose,
 //                     force_idr: present_data.force_idr_slice_idxs.contains(&idx),
+//                     scene_change,
 //                 }))?
 //             }
 
@@ -30,9 +30,9 @@ const TIMEOUT: Duration = Duration::from_secs(1);
 
 const STATISTICS_MAX_INTERVAL: Duration = Duration::from_secs(1);
 
-fn get_settings() -> StrResult<Settings> {
-    load_settings(&Path::new(env!("INSTALL_ROOT")).join("settings.json"))
-}
+// fn get_settings() -> StrResult<Settings> {
+//     load_settings(&Path::new(env!("INSTALL_ROOT")).join("settings.json"))
+// }
 
 fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     // vr_server: Arc<Mutex<VrServer>>,
@@ -40,9 +40,7 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     // shutdown_signal_receiver: Receiver<ShutdownSignal>,
     // session_desc_loader: Arc<Mutex<SessionDescLoader>>,
 ) -> StrResult<Arc<Mutex<VrServer>>> {
-    let maybe_settings = get_settings()
-        .map_err(|_| error!("Cannot read settings. BridgeVR server will be in an invalid state."))
-        .ok();
+    let settings = load_settings_or_default(&Path::new(env!("INSTALL_ROOT")).join("settings.json"));
 
     let session_desc_loader = Arc::new(Mutex::new(SessionDescLoader::load(
         &Path::new(env!("INSTALL_ROOT")).join("session.json"),
@@ -50,11 +48,13 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
 
     // let graphics = Arc::new(GraphicsContext::new(None)?);
 
+    // let statistics = Arc::new(Mutex::new(Statistics::default()));
+
     // let (shutdown_signal_sender, shutdown_signal_receiver) = mpsc::channel();
 
     let vr_server = Arc::new(Mutex::new(VrServer::new(
         // graphics.clone(),
-        maybe_settings.as_ref(),
+        Some(&settings),
         &session_desc_loader.lock().get_mut(),
         // shutdown_signal_sender.clone(),
     )));
@@ -74,17 +74,14 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                 get_settings()?
     //             };
 
+    //             let clients =
+    //                 search_clients(settings.connection.client_ip.clone(), TIMEOUT)?;
+    //             // todo: let the user choose the client from the GUI
+    //             // unwrap never fails: search_clients() returns at least one client
     //             let (found_client_ip, client_handshake_packet) =
-    //                 search_client(settings.connection.client_ip.clone(), TIMEOUT)?;
-
-    //             if client_handshake_packet.version < BVR_MIN_VERSION_CLIENT {
-    //                 return trace_str!(
-    //                     "Espected client of version {} or greater, found {}.",
-    //                     BVR_MIN_VERSION_CLIENT,
-    //                     client_handshake_packet.version
-    //                 );
-    //             }
+    //                 clients.into_iter().next().unwrap();
 
+    //             // Client version is checked by search_clients()
     //             session_desc_loader
     //                 .lock()
     //                 .get_mut()
@@ -95,29 +92,40 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                 .map_err(|e| warn!("{}", e))
     //                 .ok();
 
-    //             let target_eye_resolution = match &settings.video.frame_size {
-    //                 FrameSize::Scale(scale) => {
-    //                     let (native_eye_width, native_eye_height) =
-    //                         client_handshake_packet.native_eye_resolution;
-    //                     let width = (native_eye_width as f32 * *scale) as _;
-    //                     let height = (native_eye_height as f32 * *scale) as _;
-    //                     (width, height)
-    //                 }
-    //                 FrameSize::Absolute(width, height) => (*width, *height),
+    //             let target_eye_resolution = settings
+    //                 .video
+    //                 .frame_size
+    //                 .target_eye_resolution(client_handshake_packet.native_eye_resolution);
+    //             let frame_slice_count = settings.video.resolve_frame_slice_count(
+    //                 target_eye_resolution,
+    //                 client_handshake_packet.fps,
+    //                 graphics.gpu_vendor(),
+    //             )?;
+
+    //             let (encryption_public_key, data_cipher) = match negotiate_server_encryption(
+    //                 settings.connection.encrypt_data_channels,
+    //                 client_handshake_packet.encryption_public_key,
+    //             )? {
+    //                 Some((public_key, cipher)) => (Some(public_key), Some(cipher)),
+    //                 None => (None, None),
     //             };
 
     //             let server_handshake_packet = ServerHandshakePacket {
     //                 config: ServerConfig {
     //                     version: BVR_VERSION_SERVER,
     //                     target_eye_resolution,
+    //                     frame_slice_count,
     //                 },
     //                 settings: settings.clone(),
+    //                 encryption_public_key,
     //             };
 
     //             let mut connection_manager = ConnectionManager::connect_to_client(
     //                 found_client_ip,
     //                 settings.connection.config.clone(),
     //                 server_handshake_packet,
+    //                 client_handshake_packet.supports_control_compression,
+    //                 data_cipher,
     //                 {
     //                     let shutdown_signal_sender = shutdown_signal_sender.clone();
 
@@ -136,7 +144,7 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //             let mut slice_senders = vec![];
     //             let mut slice_encoded_notif_receivers = vec![];
     //             let mut slice_interop_encoders = vec![];
-    //             for _ in 0..settings.video.frame_slice_count {
+    //             for _ in 0..frame_slice_count {
     //                 let (slice_sender, slice_receiver) = channel();
     //                 let (slice_encoded_notif_sender, slice_encoded_notif_receiver) = channel();
     //                 slice_senders.push(slice_sender);
@@ -149,15 +157,34 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                 CompositorDesc {
     //                     target_eye_resolution,
     //                     filter_type: settings.video.composition_filtering,
+    //                     sharpening: settings.video.sharpening.clone().into_option(),
     //                     ffr_desc: settings.video.foveated_rendering.clone().into_option(),
+    //                     pixel_format: settings.video.pixel_format,
     //                 },
     //                 present_receiver,
     //                 present_done_notif_sender,
     //                 slice_senders,
     //                 slice_encoded_notif_receivers,
+    //                 statistics.clone(),
     //             )?;
 
     //             let video_encoder_resolution = compositor.encoder_resolution();
+    //             let encoder_config = settings.video.to_encoder_config(
+    //                 video_encoder_resolution,
+    //                 client_handshake_packet.fps,
+    //                 graphics.gpu_vendor(),
+    //             )?;
+    //             let maybe_fallback_encoder_config = settings.video.to_fallback_encoder_config(
+    //                 video_encoder_resolution,
+    //                 client_handshake_packet.fps,
+    //                 graphics.gpu_vendor(),
+    //             )?;
+    //             let mut encoder_fallback_enqueuer = connection_manager
+    //                 .register_enqueuer(StreamType::Other, SendMode::ReliableUnordered);
+    //             let slice_send_gate = Arc::new(Mutex::new(SliceSendGate::new(
+    //                 settings.video.progressive_slices,
+    //                 frame_slice_count as _,
+    //             )));
 
     //             let mut video_encoders = vec![];
     //             for (idx, (slice_receiver, slice_encoded_notif_sender)) in
@@ -171,15 +198,24 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                 let packet_enqueuer = connection_manager
     //                     .register_enqueuer(StreamType::VideoSlice(idx as _), send_mode);
 
-    //                 video_encoders.push(VideoEncoder::new(
+    //                 let video_encoder = VideoEncoder::new(
     //                     &format!("Video encoder loop {}", idx),
-    //                     settings.video.encoder.clone(),
-    //                     video_encoder_resolution,
-    //                     client_handshake_packet.fps,
+    //                     encoder_config.clone(),
+    //                     maybe_fallback_encoder_config.clone(),
     //                     slice_receiver,
     //                     slice_encoded_notif_sender,
     //                     packet_enqueuer,
-    //                 )?);
+    //                     slice_send_gate.clone(),
+    //                 )?;
+
+    //                 if video_encoder.codec_name() != encoder_config.codec_name {
+    //                     encoder_fallback_enqueuer.enqueue(&OtherServerPacket::VideoEncoderFallback {
+    //                         slice_idx: idx as _,
+    //                         codec_name: video_encoder.codec_name().to_owned(),
+    //                     })?;
+    //                 }
+
+    //                 video_encoders.push(video_encoder);
     //             }
 
     //             let mut maybe_game_audio_recorder = match &settings.game_audio {
@@ -195,6 +231,7 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                     Some(AudioRecorder::start_recording(
     //                         desc.input_device_index,
     //                         true,
+    //                         settings.connection.config.effective_max_packet_size(),
     //                         packet_enqueuer,
     //                     )?)
     //                 }
@@ -218,16 +255,22 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //             let haptic_enqueuer = connection_manager
     //                 .register_enqueuer(StreamType::Other, SendMode::UnreliableUnordered);
 
-    //             vr_server.lock().initialize_for_client_or_request_restart(
-    //                 &settings,
-    //                 session_desc_loader.lock().get_mut(),
-    //                 present_sender,
-    //                 present_done_notif_receiver,
-    //                 haptic_enqueuer,
-    //             )?;
+    //             let client_initialization =
+    //                 vr_server.lock().initialize_for_client_or_request_restart(
+    //                     &settings,
+    //                     session_desc_loader.lock().get_mut(),
+    //                     present_sender,
+    //                     present_done_notif_receiver,
+    //                     haptic_enqueuer,
+    //                 )?;
 
     //             let mut other_packet_dequeuer = connection_manager.register_dequeuer(StreamType::Other);
     //             let shutdown_signal = loop {
+    //                 // The client is told to shut down, then it reconnects after SteamVR restarts
+    //                 if let ClientInitialization::RestartRequested(reason) = client_initialization {
+    //                     info!("SteamVR restart requested: {:?}", reason);
+    //                     break ShutdownSignal::BackendShutdown;
+    //                 }
     //                 if let Ok(packet) = other_packet_dequeuer.dequeue(STATISTICS_MAX_INTERVAL) {
     //                     match packet.get::<OtherClientPacket>() {
     //                         Ok(OtherClientPacket::MotionAndTiming {
@@ -238,10 +281,12 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                             for device_motion in device_motions {
     //                                 let sample_6dof = match device_motion.sample {
     //                                     MotionSampleDesc::Dof6(sample) => sample,
-    //                                     MotionSampleDesc::Dof3(_) => {
-    //                                         // todo: use 3dof to 6dof model
-    //                                         todo!()
-    //                                     }
+    //                                     // todo: arm model for the 3DoF controllers
+    //                                     MotionSampleDesc::Dof3(sample) => motion_3dof_to_6dof(
+    //                                         &sample,
+    //                                         device_motion.device_type == TrackedDeviceType::HMD
+    //                                             && settings.vr_server.openvr.hmd_neck_model,
+    //                                     ),
     //                                 };
 
     //                                 vr_server.process_motion(
@@ -256,8 +301,12 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                             vr_server.lock().process_input(data, timestamp_ns)
     //                         }
     //                         Ok(OtherClientPacket::Statistics(_)) => {
-    //                             log_statistics(); // todo
+    //                             log_statistics(&mut statistics.lock()); // todo
     //                         }
+    //                         Ok(OtherClientPacket::Battery {
+    //                             device_type,
+    //                             battery,
+    //                         }) => vr_server.lock().update_battery_level(device_type, battery),
     //                         Ok(OtherClientPacket::Disconnected) => {
     //                             break ShutdownSignal::ClientDisconnected
     //                         }
@@ -272,10 +321,13 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                 }
     //             };
 
-    //             connection_manager
-    //                 .register_enqueuer(StreamType::Other, SendMode::ReliableUnordered)
-    //                 .enqueue(&())
-    //                 .ok();
+    //             let mut shutdown_enqueuer = connection_manager
+    //                 .register_enqueuer(StreamType::Other, SendMode::ReliableUnordered);
+    //             // A final failure is logged as a warning by send_with_retry()
+    //             let client_notified =
+    //                 send_with_retry(|| shutdown_enqueuer.enqueue(&OtherServerPacket::Shutdown))
+    //                     .is_ok();
+    //             debug!("Client notified of shutdown: {}", client_notified);
 
     //             connection_manager.request_stop();
     //             compositor.request_stop();
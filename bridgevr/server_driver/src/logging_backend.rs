@@ -1,8 +1,17 @@
+use bridgevr_common::logging::*;
+use lazy_static::lazy_static;
 use log::*;
 use std::{path::Path, sync::Once};
 
+const RECENT_LOG_CAPACITY: usize = 1000;
+const RECENT_WARNINGS_IN_ERROR_MESSAGE: usize = 10;
+
 static INIT_LOGGING_ENTRY_POINT: Once = Once::new();
 
+lazy_static! {
+    static ref RECENT_LOG: LogRingBuffer = LogRingBuffer::new(RECENT_LOG_CAPACITY);
+}
+
 #[cfg(target_os = "linux")]
 fn show_error_message_box(_: &str, message_with_intro: &str) {
     use gtk::*;
@@ -59,10 +68,13 @@ pub fn init_logging() {
                 .level(LevelFilter::Info)
         }
         .chain(fern::log_file(Path::new(env!("INSTALL_ROOT")).join("log.txt")).unwrap())
+        .chain(fern::Output::call(|record| RECENT_LOG.push(record)))
         .apply()
         .unwrap();
 
         fn log_error_fn(message: &str) {
+            let message =
+                RECENT_LOG.append_recent_warnings(message, RECENT_WARNINGS_IN_ERROR_MESSAGE);
             show_error_message_box("BridgeVR crashed", &message);
         }
 
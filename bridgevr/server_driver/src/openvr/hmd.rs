@@ -1,4 +1,4 @@
-use super::tracked_device::*;
+use super::{pose::*, tracked_device::*, SendPtr};
 use crate::compositor::*;
 use bridgevr_common::{data::*, graphics::*};
 use log::*;
@@ -11,13 +11,30 @@ use std::{
     ptr,
     sync::{mpsc::*, Arc},
     thread,
-    time::*,
 };
 
-pub const TIMEOUT: Duration = Duration::from_millis(500);
-
 const SWAP_TEXTURE_SET_SIZE: usize = 3;
 
+// Formats of the textures submitted by SteamVR to the virtual display.
+const STEAMVR_COMPOSITOR_FORMATS: &[Format] = &[
+    Format::Rgba8Unorm,
+    Format::Rgba8Srgb,
+    Format::Bgra8Unorm,
+    Format::Bgra8Srgb,
+    Format::A2b10g10r10Unorm,
+    Format::Rgba16Sfloat,
+];
+
+// The custom compositor supports only 8 bit per channel formats.
+const CUSTOM_COMPOSITOR_FORMATS: &[Format] = &[
+    Format::Rgba8Unorm,
+    Format::Rgba8Srgb,
+    Format::Bgra8Unorm,
+    Format::Bgra8Srgb,
+];
+
+const FALLBACK_SHARED_TEXTURE_FORMAT: Format = Format::Rgba8Unorm;
+
 // On VirtualDisplay interface the same texture is used for left and right eye.
 const VIRTUAL_DISPLAY_TEXTURE_BOUNDS: [TextureBounds; 2] = [
     // left
@@ -36,20 +53,32 @@ const VIRTUAL_DISPLAY_TEXTURE_BOUNDS: [TextureBounds; 2] = [
     },
 ];
 
-fn pose_from_openvr_matrix(matrix: &vr::HmdMatrix34_t) -> Pose {
-    use nalgebra::{Matrix3, UnitQuaternion};
-
-    let m = matrix.m;
-    let na_matrix = Matrix3::new(
-        m[0][0], m[0][1], m[0][2], m[1][0], m[1][1], m[1][2], m[2][0], m[2][1], m[2][2],
-    );
-    let na_quat = UnitQuaternion::from_matrix(&na_matrix);
-    let orientation = [na_quat[3], na_quat[0], na_quat[1], na_quat[2]];
-    let position = [m[0][3], m[1][3], m[2][3]];
+fn supported_shared_texture_formats(compositor_type: CompositorType) -> &'static [Format] {
+    match compositor_type {
+        CompositorType::SteamVR => STEAMVR_COMPOSITOR_FORMATS,
+        CompositorType::Custom => CUSTOM_COMPOSITOR_FORMATS,
+    }
+}
 
-    Pose {
-        position,
-        orientation,
+pub fn shared_texture_format(compositor_type: CompositorType, native_format: u32) -> Format {
+    match format_from_native(native_format) {
+        Some(format) if supported_shared_texture_formats(compositor_type).contains(&format) => {
+            format
+        }
+        Some(format) => {
+            error!(
+                "Texture format {:?} is not supported by the {:?} compositor. Using {:?}",
+                format, compositor_type, FALLBACK_SHARED_TEXTURE_FORMAT
+            );
+            FALLBACK_SHARED_TEXTURE_FORMAT
+        }
+        None => {
+            error!(
+                "Unknown native texture format {}. Using {:?}",
+                native_format, FALLBACK_SHARED_TEXTURE_FORMAT
+            );
+            FALLBACK_SHARED_TEXTURE_FORMAT
+        }
     }
 }
 
@@ -66,9 +95,10 @@ fn pose_from_openvr_matrix(matrix: &vr::HmdMatrix34_t) -> Pose {
 #[allow(clippy::type_complexity)]
 pub struct HmdContext {
     pub tracked_device_context: Arc<TrackedDeviceContext>,
-    // pub display_component_ptr: Mutex<*mut vr::DisplayComponent>, // Mutex is needed during initialization
-    // pub virtual_display_ptr: Mutex<*mut vr::VirtualDisplay>,
-    // pub driver_direct_mode_component_ptr: Mutex<*mut vr::DriverDirectModeComponent>,
+    pub compositor_type: CompositorType,
+    // pub display_component_ptr: Mutex<SendPtr<vr::DisplayComponent>>, // Mutex is needed during initialization
+    pub virtual_display_ptr: Mutex<SendPtr<vr::VirtualDisplay>>,
+    // pub driver_direct_mode_component_ptr: Mutex<SendPtr<vr::DriverDirectModeComponent>>,
     // pub graphics: Arc<GraphicsContext>,
     // pub swap_texture_manager: Mutex<SwapTextureManager<AuxiliaryTextureData>>,
     // pub current_layers: Mutex<Vec<([(Arc<Texture>, TextureBounds); 2], Pose)>>,
@@ -77,9 +107,6 @@ pub struct HmdContext {
     // pub latest_vsync: Mutex<(Instant, u64)>,
 }
 
-unsafe impl Send for HmdContext {}
-unsafe impl Sync for HmdContext {}
-
 // unsafe extern "C" fn get_window_bounds(
 //     context: *mut c_void,
 //     x: *mut i32,
@@ -132,7 +159,11 @@ unsafe impl Sync for HmdContext {}
 //         .settings
 //         .lock()
 //         .target_eye_resolution;
-//     *x = eye_width * (eye as u32);
+//     let eye = match Eye::from_index(eye as _) {
+//         Some(eye) => eye,
+//         None => return,
+//     };
+//     *x = eye_width * eye.index() as u32;
 //     *y = 0;
 //     *width = eye_width;
 //     *height = eye_height;
@@ -148,11 +179,14 @@ unsafe impl Sync for HmdContext {}
 // ) {
 //     let context = context as *const HmdContext;
 //     let settings = (*context).tracked_device_context.settings.lock();
-//     let eye = eye as usize;
-//     *left = settings.fov[eye].left;
-//     *right = settings.fov[eye].right;
-//     *top = settings.fov[eye].top;
-//     *bottom = settings.fov[eye].bottom;
+//     let fov = match Eye::from_index(eye as _) {
+//         Some(eye) => settings.fov[eye.index()],
+//         None => return,
+//     };
+//     *left = fov.left;
+//     *right = fov.right;
+//     *top = fov.top;
+//     *bottom = fov.bottom;
 // }
 
 // extern "C" fn compute_distortion(
@@ -220,7 +254,7 @@ unsafe impl Sync for HmdContext {}
 //                 &*((*present_info).backbufferTextureHandle as *mut vr::VRVulkanTextureData_t)
 //             };
 
-//             let format = format_from_native(data.m_nFormat);
+//             let format = shared_texture_format(context.compositor_type, data.m_nFormat);
 //             Texture::from_shared_vulkan_ptrs(
 //                 data.m_nImage,
 //                 context.graphics.clone(),
@@ -275,7 +309,8 @@ unsafe impl Sync for HmdContext {}
 //         &*context.compositor_interop.lock(),
 //     ) {
 //         let sync_texture = texture.clone();
-//         let res = sync_texture.acquire_sync(TIMEOUT);
+//         let present_timeout = context.tracked_device_context.settings.lock().present_timeout;
+//         let res = sync_texture.acquire_sync(present_timeout);
 
 //         if res.is_ok() {
 //             *context.sync_texture.lock() = Some(sync_texture.clone());
@@ -298,6 +333,7 @@ unsafe impl Sync for HmdContext {}
 //                     layers,
 //                     sync_texture,
 //                     force_idr_slice_idxs: vec![], // todo
+//                     scene_change: false,
 //                 })
 //                 .map_err(|e| debug!("{:?}", e))
 //                 .ok();
@@ -310,10 +346,11 @@ unsafe impl Sync for HmdContext {}
 // extern "C" fn wait_for_present(context: *mut c_void) {
 //     let context = unsafe { &*(context as *const HmdContext) };
 
+//     let present_timeout = context.tracked_device_context.settings.lock().present_timeout;
 //     if let Some(compositor_interop) = &*context.compositor_interop.lock() {
 //         compositor_interop
 //             .present_done_notif_receiver
-//             .recv_timeout(TIMEOUT)
+//             .recv_timeout(present_timeout)
 //             .map_err(|e| debug!("{}", e))
 //             .ok();
 //     };
@@ -333,7 +370,7 @@ unsafe impl Sync for HmdContext {}
 //     let context = context as *const HmdContext;
 
 //     let (vsync_time, vsync_index) = &*(*context).latest_vsync.lock();
-//     *seconds_since_last_vsync = (*vsync_time - Instant::now()).as_secs_f32();
+//     *seconds_since_last_vsync = vsync_time.elapsed().as_secs_f32();
 //     *frame_counter = *vsync_index;
 
 //     true
@@ -359,7 +396,8 @@ unsafe impl Sync for HmdContext {}
 //     let context = unsafe { &*(context as *const HmdContext) };
 
 //     let maybe_swap_texture_set = unsafe {
-//         let format = format_from_native((*swap_texture_set_desc).nFormat);
+//         let format =
+//             shared_texture_format(context.compositor_type, (*swap_texture_set_desc).nFormat);
 
 //         context
 //             .swap_texture_manager
@@ -484,7 +522,8 @@ unsafe impl Sync for HmdContext {}
 //         &*context.compositor_interop.lock(),
 //         &context.swap_texture_manager.lock().get(sync_handle),
 //     ) {
-//         let res = sync_texture.acquire_sync(TIMEOUT);
+//         let present_timeout = context.tracked_device_context.settings.lock().present_timeout;
+//         let res = sync_texture.acquire_sync(present_timeout);
 //         if res.is_ok() {
 //             *context.sync_texture.lock() = Some(sync_texture.clone());
 
@@ -499,6 +538,7 @@ unsafe impl Sync for HmdContext {}
 //                     layers,
 //                     sync_texture,
 //                     force_idr_slice_idxs: vec![], // todo
+//                     scene_change: false,
 //                 })
 //                 .map_err(|e| debug!("{:?}", e))
 //                 .ok();
@@ -509,10 +549,11 @@ unsafe impl Sync for HmdContext {}
 // extern "C" fn post_present(context: *mut c_void) {
 //     let context = unsafe { &*(context as *const HmdContext) };
 
+//     let present_timeout = context.tracked_device_context.settings.lock().present_timeout;
 //     if let Some(compositor_interop) = &*context.compositor_interop.lock() {
 //         compositor_interop
 //             .present_done_notif_receiver
-//             .recv_timeout(TIMEOUT)
+//             .recv_timeout(present_timeout)
 //             .map_err(|e| debug!("{}", e))
 //             .ok();
 //     };
@@ -574,26 +615,34 @@ unsafe extern "C" fn hmd_get_component(
     context: *mut c_void,
     component_name_and_version: *const c_char,
 ) -> *mut c_void {
-    // let context = context as *const HmdContext;
+    let context = context as *const HmdContext;
 
     // let component_name_and_version_c_str = CStr::from_ptr(component_name_and_version);
     // if component_name_and_version_c_str
     //     == CStr::from_bytes_with_nul_unchecked(vr::IVRDisplayComponent_Version)
     // {
-    //     *(*context).display_component_ptr.lock() as _
+    //     (*context).display_component_ptr.lock().0 as _
     // } else if component_name_and_version_c_str
     //     == CStr::from_bytes_with_nul_unchecked(vr::IVRVirtualDisplay_Version)
     // {
-    //     *(*context).virtual_display_ptr.lock() as _
+    //     (*context).virtual_display_ptr.lock().0 as _
     // } else if component_name_and_version_c_str
     //     == CStr::from_bytes_with_nul_unchecked(vr::IVRDriverDirectModeComponent_Version)
     // {
-    //     *(*context).driver_direct_mode_component_ptr.lock() as _
+    //     (*context).driver_direct_mode_component_ptr.lock().0 as _
     // } else {
     //     ptr::null_mut()
     // }
 
-    ptr::null_mut()
+    // todo: display and driver direct mode components
+    if CStr::from_ptr(component_name_and_version)
+        == CStr::from_bytes_with_nul_unchecked(vr::IVRVirtualDisplay_Version)
+    {
+        // null until the virtual display is registered
+        (*context).virtual_display_ptr.lock().0 as _
+    } else {
+        ptr::null_mut()
+    }
 }
 
 extern "C" fn hmd_get_pose(context: *mut c_void) -> vr::DriverPose_t {
@@ -602,6 +651,30 @@ extern "C" fn hmd_get_pose(context: *mut c_void) -> vr::DriverPose_t {
     get_pose(&*context.tracked_device_context as *const _ as _)
 }
 
+pub fn register_display_components(hmd_context: &Arc<HmdContext>) {
+    // let display_callbacks = create_display_callbacks(hmd_context.clone());
+    // *hmd_context.display_component_ptr.lock() =
+    //     SendPtr(unsafe { vr::vrCreateDisplayComponent(display_callbacks) });
+
+    match hmd_context.compositor_type {
+        CompositorType::SteamVR => {
+            // todo: register the virtual display once virtual_display_present can forward the
+            // frames to the compositor. Until then SteamVR would render into a display whose
+            // frames are dropped.
+            // let virtual_display_callbacks = create_virtual_display_callbacks(hmd_context.clone());
+            // *hmd_context.virtual_display_ptr.lock() =
+            //     SendPtr(unsafe { vr::vrCreateVirtualDisplay(virtual_display_callbacks) });
+        }
+        CompositorType::Custom => {
+            // let driver_direct_mode_callbacks =
+            //     create_driver_direct_mode_callbacks(hmd_context.clone());
+            // *hmd_context.driver_direct_mode_component_ptr.lock() = SendPtr(unsafe {
+            //     vr::vrCreateDriverDirectModeComponent(driver_direct_mode_callbacks)
+            // });
+        }
+    }
+}
+
 pub fn create_hmd_callbacks(
     hmd_context: Arc<HmdContext>,
 ) -> vr::TrackedDeviceServerDriverCallbacks {
@@ -615,3 +688,83 @@ pub fn create_hmd_callbacks(
         GetPose: Some(hmd_get_pose),
     }
 }
+
+// The native format values are defined only for the platforms with a format_from_native()
+#[cfg(all(test, any(windows, target_os = "linux")))]
+mod tests {
+    use super::{super::settings::*, *};
+
+    // Native values of R8G8B8A8_UNORM and R16G16B16A16_FLOAT
+    #[cfg(windows)]
+    const NATIVE_RGBA8_UNORM: u32 = 28;
+    #[cfg(windows)]
+    const NATIVE_RGBA16_SFLOAT: u32 = 10;
+    #[cfg(target_os = "linux")]
+    const NATIVE_RGBA8_UNORM: u32 = 37;
+    #[cfg(target_os = "linux")]
+    const NATIVE_RGBA16_SFLOAT: u32 = 97;
+
+    #[test]
+    fn supported_format_is_kept() {
+        assert_eq!(
+            shared_texture_format(CompositorType::SteamVR, NATIVE_RGBA16_SFLOAT),
+            Format::Rgba16Sfloat
+        );
+        assert_eq!(
+            shared_texture_format(CompositorType::Custom, NATIVE_RGBA8_UNORM),
+            Format::Rgba8Unorm
+        );
+    }
+
+    #[test]
+    fn unsupported_format_falls_back() {
+        assert_eq!(
+            shared_texture_format(CompositorType::Custom, NATIVE_RGBA16_SFLOAT),
+            FALLBACK_SHARED_TEXTURE_FORMAT
+        );
+    }
+
+    #[test]
+    fn unknown_format_falls_back() {
+        // UNDEFINED / DXGI_FORMAT_UNKNOWN
+        assert_eq!(
+            shared_texture_format(CompositorType::SteamVR, 0),
+            FALLBACK_SHARED_TEXTURE_FORMAT
+        );
+    }
+
+    fn hmd_context(compositor_type: CompositorType) -> Arc<HmdContext> {
+        let settings = create_openvr_settings(None, &SessionDesc::default());
+        let motion_prediction = settings.hmd_motion_prediction;
+        Arc::new(HmdContext {
+            tracked_device_context: Arc::new(TrackedDeviceContext {
+                settings: Arc::new(Mutex::new(settings)),
+                battery_throttle: Mutex::new(BatteryThrottle::new()),
+                motion_filter: Mutex::new(MotionFilter::new(motion_prediction)),
+            }),
+            compositor_type,
+            virtual_display_ptr: Mutex::new(SendPtr::null()),
+        })
+    }
+
+    #[test]
+    fn virtual_display_is_not_exposed_while_frames_are_dropped() {
+        let context = hmd_context(CompositorType::SteamVR);
+        register_display_components(&context);
+
+        let component = unsafe {
+            hmd_get_component(
+                &*context as *const _ as _,
+                vr::IVRVirtualDisplay_Version.as_ptr() as _,
+            )
+        };
+        assert!(component.is_null());
+    }
+
+    // HmdContext is shared with the SteamVR threads. Raw pointers must be wrapped in SendPtr
+    #[test]
+    fn hmd_context_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<HmdContext>();
+    }
+}
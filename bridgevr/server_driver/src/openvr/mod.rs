@@ -1,7 +1,11 @@
 mod hmd;
+mod pose;
 mod settings;
 mod tracked_device;
 
+pub use pose::motion_3dof_to_6dof;
+pub use settings::RestartReason;
+
 use crate::{compositor::*, shutdown_signal::ShutdownSignal};
 use bridgevr_common::{data::*, graphics::*, input_paths::*, sockets::*, *};
 use hmd::*;
@@ -52,10 +56,27 @@ const DEFAULT_DRIVER_POSE: vr::DriverPose_t = vr::DriverPose_t {
     deviceIsConnected: true,
 };
 
-// fn should_restart(old_settings: &OpenvrSettings, new_settings: &OpenvrSettings) -> bool {
-//     new_settings.fov != old_settings.fov
-//         || new_settings.frame_interval != old_settings.frame_interval
-// }
+// Pointer to an object created with openvr_driver_sys (components and drivers). These objects are
+// created and destroyed by VrServer, and in between SteamVR uses them from any thread. The pointer
+// is never dereferenced by BridgeVR, so it can be shared freely.
+#[derive(Clone, Copy)]
+pub struct SendPtr<T>(pub *mut T);
+
+unsafe impl<T> Send for SendPtr<T> {}
+unsafe impl<T> Sync for SendPtr<T> {}
+
+impl<T> SendPtr<T> {
+    pub fn null() -> Self {
+        Self(null_mut())
+    }
+}
+
+// When a restart is requested, the client is not initialized and SteamVR will shut down the driver
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientInitialization {
+    Initialized,
+    RestartRequested(RestartReason),
+}
 
 struct ServerContext {
     // settings: Arc<Mutex<OpenvrSettings>>,
@@ -195,6 +216,7 @@ pub struct VrServer {
     // // input_thread: Option<ThreadLoop>,
     // input_timer: Instant,
     // controllers_contexts: Vec<Arc<TrackedDeviceContext>>,
+    // input_range_enforcer: InputRangeEnforcer,
 }
 
 unsafe impl Send for VrServer {}
@@ -210,17 +232,32 @@ impl VrServer {
         let openvr_settings = Arc::new(Mutex::new(create_openvr_settings(settings, &session_desc)));
         // let shutdown_signal_sender = Arc::new(Mutex::new(shutdown_signal_sender));
 
+        let (hmd_motion_prediction, controllers_motion_prediction) = {
+            let openvr_settings = openvr_settings.lock();
+            (
+                openvr_settings.hmd_motion_prediction,
+                openvr_settings.controllers_motion_prediction,
+            )
+        };
+
         let tracked_devices_contexts = openvr_settings
             .lock()
             .tracked_devices
             .iter()
             .map(|td| {
+                let motion_prediction = if let TrackedDeviceType::HMD = td.device_type {
+                    hmd_motion_prediction
+                } else {
+                    controllers_motion_prediction
+                };
                 (
                     td.device_type,
                     Arc::new(TrackedDeviceContext {
                         // device_type: td.device_type,
                         // object_id: Mutex::new(None),
-                        // settings: openvr_settings.clone(),
+                        settings: openvr_settings.clone(),
+                        battery_throttle: Mutex::new(BatteryThrottle::new()),
+                        motion_filter: Mutex::new(MotionFilter::new(motion_prediction)),
                         // pose: Mutex::new(DEFAULT_DRIVER_POSE),
                         // input_to_component_map: Mutex::new(HashMap::new()),
                         // haptic_component: Mutex::new(vr::k_ulInvalidInputComponentHandle),
@@ -239,11 +276,18 @@ impl VrServer {
                 //     VIRTUAL_DISPLAY_MAX_TEXTURES,
                 // ));
 
+                let compositor_type = if let Some(settings) = settings {
+                    settings.vr_server.openvr.compositor_type
+                } else {
+                    DEFAULT_COMPOSITOR_TYPE
+                };
+
                 let hmd_context = Arc::new(HmdContext {
                     tracked_device_context: ctx.clone(),
-                    // display_component_ptr: Mutex::new(null_mut()),
-                    // virtual_display_ptr: Mutex::new(null_mut()),
-                    // driver_direct_mode_component_ptr: Mutex::new(null_mut()),
+                    compositor_type,
+                    // display_component_ptr: Mutex::new(SendPtr::null()),
+                    virtual_display_ptr: Mutex::new(SendPtr::null()),
+                    // driver_direct_mode_component_ptr: Mutex::new(SendPtr::null()),
                     // graphics: graphics.clone(),
                     // swap_texture_manager,
                     // current_layers: Mutex::new(vec![]),
@@ -252,31 +296,7 @@ impl VrServer {
                     // latest_vsync: Mutex::new((Instant::now(), 0)),
                 });
 
-                // let display_callbacks = create_display_callbacks(hmd_context.clone());
-                // *hmd_context.display_component_ptr.lock() =
-                //     unsafe { vr::vrCreateDisplayComponent(display_callbacks) };
-
-                // let compositor_type = if let Some(settings) = settings {
-                //     settings.openvr.compositor_type
-                // } else {
-                //     DEFAULT_COMPOSITOR_TYPE
-                // };
-
-                // match compositor_type {
-                //     CompositorType::SteamVR => {
-                //         let virtual_display_callbacks =
-                //             create_virtual_display_callbacks(hmd_context.clone());
-                //         *hmd_context.virtual_display_ptr.lock() =
-                //             unsafe { vr::vrCreateVirtualDisplay(virtual_display_callbacks) };
-                //     }
-                //     CompositorType::Custom => {
-                //         let driver_direct_mode_callbacks =
-                //             create_driver_direct_mode_callbacks(hmd_context.clone());
-                //         *hmd_context.driver_direct_mode_component_ptr.lock() = unsafe {
-                //             vr::vrCreateDriverDirectModeComponent(driver_direct_mode_callbacks)
-                //         };
-                //     }
-                // }
+                register_display_components(&hmd_context);
 
                 let hmd_callbacks = create_hmd_callbacks(hmd_context.clone());
                 let hmd_ptr = unsafe { vr::vrCreateTrackedDeviceServerDriver(hmd_callbacks) };
@@ -316,6 +336,7 @@ impl VrServer {
             // tracked_devices_contexts: tracked_devices_contexts.into_iter().collect(),
             // input_timer: Instant::now(),
             // controllers_contexts,
+            // input_range_enforcer: InputRangeEnforcer::new(),
         }
     }
 
@@ -342,8 +363,10 @@ impl VrServer {
 
     //         let p = sample.pose.position;
     //         let o = sample.pose.orientation;
-    //         let v = sample.linear_velocity;
-    //         let av = sample.angular_velocity;
+    //         let (v, av) = context
+    //             .motion_filter
+    //             .lock()
+    //             .filter_velocities(sample.linear_velocity, sample.angular_velocity);
     //         driver_pose.vecPosition = [p[0] as _, p[1] as _, p[2] as _];
     //         driver_pose.vecVelocity = [v[0] as _, v[1] as _, v[2] as _];
     //         driver_pose.qRotation = vr::HmdQuaternion_t {
@@ -370,6 +393,30 @@ impl VrServer {
     //     }
     // }
 
+    // pub fn update_battery_level(&self, device_type: TrackedDeviceType, battery: BatteryDesc) {
+    //     if let Some(context) = self.tracked_devices_contexts.get(&device_type) {
+    //         if !context.battery_throttle.lock().should_update(battery) {
+    //             return;
+    //         }
+
+    //         if let Some(object_id) = *context.object_id.lock() {
+    //             unsafe {
+    //                 let container = vr::vrTrackedDeviceToPropertyContainer(object_id);
+    //                 vr::vrSetFloatProperty(
+    //                     container,
+    //                     vr::Prop_DeviceBatteryPercentage_Float,
+    //                     battery.percentage,
+    //                 );
+    //                 vr::vrSetBoolProperty(
+    //                     container,
+    //                     vr::Prop_DeviceIsCharging_Bool,
+    //                     battery.is_charging,
+    //                 );
+    //             }
+    //         }
+    //     }
+    // }
+
     // pub fn update_virtual_vsync(&mut self, virtual_vsync_offset_ns: i32) {
     //     if let Some(hmd_context) = &self.hmd_context {
     //         let (vsync, _) = &mut *hmd_context.latest_vsync.lock();
@@ -384,14 +431,14 @@ impl VrServer {
     //     }
     // }
 
-    // pub fn process_input(&self, data: InputDeviceData, timestamp_ns: u64) {
+    // pub fn process_input(&mut self, data: InputDeviceData, timestamp_ns: u64) {
     //     let input_timestamp_ns = timestamp_ns as i64;
     //     let input = input_device_data_to_str_value_map(&data);
 
     //     for ctx in &self.controllers_contexts {
     //         let component_map = ctx.input_to_component_map.lock();
     //         for (path, value) in &input {
-    //             if let Some(component) = component_map.get(*path) {
+    //             if let Some((component, input_type)) = component_map.get(*path) {
     //                 let time_offset_s = (self.input_timer.elapsed().as_nanos() as i64
     //                     - input_timestamp_ns) as f64
     //                     / 1_000_000_f64;
@@ -408,9 +455,11 @@ impl VrServer {
     //                         }
     //                         InputValue::NormalizedOneSided(value)
     //                         | InputValue::NormalizedTwoSided(value) => {
+    //                             let value =
+    //                                 self.input_range_enforcer.enforce(path, *value, input_type);
     //                             vr::vrDriverInputUpdateScalarComponent(
     //                                 *component,
-    //                                 *value,
+    //                                 value,
     //                                 time_offset_s,
     //                             );
     //                         }
@@ -429,14 +478,17 @@ impl VrServer {
     //     present_sender: Sender<PresentData>,
     //     present_done_notif_receiver: Receiver<()>,
     //     haptic_enqueuer: PacketEnqueuer,
-    // ) -> StrResult {
+    // ) -> StrResult<ClientInitialization> {
     //     // the same openvr settings instance is shared between hmd, controllers and server.
     //     let new_settings = create_openvr_settings(Some(settings), session_desc);
-    //     if should_restart(&*self.settings.lock(), &new_settings) {
+    //     if let Some(reason) = restart_reason(&*self.settings.lock(), &new_settings) {
     //         // unwraps never fail
     //         unsafe {
-    //             let reason_c_string =
-    //                 CString::new("Critical properties changed. Restarting SteamVR.").unwrap();
+    //             let reason_c_string = CString::new(format!(
+    //                 "Critical properties changed ({:?}). Restarting SteamVR.",
+    //                 reason
+    //             ))
+    //             .unwrap();
     //             let executable_c_string = CString::new(
     //                 "" // todo: steamvr_launcher,
     //             ).unwrap();
@@ -454,6 +506,7 @@ impl VrServer {
     //             );
     //             // shutdown signal will be generated from SteamVR
     //         }
+    //         Ok(ClientInitialization::RestartRequested(reason))
     //     } else {
     //         *self.settings.lock() = new_settings;
     //         *self.server_context.haptic_enqueuer.lock() = Some(haptic_enqueuer);
@@ -465,8 +518,8 @@ impl VrServer {
     //         }
 
     //         // todo: notify settings changes to openvr using properties
+    //         Ok(ClientInitialization::Initialized)
     //     }
-    //     Ok(())
     // }
 
     // pub fn deinitialize_for_client(&mut self) {
@@ -484,16 +537,16 @@ impl VrServer {
 impl Drop for VrServer {
     fn drop(&mut self) {
         // if let Some(hmd_context) = &self.hmd_context {
-        //     let mut display_component_ptr = *hmd_context.display_component_ptr.lock();
+        //     let mut display_component_ptr = hmd_context.display_component_ptr.lock().0;
         //     unsafe { vr::vrDestroyDisplayComponent(&mut display_component_ptr) };
 
-        //     let mut virtual_display_ptr = *hmd_context.virtual_display_ptr.lock();
+        //     let mut virtual_display_ptr = hmd_context.virtual_display_ptr.lock().0;
         //     if !virtual_display_ptr.is_null() {
         //         unsafe { vr::vrDestroyVirtualDisplay(&mut virtual_display_ptr) };
         //     }
 
         //     let mut driver_direct_mode_component_ptr =
-        //         *hmd_context.driver_direct_mode_component_ptr.lock();
+        //         hmd_context.driver_direct_mode_component_ptr.lock().0;
         //     if !driver_direct_mode_component_ptr.is_null() {
         //         unsafe {
         //             vr::vrDestroyDriverDirectModeComponent(&mut driver_direct_mode_component_ptr)
@@ -0,0 +1,155 @@
+use bridgevr_common::data::*;
+use nalgebra::{Matrix3, Quaternion, UnitQuaternion, Vector3};
+use openvr_driver_sys as vr;
+
+// From the neck pivot to the center of the eyes, with the head facing forward (-Z)
+const NECK_TO_EYES_OFFSET: [f32; 3] = [0., 0.075, -0.08];
+
+// The orientation is normalized, so that a matrix -> pose -> matrix round trip is stable.
+pub fn pose_from_openvr_matrix(matrix: &vr::HmdMatrix34_t) -> Pose {
+    let m = matrix.m;
+    let na_matrix = Matrix3::new(
+        m[0][0], m[0][1], m[0][2], m[1][0], m[1][1], m[1][2], m[2][0], m[2][1], m[2][2],
+    );
+    let na_quat = UnitQuaternion::from_matrix(&na_matrix);
+    let orientation = [na_quat[3], na_quat[0], na_quat[1], na_quat[2]];
+    let position = [m[0][3], m[1][3], m[2][3]];
+
+    Pose {
+        position,
+        orientation,
+    }
+}
+
+// The orientation does not need to be normalized.
+pub fn pose_to_openvr_matrix(pose: &Pose) -> vr::HmdMatrix34_t {
+    let [w, x, y, z] = pose.orientation;
+    let rotation =
+        UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z)).to_rotation_matrix();
+    let r = rotation.matrix();
+    let p = pose.position;
+
+    vr::HmdMatrix34_t {
+        m: [
+            [r[(0, 0)], r[(0, 1)], r[(0, 2)], p[0]],
+            [r[(1, 0)], r[(1, 1)], r[(1, 2)], p[1]],
+            [r[(2, 0)], r[(2, 1)], r[(2, 2)], p[2]],
+        ],
+    }
+}
+
+// 3DoF devices report only the orientation. With the neck model, the head rotates around the neck
+// instead of the center of the eyes, so the position follows the orientation. The position is
+// default_position when facing forward. Without the neck model, the position is fixed.
+pub fn motion_3dof_to_6dof(
+    sample: &MotionSample3DofDesc,
+    neck_model: bool,
+) -> MotionSample6DofDesc {
+    let [w, x, y, z] = sample.orientation;
+    let rotation = UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z));
+    let default_position = Vector3::from(sample.default_position);
+    let angular_velocity = Vector3::from(sample.angular_velocity);
+
+    let (position, linear_velocity) = if neck_model {
+        let offset = Vector3::from(NECK_TO_EYES_OFFSET);
+        let rotated_offset = rotation * offset;
+        (
+            default_position + rotated_offset - offset,
+            angular_velocity.cross(&rotated_offset),
+        )
+    } else {
+        (default_position, Vector3::zeros())
+    };
+
+    MotionSample6DofDesc {
+        pose: Pose {
+            position: position.into(),
+            orientation: [rotation[3], rotation[0], rotation[1], rotation[2]],
+        },
+        linear_velocity: linear_velocity.into(),
+        angular_velocity: sample.angular_velocity,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_matrices_eq(a: &vr::HmdMatrix34_t, b: &vr::HmdMatrix34_t) {
+        for (row_a, row_b) in a.m.iter().zip(&b.m) {
+            for (value_a, value_b) in row_a.iter().zip(row_b) {
+                assert!((value_a - value_b).abs() < 1e-5, "{:?} != {:?}", a.m, b.m);
+            }
+        }
+    }
+
+    #[test]
+    fn matrix_pose_round_trip() {
+        // Rotated around more than one axis
+        let s = std::f32::consts::FRAC_1_SQRT_2;
+        let matrix = vr::HmdMatrix34_t {
+            m: [[0., s, s, 0.1], [0., s, -s, 1.6], [-1., 0., 0., -0.3]],
+        };
+
+        let pose = pose_from_openvr_matrix(&matrix);
+        assert_eq!(pose.position, [0.1, 1.6, -0.3]);
+        assert_matrices_eq(&pose_to_openvr_matrix(&pose), &matrix);
+    }
+
+    #[test]
+    fn orientation_is_normalized() {
+        let pose = Pose {
+            position: [0.; 3],
+            orientation: [2., 0., 0., 0.],
+        };
+        let identity = vr::HmdMatrix34_t {
+            m: [[1., 0., 0., 0.], [0., 1., 0., 0.], [0., 0., 1., 0.]],
+        };
+        assert_matrices_eq(&pose_to_openvr_matrix(&pose), &identity);
+
+        let pose = pose_from_openvr_matrix(&identity);
+        assert_eq!(pose.orientation, [1., 0., 0., 0.]);
+    }
+
+    fn assert_vectors_eq(a: [f32; 3], b: [f32; 3]) {
+        for (value_a, value_b) in a.iter().zip(&b) {
+            assert!((value_a - value_b).abs() < 1e-5, "{:?} != {:?}", a, b);
+        }
+    }
+
+    fn sample_3dof(orientation: [f32; 4], angular_velocity: [f32; 3]) -> MotionSample3DofDesc {
+        MotionSample3DofDesc {
+            default_position: [0., 1.6, 0.],
+            orientation,
+            linear_velocity: [0.; 3],
+            angular_velocity,
+            linear_acceleration: [0.; 3],
+            angular_acceleration: [0.; 3],
+        }
+    }
+
+    #[test]
+    fn neck_model_moves_eyes_around_neck() {
+        let s = std::f32::consts::FRAC_1_SQRT_2;
+
+        let sample = motion_3dof_to_6dof(&sample_3dof([1., 0., 0., 0.], [0., 1., 0.]), true);
+        assert_vectors_eq(sample.pose.position, [0., 1.6, 0.]);
+        assert_vectors_eq(sample.linear_velocity, [-0.08, 0., 0.]);
+        assert_eq!(sample.angular_velocity, [0., 1., 0.]);
+
+        // Turned 90 degrees to the left
+        let sample = motion_3dof_to_6dof(&sample_3dof([s, 0., s, 0.], [0.; 3]), true);
+        assert_vectors_eq(sample.pose.position, [-0.08, 1.6, 0.08]);
+        assert_vectors_eq(sample.linear_velocity, [0.; 3]);
+    }
+
+    #[test]
+    fn position_is_fixed_without_neck_model() {
+        let s = std::f32::consts::FRAC_1_SQRT_2;
+
+        let sample = motion_3dof_to_6dof(&sample_3dof([s, 0., s, 0.], [0., 1., 0.]), false);
+        assert_eq!(sample.pose.position, [0., 1.6, 0.]);
+        assert_eq!(sample.linear_velocity, [0.; 3]);
+        assert_eq!(sample.angular_velocity, [0., 1., 0.]);
+    }
+}
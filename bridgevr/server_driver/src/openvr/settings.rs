@@ -1,4 +1,4 @@
-use bridgevr_common::data::*;
+use bridgevr_common::{data::*, *};
 use log::*;
 use openvr_driver_sys as vr;
 use std::{ffi::*, time::*};
@@ -16,15 +16,45 @@ const DEFAULT_FOV: [Fov; 2] = [Fov {
 
 const DEFAULT_BLOCK_STANDBY: bool = false;
 
+const DEFAULT_MOTION_PREDICTION: MotionPredictionDesc = MotionPredictionDesc {
+    enabled: true,
+    velocity_smoothing: 0.,
+};
+
 // todo: use ::from_secs_f32 if it will be a const fn
 const DEFAULT_FRAME_INTERVAL: Duration = Duration::from_nanos((1e9 / 60_f32) as u64);
 
+const DEFAULT_PRESENT_TIMEOUT: Duration = Duration::from_millis(500);
+
 pub struct OpenvrSettings {
     pub tracked_devices: Vec<OpenvrTrackedDeviceDesc>,
+    pub controller_render_model: [Option<String>; 2],
+    pub hmd_motion_prediction: MotionPredictionDesc,
+    pub controllers_motion_prediction: MotionPredictionDesc,
     // pub block_standby: bool,
-    // pub target_eye_resolution: (u32, u32),
-    // pub fov: [Fov; 2],
-    // pub frame_interval: Duration,
+    pub target_eye_resolution: (u32, u32),
+    pub fov: [Fov; 2],
+    pub frame_interval: Duration,
+    pub present_timeout: Duration,
+}
+
+// A refresh rate higher than the client one is allowed but the extra frames are wasted
+fn frame_interval(refresh_rate_override: Option<u32>, client_fps: Option<u32>) -> Duration {
+    if let (Some(refresh_rate), Some(fps)) = (refresh_rate_override, client_fps) {
+        if refresh_rate > fps {
+            warn!(
+                "Refresh rate override ({}Hz) is higher than the client refresh rate ({}Hz)",
+                refresh_rate, fps
+            );
+        }
+    }
+
+    match refresh_rate_override.or(client_fps) {
+        Some(refresh_rate) if refresh_rate > 0 => {
+            Duration::from_secs_f32(1_f32 / refresh_rate as f32)
+        }
+        _ => DEFAULT_FRAME_INTERVAL,
+    }
 }
 
 pub fn create_openvr_settings(
@@ -33,49 +63,170 @@ pub fn create_openvr_settings(
 ) -> OpenvrSettings {
     // let block_standby;
     let tracked_devices;
+    let controller_render_model;
+    let refresh_rate_override;
+    let hmd_motion_prediction;
+    let controllers_motion_prediction;
+    let present_timeout;
     if let Some(settings) = settings {
         // block_standby = settings.openvr.block_standby;
-        tracked_devices = settings.vr_server.openvr.tracked_devices.clone();
+        let openvr_desc = &settings.vr_server.openvr;
+        for tracked_device_desc in &openvr_desc.tracked_devices {
+            for (prop_name, value) in &tracked_device_desc.properties {
+                validate_custom_prop(prop_name, value)
+                    .map_err(|e| warn!("{}", e))
+                    .ok();
+            }
+        }
+        tracked_devices = openvr_desc
+            .tracked_devices
+            .iter()
+            .filter(|td| openvr_desc.is_tracked_device_enabled(td.device_type))
+            .cloned()
+            .collect();
+        controller_render_model = openvr_desc.controller_render_model.clone();
+        refresh_rate_override = settings.vr_server.openvr.refresh_rate_override;
+        hmd_motion_prediction = settings.vr_server.openvr.hmd_motion_prediction;
+        controllers_motion_prediction = settings.vr_server.openvr.controllers_motion_prediction;
+        present_timeout = Duration::from_millis(settings.vr_server.openvr.present_timeout_ms);
     } else {
         // block_standby = DEFAULT_BLOCK_STANDBY;
         tracked_devices = vec![];
+        controller_render_model = [None, None];
+        refresh_rate_override = None;
+        hmd_motion_prediction = DEFAULT_MOTION_PREDICTION;
+        controllers_motion_prediction = DEFAULT_MOTION_PREDICTION;
+        present_timeout = DEFAULT_PRESENT_TIMEOUT;
+    };
+
+    let fov;
+    let client_fps;
+    let native_eye_resolution;
+    if let Some(client_handshake_packet) = &session_desc.last_client_handshake_packet {
+        fov = client_handshake_packet.fov;
+        client_fps = Some(client_handshake_packet.fps);
+        native_eye_resolution = client_handshake_packet.native_eye_resolution;
+    } else {
+        fov = DEFAULT_FOV;
+        client_fps = None;
+        native_eye_resolution = DEFAULT_EYE_RESOLUTION;
     };
+    let frame_interval = frame_interval(refresh_rate_override, client_fps);
 
-    // let fov;
-    // let frame_interval;
-    // if let Some(client_handshake_packet) = &session_desc.last_client_handshake_packet {
-    //     fov = client_handshake_packet.fov;
-    //     frame_interval = Duration::from_secs_f32(1_f32 / client_handshake_packet.fps as f32);
-    // } else {
-    //     fov = DEFAULT_FOV;
-    //     frame_interval = DEFAULT_FRAME_INTERVAL;
-    // };
-
-    // let target_eye_resolution = if let Some(Settings {
-    //     openvr:
-    //         OpenvrDesc {
-    //             preferred_render_eye_resolution: Some(eye_res),
-    //             ..
-    //         },
-    //     ..
-    // }) = settings
-    // {
-    //     *eye_res
-    // } else if let Some(client_handshake_packet) = &session_desc.last_client_handshake_packet {
-    //     client_handshake_packet.native_eye_resolution
-    // } else {
-    //     DEFAULT_EYE_RESOLUTION
-    // };
+    let target_eye_resolution = match settings
+        .and_then(|settings| settings.vr_server.openvr.preferred_render_eye_resolution)
+    {
+        Some(frame_size) => frame_size.target_eye_resolution(native_eye_resolution),
+        None => native_eye_resolution,
+    };
 
     OpenvrSettings {
         tracked_devices,
+        controller_render_model,
+        hmd_motion_prediction,
+        controllers_motion_prediction,
         // block_standby,
-        // target_eye_resolution,
-        // fov,
-        // frame_interval,
+        target_eye_resolution,
+        fov,
+        frame_interval,
+        present_timeout,
     }
 }
 
+// Properties that SteamVR reads only once, when the driver is loaded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestartReason {
+    EyeResolution,
+    Fov,
+    FrameInterval,
+    TrackedDevices,
+}
+
+// Returns None if the new settings can be applied without restarting SteamVR
+pub fn restart_reason(
+    old_settings: &OpenvrSettings,
+    new_settings: &OpenvrSettings,
+) -> Option<RestartReason> {
+    if new_settings.target_eye_resolution != old_settings.target_eye_resolution {
+        Some(RestartReason::EyeResolution)
+    } else if new_settings.fov != old_settings.fov {
+        Some(RestartReason::Fov)
+    } else if new_settings.frame_interval != old_settings.frame_interval {
+        Some(RestartReason::FrameInterval)
+    } else if new_settings.tracked_devices != old_settings.tracked_devices
+        || new_settings.controller_render_model != old_settings.controller_render_model
+    {
+        Some(RestartReason::TrackedDevices)
+    } else {
+        None
+    }
+}
+
+// Properties set before the ones in tracked_devices, see merge_custom_props()
+pub fn driver_default_props(
+    device_type: TrackedDeviceType,
+    settings: &OpenvrSettings,
+) -> Vec<(String, OpenvrPropValue)> {
+    let mut props = vec![
+        // The battery level is sent only by wireless devices
+        (
+            "Prop_DeviceProvidesBatteryStatus_Bool".to_owned(),
+            OpenvrPropValue::Bool(true),
+        ),
+    ];
+
+    let maybe_render_model = match device_type {
+        TrackedDeviceType::LeftController => settings.controller_render_model[0].as_ref(),
+        TrackedDeviceType::RightController => settings.controller_render_model[1].as_ref(),
+        _ => None,
+    };
+    if let Some(render_model) = maybe_render_model {
+        props.push((
+            "Prop_RenderModelName_String".to_owned(),
+            OpenvrPropValue::String(render_model.clone()),
+        ));
+    }
+
+    props
+}
+
+// Driver defaults are applied first, so that a user property with the same name replaces the
+// default. Within each list the last occurrence wins. Properties are compared by name, which maps to
+// a single property code.
+pub fn merge_custom_props(
+    driver_defaults: &[(String, OpenvrPropValue)],
+    user_props: &[(String, OpenvrPropValue)],
+) -> Vec<(String, OpenvrPropValue)> {
+    let mut merged: Vec<(String, OpenvrPropValue)> = vec![];
+    for (prop_name, value) in driver_defaults.iter().chain(user_props) {
+        if let Some(entry) = merged.iter_mut().find(|(name, _)| name == prop_name) {
+            entry.1 = value.clone();
+        } else {
+            merged.push((prop_name.clone(), value.clone()));
+        }
+    }
+
+    merged
+}
+
+// Used when the settings are loaded, so that an invalid property is reported once, before SteamVR
+// is asked to set it. The name ends with the type of the value, for example "_Float".
+pub fn validate_custom_prop(prop_name: &str, value: &OpenvrPropValue) -> StrResult {
+    trace_err!(vr::tracked_device_property_name_to_u32(prop_name))?;
+
+    let value_type = value.value_type();
+    if !prop_name.ends_with(&format!("_{:?}", value_type)) {
+        return trace_str!(
+            "Property {} cannot be set to a value of type {:?}",
+            prop_name,
+            value_type
+        );
+    }
+
+    Ok(())
+}
+
+// props: from merge_custom_props(), so that each property is set once
 // pub fn set_custom_props(
 //     container: vr::PropertyContainerHandle_t,
 //     props: &[(String, OpenvrPropValue)],
@@ -121,3 +272,193 @@ pub fn create_openvr_settings(
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn override_takes_precedence_over_client_fps() {
+        assert_eq!(
+            frame_interval(Some(90), Some(72)),
+            Duration::from_secs_f32(1. / 90.)
+        );
+        assert_eq!(
+            frame_interval(Some(60), Some(72)),
+            Duration::from_secs_f32(1. / 60.)
+        );
+    }
+
+    #[test]
+    fn client_fps_is_used_without_override() {
+        assert_eq!(
+            frame_interval(None, Some(72)),
+            Duration::from_secs_f32(1. / 72.)
+        );
+    }
+
+    #[test]
+    fn missing_or_zero_refresh_rate_uses_default() {
+        assert_eq!(frame_interval(None, None), DEFAULT_FRAME_INTERVAL);
+        assert_eq!(frame_interval(Some(0), Some(72)), DEFAULT_FRAME_INTERVAL);
+        assert_eq!(frame_interval(None, Some(0)), DEFAULT_FRAME_INTERVAL);
+    }
+
+    #[test]
+    fn present_timeout_is_read_from_the_settings() {
+        let session_desc = SessionDesc::default();
+        assert_eq!(
+            create_openvr_settings(None, &session_desc).present_timeout,
+            DEFAULT_PRESENT_TIMEOUT
+        );
+
+        let mut settings = default_settings();
+        settings.vr_server.openvr.present_timeout_ms = 2000;
+        assert_eq!(
+            create_openvr_settings(Some(&settings), &session_desc).present_timeout,
+            Duration::from_secs(2)
+        );
+    }
+
+    fn prop(name: &str, value: i32) -> (String, OpenvrPropValue) {
+        (name.to_owned(), OpenvrPropValue::Int32(value))
+    }
+
+    #[test]
+    fn user_props_replace_driver_defaults() {
+        let merged = merge_custom_props(
+            &[prop("Prop_A_Int32", 1), prop("Prop_B_Int32", 2)],
+            &[prop("Prop_B_Int32", 3), prop("Prop_C_Int32", 4)],
+        );
+        assert_eq!(
+            merged,
+            vec![
+                prop("Prop_A_Int32", 1),
+                prop("Prop_B_Int32", 3),
+                prop("Prop_C_Int32", 4)
+            ]
+        );
+    }
+
+    #[test]
+    fn last_duplicate_prop_wins() {
+        let merged = merge_custom_props(
+            &[prop("Prop_A_Int32", 1), prop("Prop_A_Int32", 2)],
+            &[prop("Prop_B_Int32", 3), prop("Prop_B_Int32", 4)],
+        );
+        assert_eq!(
+            merged,
+            vec![prop("Prop_A_Int32", 2), prop("Prop_B_Int32", 4)]
+        );
+    }
+
+    #[test]
+    fn custom_prop_type_must_match_name() {
+        assert!(validate_custom_prop(
+            "Prop_ModelNumber_String",
+            &OpenvrPropValue::String("BridgeVR".into())
+        )
+        .is_ok());
+        assert!(
+            validate_custom_prop("Prop_DisplayFrequency_Float", &OpenvrPropValue::Float(72.))
+                .is_ok()
+        );
+
+        let error =
+            validate_custom_prop("Prop_DisplayFrequency_Float", &OpenvrPropValue::Int32(72))
+                .unwrap_err();
+        assert!(error.contains("cannot be set to a value of type Int32"));
+    }
+
+    #[test]
+    fn unknown_custom_prop_is_rejected() {
+        assert!(
+            validate_custom_prop("Prop_NotAProperty_Float", &OpenvrPropValue::Float(1.)).is_err()
+        );
+    }
+
+    fn openvr_settings() -> OpenvrSettings {
+        OpenvrSettings {
+            tracked_devices: vec![],
+            controller_render_model: [None, None],
+            hmd_motion_prediction: DEFAULT_MOTION_PREDICTION,
+            controllers_motion_prediction: DEFAULT_MOTION_PREDICTION,
+            target_eye_resolution: DEFAULT_EYE_RESOLUTION,
+            fov: DEFAULT_FOV,
+            frame_interval: DEFAULT_FRAME_INTERVAL,
+            present_timeout: DEFAULT_PRESENT_TIMEOUT,
+        }
+    }
+
+    #[test]
+    fn runtime_settings_do_not_need_restart() {
+        let mut new_settings = openvr_settings();
+        new_settings.hmd_motion_prediction.enabled = false;
+        new_settings.present_timeout = Duration::from_secs(1);
+
+        assert_eq!(restart_reason(&openvr_settings(), &new_settings), None);
+    }
+
+    #[test]
+    fn settings_read_once_need_restart() {
+        let mut new_settings = openvr_settings();
+        new_settings.target_eye_resolution = (1920, 1080);
+        assert_eq!(
+            restart_reason(&openvr_settings(), &new_settings),
+            Some(RestartReason::EyeResolution)
+        );
+
+        let mut new_settings = openvr_settings();
+        new_settings.fov[1].left = 50.;
+        assert_eq!(
+            restart_reason(&openvr_settings(), &new_settings),
+            Some(RestartReason::Fov)
+        );
+
+        let mut new_settings = openvr_settings();
+        new_settings.frame_interval = frame_interval(Some(90), None);
+        assert_eq!(
+            restart_reason(&openvr_settings(), &new_settings),
+            Some(RestartReason::FrameInterval)
+        );
+
+        let mut new_settings = openvr_settings();
+        new_settings.controller_render_model[0] = Some("vr_controller_vive_1_5".into());
+        assert_eq!(
+            restart_reason(&openvr_settings(), &new_settings),
+            Some(RestartReason::TrackedDevices)
+        );
+    }
+
+    fn render_model(device_type: TrackedDeviceType, settings: &OpenvrSettings) -> Option<String> {
+        driver_default_props(device_type, settings)
+            .into_iter()
+            .find_map(|(name, value)| match value {
+                OpenvrPropValue::String(render_model) if name == "Prop_RenderModelName_String" => {
+                    Some(render_model)
+                }
+                _ => None,
+            })
+    }
+
+    #[test]
+    fn render_model_is_set_only_for_controllers() {
+        let mut settings = openvr_settings();
+        settings.controller_render_model = [Some("left_model".into()), None];
+        assert_eq!(
+            render_model(TrackedDeviceType::LeftController, &settings),
+            Some("left_model".into())
+        );
+        assert_eq!(
+            render_model(TrackedDeviceType::RightController, &settings),
+            None
+        );
+
+        settings.controller_render_model = [Some("model".into()), Some("model".into())];
+        assert_eq!(render_model(TrackedDeviceType::HMD, &settings), None);
+        assert_eq!(
+            render_model(TrackedDeviceType::GenericTracker1, &settings),
+            None
+        );
+    }
+}
@@ -10,16 +10,117 @@ use std::{
     os::raw::*,
     ptr,
     sync::{mpsc::*, Arc},
+    time::*,
 };
 
 const HAPTIC_PATH: &str = "/output/haptic";
 
+const BATTERY_UPDATE_MIN_INTERVAL: Duration = Duration::from_secs(10);
+
+// Avoid spamming property changes for a noisy battery reading. A change of charging state is always
+// forwarded.
+pub struct BatteryThrottle {
+    last_update: Option<(Instant, BatteryDesc)>,
+}
+
+impl BatteryThrottle {
+    pub fn new() -> Self {
+        Self { last_update: None }
+    }
+
+    pub fn should_update(&mut self, battery: BatteryDesc) -> bool {
+        let should_update = match self.last_update {
+            Some((time, last_battery)) => {
+                battery.is_charging != last_battery.is_charging
+                    || (battery.percentage != last_battery.percentage
+                        && time.elapsed() >= BATTERY_UPDATE_MIN_INTERVAL)
+            }
+            None => true,
+        };
+        if should_update {
+            self.last_update = Some((Instant::now(), battery));
+        }
+
+        should_update
+    }
+}
+
+impl Default for BatteryThrottle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Exponential moving average of a 3D vector
+pub struct ExponentialSmoother {
+    smoothing: f32,
+    state: Option<[f32; 3]>,
+}
+
+impl ExponentialSmoother {
+    // smoothing: weight of the previous state, in [0, 1)
+    pub fn new(smoothing: f32) -> Self {
+        Self {
+            smoothing,
+            state: None,
+        }
+    }
+
+    pub fn filter(&mut self, sample: [f32; 3]) -> [f32; 3] {
+        let mut new_state = sample;
+        if let Some(state) = self.state {
+            for (new_value, value) in new_state.iter_mut().zip(&state) {
+                *new_value = self.smoothing * value + (1_f32 - self.smoothing) * *new_value;
+            }
+        }
+        self.state = Some(new_state);
+
+        new_state
+    }
+}
+
+// Velocities used by SteamVR to extrapolate the pose
+pub struct MotionFilter {
+    prediction_enabled: bool,
+    linear_velocity: ExponentialSmoother,
+    angular_velocity: ExponentialSmoother,
+}
+
+impl MotionFilter {
+    pub fn new(desc: MotionPredictionDesc) -> Self {
+        Self {
+            prediction_enabled: desc.enabled,
+            linear_velocity: ExponentialSmoother::new(desc.velocity_smoothing),
+            angular_velocity: ExponentialSmoother::new(desc.velocity_smoothing),
+        }
+    }
+
+    // Returns linear and angular velocities. They are zero if prediction is disabled.
+    pub fn filter_velocities(
+        &mut self,
+        linear_velocity: [f32; 3],
+        angular_velocity: [f32; 3],
+    ) -> ([f32; 3], [f32; 3]) {
+        if self.prediction_enabled {
+            (
+                self.linear_velocity.filter(linear_velocity),
+                self.angular_velocity.filter(angular_velocity),
+            )
+        } else {
+            ([0_f32; 3], [0_f32; 3])
+        }
+    }
+}
+
 pub struct TrackedDeviceContext {
     // pub device_type: TrackedDeviceType,
     // pub object_id: Mutex<Option<u32>>,
-    // pub settings: Arc<Mutex<OpenvrSettings>>,
+    pub settings: Arc<Mutex<OpenvrSettings>>,
+    pub battery_throttle: Mutex<BatteryThrottle>,
+    pub motion_filter: Mutex<MotionFilter>,
     // pub pose: Mutex<vr::DriverPose_t>,
-    // pub input_to_component_map: Mutex<HashMap<String, vr::VRInputComponentHandle_t>>,
+    // pub input_to_component_map:
+    //     Mutex<HashMap<String, (vr::VRInputComponentHandle_t, OpenvrInputType)>>,
     // pub haptic_component: Mutex<vr::VRInputComponentHandle_t>,
     // pub shutdown_signal_sender: Arc<Mutex<Sender<ShutdownSignal>>>,
 }
@@ -45,14 +146,17 @@ pub extern "C" fn activate(context: *mut c_void, object_id: u32) -> vr::EVRInitE
     //     }
     // }
 
-    // if let Some(tracked_device_desc) = context
-    //     .settings
-    //     .lock()
+    // let settings = context.settings.lock();
+    // if let Some(tracked_device_desc) = settings
     //     .tracked_devices
     //     .iter()
     //     .find(|td| td.device_type == context.device_type)
     // {
-    //     set_custom_props(container, &tracked_device_desc.properties);
+    //     let driver_default_props = driver_default_props(context.device_type, &settings);
+    //     set_custom_props(
+    //         container,
+    //         &merge_custom_props(&driver_default_props, &tracked_device_desc.properties),
+    //     );
 
     //     let mut component_map_ref = context.input_to_component_map.lock();
     //     for (openvr_path, input_type, client_paths) in &tracked_device_desc.input_mapping {
@@ -85,7 +189,7 @@ pub extern "C" fn activate(context: *mut c_void, object_id: u32) -> vr::EVRInitE
     //         };
     //         if res == 0 {
     //             for path in client_paths {
-    //                 component_map_ref.insert(path.to_owned(), component);
+    //                 component_map_ref.insert(path.to_owned(), (component, input_type.clone()));
     //             }
     //         } else {
     //             warn!("Create {}: {}", openvr_path, res);
@@ -182,3 +286,77 @@ pub fn create_tracked_device_callbacks(
         GetPose: Some(get_pose),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn battery(percentage: f32, is_charging: bool) -> BatteryDesc {
+        BatteryDesc {
+            percentage,
+            is_charging,
+        }
+    }
+
+    #[test]
+    fn first_battery_reading_is_forwarded() {
+        assert!(BatteryThrottle::new().should_update(battery(0.5, false)));
+    }
+
+    #[test]
+    fn battery_level_changes_are_throttled() {
+        let mut throttle = BatteryThrottle::new();
+        assert!(throttle.should_update(battery(0.5, false)));
+        assert!(!throttle.should_update(battery(0.5, false)));
+        assert!(!throttle.should_update(battery(0.49, false)));
+    }
+
+    #[test]
+    fn charging_state_changes_are_forwarded() {
+        let mut throttle = BatteryThrottle::new();
+        assert!(throttle.should_update(battery(0.5, false)));
+        assert!(throttle.should_update(battery(0.5, true)));
+        assert!(throttle.should_update(battery(0.5, false)));
+    }
+
+    fn motion_filter(enabled: bool, velocity_smoothing: f32) -> MotionFilter {
+        MotionFilter::new(MotionPredictionDesc {
+            enabled,
+            velocity_smoothing,
+        })
+    }
+
+    #[test]
+    fn smoother_averages_with_previous_state() {
+        let mut smoother = ExponentialSmoother::new(0.5);
+        assert_eq!(smoother.filter([2., 0., -4.]), [2., 0., -4.]);
+        assert_eq!(smoother.filter([4., 2., 0.]), [3., 1., -2.]);
+        assert_eq!(smoother.filter([3., 1., -2.]), [3., 1., -2.]);
+    }
+
+    #[test]
+    fn zero_smoothing_passes_samples_through() {
+        let mut smoother = ExponentialSmoother::new(0.);
+        smoother.filter([1., 2., 3.]);
+        assert_eq!(smoother.filter([4., 5., 6.]), [4., 5., 6.]);
+    }
+
+    #[test]
+    fn velocities_are_zero_without_prediction() {
+        let mut filter = motion_filter(false, 0.5);
+        assert_eq!(
+            filter.filter_velocities([1., 2., 3.], [4., 5., 6.]),
+            ([0.; 3], [0.; 3])
+        );
+    }
+
+    #[test]
+    fn linear_and_angular_velocities_are_smoothed_separately() {
+        let mut filter = motion_filter(true, 0.5);
+        filter.filter_velocities([2., 0., 0.], [0., 2., 0.]);
+        assert_eq!(
+            filter.filter_velocities([0., 0., 0.], [0., 0., 0.]),
+            ([1., 0., 0.], [0., 1., 0.])
+        );
+    }
+}
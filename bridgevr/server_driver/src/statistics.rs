@@ -1,4 +1,206 @@
+use bridgevr_common::{graphics::*, *};
+use log::*;
+use std::time::*;
 
-pub fn log_statistics() {
+const FRAME_INTERVAL_BUCKET_WIDTH: Duration = Duration::from_micros(500);
 
-}
\ No newline at end of file
+// The last bucket collects all intervals longer than 50ms
+const FRAME_INTERVAL_BUCKET_COUNT: usize = 100;
+
+#[derive(Clone, Copy, Debug)]
+pub enum GpuStage {
+    Composition,
+    EncoderUpload,
+}
+
+pub struct FrameIntervalHistogram {
+    buckets: Vec<u32>,
+    sample_count: u32,
+}
+
+impl FrameIntervalHistogram {
+    pub fn new() -> Self {
+        Self {
+            buckets: vec![0; FRAME_INTERVAL_BUCKET_COUNT],
+            sample_count: 0,
+        }
+    }
+
+    pub fn bucket_width() -> Duration {
+        FRAME_INTERVAL_BUCKET_WIDTH
+    }
+
+    pub fn add_sample(&mut self, interval: Duration) {
+        let bucket_idx = (interval.as_micros() / FRAME_INTERVAL_BUCKET_WIDTH.as_micros()) as usize;
+        self.buckets[bucket_idx.min(FRAME_INTERVAL_BUCKET_COUNT - 1)] += 1;
+        self.sample_count += 1;
+    }
+
+    // Sample counts, each bucket is bucket_width() wide
+    pub fn buckets(&self) -> &[u32] {
+        &self.buckets
+    }
+
+    // Upper bound of the bucket that contains the percentile (in [0, 1]). None if there are no
+    // samples.
+    pub fn percentile(&self, percentile: f32) -> Option<Duration> {
+        if self.sample_count == 0 {
+            return None;
+        }
+
+        let rank = ((percentile.clamp(0., 1.) * self.sample_count as f32).ceil() as u32).max(1);
+        let mut cumulative_count = 0;
+        for (idx, count) in self.buckets.iter().enumerate() {
+            cumulative_count += count;
+            if cumulative_count >= rank {
+                return Some(FRAME_INTERVAL_BUCKET_WIDTH * (idx as u32 + 1));
+            }
+        }
+
+        // unreachable because the bucket counts sum to sample_count
+        None
+    }
+
+    pub fn reset(&mut self) {
+        self.buckets.iter_mut().for_each(|count| *count = 0);
+        self.sample_count = 0;
+    }
+}
+
+impl Default for FrameIntervalHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Default)]
+pub struct Statistics {
+    composition_gpu_ms: Option<f32>,
+    encoder_upload_gpu_ms: Option<f32>,
+    last_frame_time: Option<Instant>,
+    frame_intervals: FrameIntervalHistogram,
+}
+
+impl Statistics {
+    // The measured time is None if the graphics backend does not support timestamp queries.
+    pub fn report_gpu_stage(
+        &mut self,
+        stage: GpuStage,
+        query_provider: &impl TimestampQueryProvider,
+    ) -> StrResult {
+        let gpu_ms = measured_gpu_ms(query_provider)?;
+        match stage {
+            GpuStage::Composition => self.composition_gpu_ms = gpu_ms,
+            GpuStage::EncoderUpload => self.encoder_upload_gpu_ms = gpu_ms,
+        }
+        Ok(())
+    }
+
+    pub fn gpu_stage_ms(&self, stage: GpuStage) -> Option<f32> {
+        match stage {
+            GpuStage::Composition => self.composition_gpu_ms,
+            GpuStage::EncoderUpload => self.encoder_upload_gpu_ms,
+        }
+    }
+
+    pub fn report_frame(&mut self) {
+        let now = Instant::now();
+        if let Some(last_frame_time) = self.last_frame_time {
+            self.frame_intervals.add_sample(now - last_frame_time);
+        }
+        self.last_frame_time = Some(now);
+    }
+
+    // Intervals collected since the last call to log_statistics()
+    pub fn frame_intervals(&self) -> &FrameIntervalHistogram {
+        &self.frame_intervals
+    }
+}
+
+pub fn log_statistics(statistics: &mut Statistics) {
+    for &stage in &[GpuStage::Composition, GpuStage::EncoderUpload] {
+        match statistics.gpu_stage_ms(stage) {
+            Some(gpu_ms) => info!("{:?} GPU time: {:.2}ms", stage, gpu_ms),
+            None => debug!("{:?} GPU time: unavailable", stage),
+        }
+    }
+
+    let histogram = &statistics.frame_intervals;
+    if let (Some(p50), Some(p95), Some(p99)) = (
+        histogram.percentile(0.5),
+        histogram.percentile(0.95),
+        histogram.percentile(0.99),
+    ) {
+        info!(
+            "Frame interval p50: {:?}, p95: {:?}, p99: {:?}",
+            p50, p95, p99
+        );
+    }
+
+    statistics.frame_intervals.reset();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn empty_histogram_has_no_percentile() {
+        assert_eq!(FrameIntervalHistogram::new().percentile(0.5), None);
+    }
+
+    #[test]
+    fn samples_are_bucketed() {
+        let mut histogram = FrameIntervalHistogram::new();
+        histogram.add_sample(Duration::from_micros(200));
+        histogram.add_sample(Duration::from_micros(700));
+        histogram.add_sample(ms(1000));
+
+        let buckets = histogram.buckets();
+        assert_eq!(buckets[0], 1);
+        assert_eq!(buckets[1], 1);
+        // long intervals go to the last bucket
+        assert_eq!(buckets[FRAME_INTERVAL_BUCKET_COUNT - 1], 1);
+    }
+
+    #[test]
+    fn percentiles_are_bucket_upper_bounds() {
+        let mut histogram = FrameIntervalHistogram::new();
+        for _ in 0..98 {
+            histogram.add_sample(Duration::from_micros(11_100));
+        }
+        histogram.add_sample(ms(20));
+        histogram.add_sample(ms(40));
+
+        assert_eq!(
+            histogram.percentile(0.),
+            Some(Duration::from_micros(11_500))
+        );
+        assert_eq!(
+            histogram.percentile(0.5),
+            Some(Duration::from_micros(11_500))
+        );
+        assert_eq!(
+            histogram.percentile(0.99),
+            Some(Duration::from_micros(20_500))
+        );
+        assert_eq!(
+            histogram.percentile(1.),
+            Some(Duration::from_micros(40_500))
+        );
+    }
+
+    #[test]
+    fn reset_clears_samples() {
+        let mut histogram = FrameIntervalHistogram::new();
+        histogram.add_sample(ms(11));
+        histogram.reset();
+
+        assert_eq!(histogram.percentile(1.), None);
+        assert!(histogram.buckets().iter().all(|&count| count == 0));
+    }
+}
@@ -1,6 +1,122 @@
+use bridgevr_common::{data::EncoderConfig, *};
+use lazy_static::lazy_static;
+use log::*;
+use std::{collections::BTreeMap, time::Instant};
+
+const TRACE_CONTEXT: &str = "Video encoder";
+
+lazy_static! {
+    static ref TIMESTAMP_EPOCH: Instant = Instant::now();
+}
+
+// Monotonic clock used for VideoPacket::server_timestamp_ns. The same clock must be used when
+// establishing the clock offset with the client.
+pub fn server_timestamp_ns() -> u64 {
+    TIMESTAMP_EPOCH.elapsed().as_nanos() as u64
+}
+
+// Decides when the encoded slices are sent (VideoDesc::progressive_slices). In progressive mode each
+// slice is sent as soon as it is encoded. Otherwise the slices of a frame are held until the last one
+// is encoded, then sent together. A frame with a missing slice, for example because its encoding
+// failed, is sent when a newer frame is complete. Shared by the encoders of all slices.
+pub struct SliceSendGate<T> {
+    progressive: bool,
+    slice_count: usize,
+    pending_frames: BTreeMap<u64, Vec<T>>,
+}
+
+impl<T> SliceSendGate<T> {
+    pub fn new(progressive: bool, slice_count: usize) -> Self {
+        Self {
+            progressive,
+            slice_count,
+            pending_frames: BTreeMap::new(),
+        }
+    }
+
+    // Returns the slices to send now, oldest frame first
+    pub fn push(&mut self, frame_index: u64, slice: T) -> Vec<T> {
+        if self.progressive || self.slice_count <= 1 {
+            return vec![slice];
+        }
+
+        let frame_slices = self.pending_frames.entry(frame_index).or_default();
+        frame_slices.push(slice);
+        if frame_slices.len() < self.slice_count {
+            return vec![];
+        }
+
+        let newer_frames = self.pending_frames.split_off(&(frame_index + 1));
+        let ready_frames = std::mem::replace(&mut self.pending_frames, newer_frames);
+        ready_frames.into_values().flatten().collect()
+    }
+}
+
+// Per frame overrides of the encoder rate control
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FrameRateControl {
+    pub force_keyframe: bool,
+    // None: the configured bitrate is used
+    pub bitrate_bps: Option<i64>,
+}
+
+// Encoders that apply a bitrate change from the next frame, without restarting the stream
+fn supports_dynamic_bitrate(codec_name: &str) -> bool {
+    codec_name.ends_with("_nvenc") || codec_name == "libx264" || codec_name == "libx265"
+}
+
+// scene_change: set by the compositor for the first frame of a new scene. The frame is encoded as a
+// keyframe, since it would be almost as large anyway. Its bitrate is lowered only if the encoder
+// supports changing it between frames.
+pub fn scene_change_rate_control(config: &EncoderConfig, scene_change: bool) -> FrameRateControl {
+    match config.scene_change_bitrate_scale {
+        Some(scale) if scene_change => FrameRateControl {
+            force_keyframe: true,
+            bitrate_bps: config
+                .bitrate_bps
+                .filter(|_| supports_dynamic_bitrate(&config.codec_name))
+                .map(|bitrate_bps| ((bitrate_bps as f32 * scale) as i64).max(1)),
+        },
+        _ => FrameRateControl {
+            force_keyframe: false,
+            bitrate_bps: None,
+        },
+    }
+}
+
+// Open an encoder with the primary config and, if it fails, with the fallback config. Returns the
+// encoder and the config that was used.
+pub fn open_encoder_with_fallback<T>(
+    primary_config: EncoderConfig,
+    maybe_fallback_config: Option<EncoderConfig>,
+    mut open: impl FnMut(EncoderConfig) -> StrResult<T>,
+) -> StrResult<(T, EncoderConfig)> {
+    let primary_error = match open(primary_config.clone()) {
+        Ok(encoder) => return Ok((encoder, primary_config)),
+        Err(e) => e,
+    };
+
+    let fallback_config = if let Some(config) = maybe_fallback_config {
+        config
+    } else {
+        return Err(primary_error);
+    };
+
+    warn!(
+        "Encoder {} failed to open, falling back to {}: {}",
+        primary_config.codec_name, fallback_config.codec_name, primary_error
+    );
+    let encoder = trace_err!(
+        open(fallback_config.clone()),
+        "Fallback encoder {} failed to open",
+        fallback_config.codec_name
+    )?;
+
+    Ok((encoder, fallback_config))
+}
+
 // use crate::compositor::*;
 // use bridgevr_common::{
-//     data::VideoEncoderDesc,
 //     sockets::*,
 //     thread_loop::{self, *},
 //     *,
@@ -8,8 +124,6 @@
 // use log::debug;
 // use std::{sync::mpsc::*, time::Duration};
 
-// const TRACE_CONTEXT: &str = "Video encoder";
-
 // const TIMEOUT: Duration = Duration::from_millis(100);
 
 // pub fn aligned_resolution((width, height): (u32, u32)) -> (u32, u32) {
@@ -21,50 +135,77 @@
 
 // pub struct VideoEncoder {
 //     thread_loop: ThreadLoop,
+//     codec_name: String,
 // }
 
 // impl VideoEncoder {
 //     pub fn new(
 //         thread_name: &str,
-//         settings: VideoEncoderDesc,
-//         resolution: (u32, u32),
-//         frame_rate: u32,
+//         config: EncoderConfig,
+//         maybe_fallback_config: Option<EncoderConfig>,
 //         slice_receiver: Receiver<FrameSlice>,
 //         slice_encoded_notif_sender: Sender<()>,
 //         packet_enqueuer: PacketEnqueuer,
+//         slice_send_gate: Arc<Mutex<SliceSendGate<Box<dyn FnOnce() + Send>>>>,
 //     ) -> StrResult<Self> {
+//         // todo: implement FfmpegEncoder
+//         let (ffmpeg_encoder, config) =
+//             open_encoder_with_fallback(config, maybe_fallback_config, |config| {
+//                 FfmpegEncoder::open(&config)
+//             })?;
+
 //         // let encode_callback = match settings {
 //         //     VideoEncoderDesc::Nvidia(nv_codec) => {
 //         //         let encoder =
-//         //             NvidiaEncoder::new(graphics_device_ptr, resolution, frame_rate, nv_codec)?;
+//         //             NvidiaEncoder::new(
+//         //                 graphics_device_ptr,
+//         //                 resolution,
+//         //                 pixel_format,
+//         //                 frame_rate,
+//         //                 nv_codec,
+//         //             )?;
 
-//         //         move |texture, force_idr| encoder.encode(force_idr, texture)
+//         //         move |texture, force_idr, bitrate_bps| {
+//         //             encoder.encode(force_idr, bitrate_bps, texture)
+//         //         }
 //         //     }
 //         //     VideoEncoderDesc::Gstreamer(pipeline_str) => todo!(),
 //         // };
 
 //         // let thread_loop = thread_loop::spawn(thread_name, move || {
 //         //     let mut maybe_video_packet = None;
+//         //     let mut frame_index = 0;
 //         //     frame_consumer
 //         //         .consume(TIMEOUT, |frame_slice| {
-//         //             maybe_video_packet =
-//         //                 encode_callback(frame_slice.texture.clone(), frame_slice.force_idr)
-//         //                     .map_err(|e| debug!("{}", e))
-//         //                     .ok();
+//         //             frame_index = frame_slice.frame_index;
+//         //             let rate_control =
+//         //                 scene_change_rate_control(&config, frame_slice.scene_change);
+//         //             maybe_video_packet = encode_callback(
+//         //                 frame_slice.texture.clone(),
+//         //                 frame_slice.force_idr || rate_control.force_keyframe,
+//         //                 rate_control.bitrate_bps.or(config.bitrate_bps),
+//         //             )
+//         //             .map_err(|e| debug!("{}", e))
+//         //             .ok();
 //         //             Ok(())
 //         //         })
 //         //         .map_err(|e| debug!("{:?}", e))
 //         //         .ok();
 
 //         //     if let Some(video_packet) = maybe_video_packet {
-//         //         packet_producer
-//         //             .fill(TIMEOUT, |sender_data| {
-//         //                 sender_data.packet = video_packet;
-//         //                 // todo fill other fields
-//         //                 Ok(())
-//         //             })
-//         //             .map_err(|e| debug!("{:?}", e))
-//         //             .ok();
+//         //         let mut packet_enqueuer = packet_enqueuer.clone();
+//         //         let send: Box<dyn FnOnce() + Send> = Box::new(move || {
+//         //             // todo: packetize_nal(nal_index, hmd_pose, server_timestamp_ns(), ..)
+//         //             packet_enqueuer
+//         //                 .enqueue(&video_packet)
+//         //                 .map_err(|e| debug!("{}", e))
+//         //                 .ok();
+//         //         });
+//         //         // The lock is released before sending
+//         //         let ready_slices = slice_send_gate.lock().push(frame_index, send);
+//         //         for send in ready_slices {
+//         //             send();
+//         //         }
 //         //     }
 //         // })?;
 
@@ -72,7 +213,184 @@
 //         todo!()
 //     }
 
+//     // Differs from the configured codec if the fallback encoder is used
+//     pub fn codec_name(&self) -> &str {
+//         &self.codec_name
+//     }
+
 //     pub fn request_stop(&mut self) {
 //         self.thread_loop.request_stop()
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bridgevr_common::data::VideoPixelFormat;
+
+    fn encoder_config(codec_name: &str) -> EncoderConfig {
+        EncoderConfig {
+            resolution: (1920, 1080),
+            fps: 72,
+            codec_name: codec_name.into(),
+            pixel_format: VideoPixelFormat::NV12,
+            bitrate_bps: Some(30_000_000),
+            scene_change_bitrate_scale: None,
+            max_gop: None,
+            context_options: vec![],
+            priv_data_options: vec![],
+            codec_open_options: vec![],
+            frame_options: vec![],
+            hw_frames_context_options: vec![],
+        }
+    }
+
+    // Opens only the encoders in `working_codecs`, and records the attempts
+    fn open_fn<'a>(
+        working_codecs: &'a [&str],
+        attempts: &'a mut Vec<String>,
+    ) -> impl FnMut(EncoderConfig) -> StrResult<String> + 'a {
+        move |config| {
+            attempts.push(config.codec_name.clone());
+            if working_codecs.contains(&config.codec_name.as_str()) {
+                Ok(config.codec_name)
+            } else {
+                Err(format!("{} failed", config.codec_name))
+            }
+        }
+    }
+
+    #[test]
+    fn primary_encoder_is_preferred() {
+        let mut attempts = vec![];
+        let (encoder, config) = open_encoder_with_fallback(
+            encoder_config("hevc_nvenc"),
+            Some(encoder_config("libx264")),
+            open_fn(&["hevc_nvenc", "libx264"], &mut attempts),
+        )
+        .unwrap();
+
+        assert_eq!(encoder, "hevc_nvenc");
+        assert_eq!(config, encoder_config("hevc_nvenc"));
+        assert_eq!(attempts, vec!["hevc_nvenc"]);
+    }
+
+    #[test]
+    fn fallback_encoder_is_used_if_primary_fails() {
+        let mut attempts = vec![];
+        let (encoder, config) = open_encoder_with_fallback(
+            encoder_config("hevc_nvenc"),
+            Some(encoder_config("libx264")),
+            open_fn(&["libx264"], &mut attempts),
+        )
+        .unwrap();
+
+        assert_eq!(encoder, "libx264");
+        assert_eq!(config, encoder_config("libx264"));
+        assert_eq!(attempts, vec!["hevc_nvenc", "libx264"]);
+    }
+
+    #[test]
+    fn encoder_errors_are_reported() {
+        let mut attempts = vec![];
+        let error = open_encoder_with_fallback(
+            encoder_config("hevc_nvenc"),
+            None,
+            open_fn(&[], &mut attempts),
+        )
+        .unwrap_err();
+        assert_eq!(error, "hevc_nvenc failed");
+
+        let error = open_encoder_with_fallback(
+            encoder_config("hevc_nvenc"),
+            Some(encoder_config("libx264")),
+            open_fn(&[], &mut attempts),
+        )
+        .unwrap_err();
+        assert!(error.contains("Fallback encoder libx264 failed to open"));
+        assert!(error.ends_with("libx264 failed"));
+    }
+
+    const DEFAULT_RATE_CONTROL: FrameRateControl = FrameRateControl {
+        force_keyframe: false,
+        bitrate_bps: None,
+    };
+
+    #[test]
+    fn scene_change_lowers_bitrate_of_keyframe() {
+        let mut config = encoder_config("hevc_nvenc");
+        config.scene_change_bitrate_scale = Some(0.5);
+
+        assert_eq!(
+            scene_change_rate_control(&config, true),
+            FrameRateControl {
+                force_keyframe: true,
+                bitrate_bps: Some(15_000_000)
+            }
+        );
+        assert_eq!(
+            scene_change_rate_control(&config, false),
+            DEFAULT_RATE_CONTROL
+        );
+    }
+
+    #[test]
+    fn scene_change_bitrate_requires_dynamic_bitrate_support() {
+        let mut config = encoder_config("hevc_amf");
+        config.scene_change_bitrate_scale = Some(0.5);
+        assert_eq!(
+            scene_change_rate_control(&config, true),
+            FrameRateControl {
+                force_keyframe: true,
+                bitrate_bps: None
+            }
+        );
+
+        let mut config = encoder_config("libx264");
+        config.scene_change_bitrate_scale = Some(0.);
+        assert_eq!(
+            scene_change_rate_control(&config, true).bitrate_bps,
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn scene_change_is_ignored_if_disabled() {
+        let config = encoder_config("hevc_nvenc");
+        assert_eq!(
+            scene_change_rate_control(&config, true),
+            DEFAULT_RATE_CONTROL
+        );
+    }
+
+    #[test]
+    fn progressive_slices_are_sent_immediately() {
+        let mut gate = SliceSendGate::new(true, 3);
+        assert_eq!(gate.push(0, "0.1"), vec!["0.1"]);
+        assert_eq!(gate.push(0, "0.0"), vec!["0.0"]);
+
+        let mut gate = SliceSendGate::new(false, 1);
+        assert_eq!(gate.push(0, "0.0"), vec!["0.0"]);
+    }
+
+    #[test]
+    fn slices_are_held_until_frame_is_complete() {
+        let mut gate = SliceSendGate::new(false, 2);
+        assert!(gate.push(0, "0.1").is_empty());
+        assert!(gate.push(1, "1.0").is_empty());
+        assert_eq!(gate.push(0, "0.0"), vec!["0.1", "0.0"]);
+        assert_eq!(gate.push(1, "1.1"), vec!["1.0", "1.1"]);
+    }
+
+    #[test]
+    fn incomplete_frame_is_sent_with_newer_complete_frame() {
+        let mut gate = SliceSendGate::new(false, 2);
+        assert!(gate.push(0, "0.0").is_empty());
+        assert!(gate.push(1, "1.0").is_empty());
+        assert!(gate.push(2, "2.0").is_empty());
+        assert_eq!(gate.push(1, "1.1"), vec!["0.0", "1.0", "1.1"]);
+
+        // The newer incomplete frame is still held
+        assert_eq!(gate.push(2, "2.1"), vec!["2.0", "2.1"]);
+    }
+}
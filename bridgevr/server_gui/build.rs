@@ -2,5 +2,9 @@ use bridgevr_common::data::*;
 
 fn main() {
     println!("cargo:rustc-env=BVR_SERVER_VERSION={}", BVR_SERVER_VERSION);
-    println!("cargo:rustc-env=SETTINGS_SCHEMA={}", serde_json::to_string(&settings_schema(settings_default())).unwrap());
+    let schema = settings_schema(settings_default());
+    if let Err(errors) = schema.validate_defaults() {
+        panic!("Invalid settings defaults:\n{}", errors.join("\n"));
+    }
+    println!("cargo:rustc-env=SETTINGS_SCHEMA={}", serde_json::to_string(&schema).unwrap());
 }
@@ -3,8 +3,8 @@ use proc_macro2::{Span, TokenStream as TokenStream2};
 use quote::{quote, ToTokens};
 use std::string::ToString;
 use syn::{
-    Attribute, Data, DeriveInput, Error, Fields, FieldsNamed, GenericArgument, Ident, Lit, Meta,
-    NestedMeta, PathArguments, Type,
+    Attribute, Data, DeriveInput, Error, Expr, ExprLit, Fields, FieldsNamed, GenericArgument,
+    Ident, Lit, LitStr, Meta, NestedMeta, PathArguments, Type, TypeArray,
 };
 
 fn error<T, TT: ToTokens>(message: &str, tokens: TT) -> Result<T, TokenStream> {
@@ -42,18 +42,28 @@ fn get_only_type_argument(arguments: &PathArguments) -> &Type {
 
 struct SchemaAttributes {
     advanced: bool,
+    cascade_advanced: bool,
+    basic: bool,
     min: Option<Lit>,
     max: Option<Lit>,
     step: Option<Lit>,
     gui: Option<Lit>,
+    min_length: Option<Lit>,
+    max_length: Option<Lit>,
+    name: Option<Lit>,
 }
 
 fn schema_attributes(attrs: Vec<Attribute>) -> Result<SchemaAttributes, TokenStream> {
     let mut advanced = false;
+    let mut cascade_advanced = false;
+    let mut maybe_basic_path = None;
     let mut min = None;
     let mut max = None;
     let mut step = None;
     let mut gui = None;
+    let mut min_length = None;
+    let mut max_length = None;
+    let mut name = None;
     for attr in schema_attrs(attrs) {
         let parsed_attr = attr
             .parse_meta()
@@ -67,6 +77,10 @@ fn schema_attributes(attrs: Vec<Attribute>) -> Result<SchemaAttributes, TokenStr
                                 if let Some(arg_ident) = path_arg.get_ident() {
                                     if arg_ident == "advanced" {
                                         advanced = true;
+                                    } else if arg_ident == "cascade_advanced" {
+                                        cascade_advanced = true;
+                                    } else if arg_ident == "basic" {
+                                        maybe_basic_path = Some(path_arg);
                                     } else {
                                         return error(
                                             "Unknown identifier or missing value",
@@ -84,6 +98,9 @@ fn schema_attributes(attrs: Vec<Attribute>) -> Result<SchemaAttributes, TokenStr
                                         "max" => max = Some(name_value_arg.lit),
                                         "step" => step = Some(name_value_arg.lit),
                                         "gui" => gui = Some(name_value_arg.lit),
+                                        "min_length" => min_length = Some(name_value_arg.lit),
+                                        "max_length" => max_length = Some(name_value_arg.lit),
+                                        "name" => name = Some(name_value_arg.lit),
                                         _ => return error("Unknown argument name", arg_ident),
                                     }
                                 } else {
@@ -100,15 +117,61 @@ fn schema_attributes(attrs: Vec<Attribute>) -> Result<SchemaAttributes, TokenStr
             _ => return error("Expected arguments", parsed_attr),
         }
     }
+
+    let basic = maybe_basic_path.is_some();
+    if let (Some(basic_path), true) = (maybe_basic_path, advanced || cascade_advanced) {
+        return error("`basic` conflicts with `advanced`", basic_path);
+    }
+
     Ok(SchemaAttributes {
         advanced,
+        cascade_advanced,
+        basic,
         min,
         max,
         step,
         gui,
+        min_length,
+        max_length,
+        name,
     })
 }
 
+// On a struct or enum, only `advanced` is supported. It makes `advanced` the default for all the
+// fields or variants. A field or variant opts out with `basic`.
+fn type_level_advanced(attrs: Vec<Attribute>) -> Result<bool, TokenStream> {
+    let attrs = schema_attrs(attrs);
+    let maybe_first_attr = attrs.first().cloned();
+    let SchemaAttributes {
+        advanced,
+        cascade_advanced,
+        basic,
+        min,
+        max,
+        step,
+        gui,
+        min_length,
+        max_length,
+        name,
+    } = schema_attributes(attrs)?;
+
+    let maybe_unsupported_lit = min
+        .or(max)
+        .or(step)
+        .or(gui)
+        .or(min_length)
+        .or(max_length)
+        .or(name);
+    if let Some(literal) = maybe_unsupported_lit {
+        return error("Only `advanced` is supported on structs and enums", literal);
+    }
+    if let (Some(attr), true) = (maybe_first_attr, cascade_advanced || basic) {
+        return error("Only `advanced` is supported on structs and enums", attr);
+    }
+
+    Ok(advanced)
+}
+
 struct TypeSchema {
     default_ty_ts: TokenStream2,
     schema_code_ts: TokenStream2,
@@ -134,6 +197,7 @@ fn bool_type_schema(schema_attrs: SchemaAttributes) -> Result<TokenStream2, Toke
     Ok(quote! {
         settings_schema::SchemaNode {
             advanced: #advanced,
+            keep_basic: false,
             node_type: settings_schema::SchemaNodeType::Boolean { default }
         }
     })
@@ -159,16 +223,48 @@ fn maybe_float_literal(literal: Option<Lit>) -> Result<TokenStream2, TokenStream
     }
 }
 
+fn maybe_length_literal(literal: Option<Lit>) -> Result<TokenStream2, TokenStream> {
+    if let Some(literal) = literal {
+        if let Lit::Int(lit_int) = literal {
+            Ok(quote!(Some(#lit_int)))
+        } else {
+            error("Expected integer literal", literal)
+        }
+    } else {
+        Ok(quote!(None))
+    }
+}
+
+// The match is case insensitive and ignores surrounding whitespace. The returned identifier has the
+// canonical spelling.
+fn gui_type_ident(lit_str: &LitStr, valid_names: &[&str]) -> Result<Ident, TokenStream> {
+    let lit_val = lit_str.value();
+    if let Some(name) = valid_names
+        .iter()
+        .find(|name| name.eq_ignore_ascii_case(lit_val.trim()))
+    {
+        Ok(Ident::new(name, lit_str.span()))
+    } else {
+        let valid_names = valid_names
+            .iter()
+            .map(|name| format!(r#""{}""#, name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        error(
+            &format!(
+                r#"Invalid gui type "{}". Valid values: {}"#,
+                lit_val, valid_names
+            ),
+            lit_str,
+        )
+    }
+}
+
 fn maybe_numeric_gui(literal: Option<Lit>) -> Result<TokenStream2, TokenStream> {
     if let Some(literal) = literal {
         if let Lit::Str(lit_str) = literal {
-            let lit_val = lit_str.value();
-            if matches!(lit_val.as_str(), "TextBox" | "UpDown" | "Slider") {
-                let ident = Ident::new(&lit_val, lit_str.span());
-                Ok(quote!(Some(settings_schema::NumericGuiType::#ident)))
-            } else {
-                error(r#"Expected "TextBox", "UpDown" or "Slider""#, lit_str)
-            }
+            let ident = gui_type_ident(&lit_str, &["TextBox", "UpDown", "Slider"])?;
+            Ok(quote!(Some(settings_schema::NumericGuiType::#ident)))
         } else {
             error("Expected string literal", literal)
         }
@@ -177,6 +273,40 @@ fn maybe_numeric_gui(literal: Option<Lit>) -> Result<TokenStream2, TokenStream>
     }
 }
 
+fn maybe_text_gui(literal: Option<Lit>) -> Result<TokenStream2, TokenStream> {
+    if let Some(literal) = literal {
+        if let Lit::Str(lit_str) = literal {
+            let ident = gui_type_ident(&lit_str, &["FilePath", "Password"])?;
+            Ok(quote!(Some(settings_schema::TextGuiType::#ident)))
+        } else {
+            error("Expected string literal", literal)
+        }
+    } else {
+        Ok(quote!(None))
+    }
+}
+
+// None if the array is not [f32; 3] or [f32; 4]
+fn color_array_has_alpha(ty_array: &TypeArray) -> Option<bool> {
+    if ty_array.elem.to_token_stream().to_string() != "f32" {
+        return None;
+    }
+
+    if let Expr::Lit(ExprLit {
+        lit: Lit::Int(lit_int),
+        ..
+    }) = &ty_array.len
+    {
+        match lit_int.base10_digits() {
+            "3" => Some(false),
+            "4" => Some(true),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
 fn integer_type_schema(
     ty_ident: &Ident,
     schema_attrs: SchemaAttributes,
@@ -206,6 +336,7 @@ fn integer_type_schema(
         let step: #ty_ident = #step_ts;
         settings_schema::SchemaNode {
             advanced: #advanced,
+            keep_basic: false,
             node_type: settings_schema::SchemaNodeType::Integer {
                 default: default as _,
                 min: min as _,
@@ -227,6 +358,7 @@ fn float_type_schema(schema_attrs: SchemaAttributes) -> Result<TokenStream2, Tok
     Ok(quote! {
         settings_schema::SchemaNode {
             advanced: #advanced,
+            keep_basic: false,
             node_type: settings_schema::SchemaNodeType::Float {
                 default: default as _,
                 min: #min_ts,
@@ -245,20 +377,20 @@ fn string_type_schema(schema_attrs: SchemaAttributes) -> Result<TokenStream2, To
         Some(max)
     } else if let Some(step) = schema_attrs.step {
         Some(step)
-    } else if let Some(gui) = schema_attrs.gui {
-        Some(gui)
     } else {
         None
     };
     if let Some(arg) = maybe_invalid_arg {
         error("Unexpected argument for String type", arg)?;
     }
+    let gui_ts = maybe_text_gui(schema_attrs.gui)?;
 
     let advanced = schema_attrs.advanced;
     Ok(quote! {
         settings_schema::SchemaNode {
             advanced: #advanced,
-            node_type: settings_schema::SchemaNodeType::Text { default }
+            keep_basic: false,
+            node_type: settings_schema::SchemaNodeType::Text { default, gui: #gui_ts }
         }
     })
 }
@@ -296,10 +428,65 @@ fn type_schema(ty: &Type, schema_attrs: SchemaAttributes) -> Result<TypeSchema,
     match &ty {
         Type::Array(ty_array) => {
             let len = &ty_array.len;
+
+            // The "ColorPicker" hint turns the whole array into a color, other hints apply to each
+            // element
+            let SchemaAttributes {
+                advanced,
+                min,
+                max,
+                step,
+                gui,
+                min_length,
+                max_length,
+                ..
+            } = schema_attrs;
+            if let Some(Lit::Str(lit_str)) = &gui {
+                if lit_str.value().trim().eq_ignore_ascii_case("ColorPicker") {
+                    let has_alpha = match color_array_has_alpha(ty_array) {
+                        Some(has_alpha) => has_alpha,
+                        None => {
+                            return error(
+                                r#""ColorPicker" is supported only for [f32; 3] and [f32; 4]"#,
+                                lit_str,
+                            )
+                        }
+                    };
+                    return Ok(TypeSchema {
+                        default_ty_ts: quote!([f32; #len]),
+                        schema_code_ts: quote! {{
+                            let mut color = [0., 0., 0., 1.];
+                            color[..#len].copy_from_slice(&default);
+
+                            settings_schema::SchemaNode {
+                                advanced: #advanced,
+                                keep_basic: false,
+                                node_type: settings_schema::SchemaNodeType::Color {
+                                    has_alpha: #has_alpha,
+                                    default: color,
+                                },
+                            }
+                        }},
+                    });
+                }
+            }
+            let element_schema_attrs = SchemaAttributes {
+                advanced,
+                cascade_advanced: false,
+                basic: false,
+                min,
+                max,
+                step,
+                gui,
+                min_length,
+                max_length,
+                name: None,
+            };
+
             let TypeSchema {
                 default_ty_ts,
                 schema_code_ts,
-            } = type_schema(&*ty_array.elem, schema_attrs)?;
+            } = type_schema(&*ty_array.elem, element_schema_attrs)?;
             Ok(TypeSchema {
                 default_ty_ts: quote!([#default_ty_ts; #len]),
                 schema_code_ts: quote! {{
@@ -314,6 +501,7 @@ fn type_schema(ty: &Type, schema_attrs: SchemaAttributes) -> Result<TypeSchema,
 
                     settings_schema::SchemaNode {
                         advanced: #advanced,
+                        keep_basic: false,
                         node_type: settings_schema::SchemaNodeType::Array(content),
                     }
                 }},
@@ -323,12 +511,37 @@ fn type_schema(ty: &Type, schema_attrs: SchemaAttributes) -> Result<TypeSchema,
             let ty_last = ty_path.path.segments.last().unwrap();
             let ty_ident = &ty_last.ident;
             if matches!(ty_last.arguments, PathArguments::None) {
+                if let Some(arg) = schema_attrs
+                    .min_length
+                    .as_ref()
+                    .or(schema_attrs.max_length.as_ref())
+                {
+                    return error("Length arguments are supported only for Vec", arg);
+                }
+
                 let mut custom_default_ty_ts = None;
                 let schema_code_ts = match ty_ident.to_string().as_str() {
                     "bool" => bool_type_schema(schema_attrs)?,
                     "i8" | "u8" | "i16" | "u16" | "i32" | "u32" | "i64" | "u64" => {
                         integer_type_schema(ty_ident, schema_attrs)?
                     }
+                    // Settings are sent to the client, which may have a different pointer width
+                    "usize" | "isize" => {
+                        return error(
+                            &format!(
+                                "{} is hardware dependent. Use a fixed width integer",
+                                ty_ident
+                            ),
+                            ty_ident,
+                        )
+                    }
+                    // The JSON number type used by the GUI cannot represent all 128 bit values
+                    "i128" | "u128" => {
+                        return error(
+                            &format!("{} is not supported. Use an integer up to 64 bit", ty_ident),
+                            ty_ident,
+                        )
+                    }
                     "f32" | "f64" => float_type_schema(schema_attrs)?,
                     "String" => string_type_schema(schema_attrs)?,
                     _ => {
@@ -358,6 +571,7 @@ fn type_schema(ty: &Type, schema_attrs: SchemaAttributes) -> Result<TypeSchema,
                         let content = Box::new(#schema_code_ts);
                         settings_schema::SchemaNode {
                             advanced: #advanced,
+                            keep_basic: false,
                             node_type: settings_schema::SchemaNodeType::Optional { default_set, content }
                         }
                     }},
@@ -375,11 +589,37 @@ fn type_schema(ty: &Type, schema_attrs: SchemaAttributes) -> Result<TypeSchema,
                         let content = Box::new(#schema_code_ts);
                         settings_schema::SchemaNode {
                             advanced: #advanced,
+                            keep_basic: false,
                             node_type: settings_schema::SchemaNodeType::Switch { default_enabled, content }
                         }
                     }},
                 })
             } else if ty_ident == "Vec" {
+                let SchemaAttributes {
+                    advanced,
+                    min,
+                    max,
+                    step,
+                    gui,
+                    min_length,
+                    max_length,
+                    ..
+                } = schema_attrs;
+                let min_length_ts = maybe_length_literal(min_length)?;
+                let max_length_ts = maybe_length_literal(max_length)?;
+                let schema_attrs = SchemaAttributes {
+                    advanced,
+                    cascade_advanced: false,
+                    basic: false,
+                    min,
+                    max,
+                    step,
+                    gui,
+                    min_length: None,
+                    max_length: None,
+                    name: None,
+                };
+
                 let ty = get_only_type_argument(&ty_last.arguments);
                 if let Type::Tuple(ty_tuple) = ty {
                     if ty_tuple.elems.len() != 2 {
@@ -402,10 +642,14 @@ fn type_schema(ty: &Type, schema_attrs: SchemaAttributes) -> Result<TypeSchema,
                                 let default_value = Box::new(#schema_code_ts);
                                 settings_schema::SchemaNode {
                                     advanced: #advanced,
+                                    keep_basic: false,
                                     node_type: settings_schema::SchemaNodeType::Dictionary {
                                         default_key,
                                         default_value,
-                                        default: default_content }
+                                        default: default_content,
+                                        min_length: #min_length_ts,
+                                        max_length: #max_length_ts,
+                                    }
                                 }
                             }},
                         })
@@ -424,9 +668,12 @@ fn type_schema(ty: &Type, schema_attrs: SchemaAttributes) -> Result<TypeSchema,
                             let default_element = Box::new(#schema_code_ts);
                             settings_schema::SchemaNode {
                                 advanced: #advanced,
+                                keep_basic: false,
                                 node_type: settings_schema::SchemaNodeType::Vector {
                                     default_element,
-                                    default: default_content
+                                    default: default_content,
+                                    min_length: #min_length_ts,
+                                    max_length: #max_length_ts,
                                 }
                             }
                         }},
@@ -453,25 +700,61 @@ fn schema_attrs(attrs: Vec<Attribute>) -> Vec<Attribute> {
         .collect()
 }
 
+// `cascade_advanced` and `basic` apply to the whole subtree of the field, so they are applied to the
+// field schema after it is built.
+fn subtree_attributes_schema(
+    schema_code_ts: TokenStream2,
+    cascade_advanced: bool,
+    basic: bool,
+) -> TokenStream2 {
+    if cascade_advanced {
+        quote! {{
+            let mut node = #schema_code_ts;
+            settings_schema::cascade_advanced(&mut node);
+            node
+        }}
+    } else if basic {
+        quote! {{
+            let mut node = #schema_code_ts;
+            node.keep_basic = true;
+            node
+        }}
+    } else {
+        schema_code_ts
+    }
+}
+
 struct NamedFieldsData {
     idents: Vec<Ident>,
     tys_ts: Vec<TokenStream2>,
     schema_code_ts: TokenStream2,
 }
 
-fn schema_named_fields(fields_block: FieldsNamed) -> Result<NamedFieldsData, TokenStream> {
+fn schema_named_fields(
+    fields_block: FieldsNamed,
+    default_advanced: bool,
+) -> Result<NamedFieldsData, TokenStream> {
     let mut idents = vec![];
     let mut tys_ts = vec![];
     let mut schema_values_ts = vec![];
     for field in fields_block.named {
-        let schema_attrs = schema_attributes(field.attrs)?;
+        let mut schema_attrs = schema_attributes(field.attrs)?;
+        schema_attrs.advanced |= default_advanced && !schema_attrs.basic;
+        if let Some(name) = schema_attrs.name {
+            return error("`name` is supported only on variants", name);
+        }
+        let (cascade_advanced, basic) = (schema_attrs.cascade_advanced, schema_attrs.basic);
         let TypeSchema {
             default_ty_ts,
             schema_code_ts,
         } = type_schema(&field.ty, schema_attrs)?;
         idents.push(field.ident.unwrap());
         tys_ts.push(default_ty_ts);
-        schema_values_ts.push(schema_code_ts);
+        schema_values_ts.push(subtree_attributes_schema(
+            schema_code_ts,
+            cascade_advanced,
+            basic,
+        ));
     }
 
     let schema_keys = idents.iter().map(ToString::to_string);
@@ -485,6 +768,7 @@ fn schema_named_fields(fields_block: FieldsNamed) -> Result<NamedFieldsData, Tok
         )*
         settings_schema::SchemaNode {
             advanced: false,
+            keep_basic: false,
             node_type: settings_schema::SchemaNodeType::Section { entries }
         }
     }};
@@ -505,13 +789,7 @@ fn schema(input: DeriveInput) -> Result<TokenStream2, TokenStream> {
         return error("Generics not supported", &input.generics);
     }
 
-    let schema_attrs = schema_attrs(input.attrs);
-    if !schema_attrs.is_empty() {
-        return error(
-            "`schema` attribute supported only on fields and variants",
-            &schema_attrs[0],
-        );
-    }
+    let default_advanced = type_level_advanced(input.attrs)?;
 
     let mut field_idents = vec![];
     let mut field_tys_ts = vec![];
@@ -521,7 +799,7 @@ fn schema(input: DeriveInput) -> Result<TokenStream2, TokenStream> {
         Data::Struct(data_struct) => {
             match data_struct.fields {
                 Fields::Named(fields_block) => {
-                    let fields_data = schema_named_fields(fields_block)?;
+                    let fields_data = schema_named_fields(fields_block, default_advanced)?;
                     field_idents = fields_data.idents;
                     field_tys_ts = fields_data.tys_ts;
                     schema_root_code_ts = fields_data.schema_code_ts;
@@ -537,15 +815,24 @@ fn schema(input: DeriveInput) -> Result<TokenStream2, TokenStream> {
 
             let mut variant_idents = vec![];
             let mut variant_strings = vec![];
+            let mut variant_display_names = vec![];
             let mut variant_aux_objects_ts = vec![];
             let mut schema_variants_ts = vec![];
             for variant in data_enum.variants {
-                let schema_attrs = schema_attributes(variant.attrs)?;
+                let mut schema_attrs = schema_attributes(variant.attrs)?;
+                let variant_default_advanced = default_advanced && !schema_attrs.basic;
+                schema_attrs.advanced |= variant_default_advanced;
                 let variant_ident = variant.ident;
                 let variant_string = variant_ident.to_string();
+                let variant_display_name = match schema_attrs.name.take() {
+                    Some(Lit::Str(lit_str)) => lit_str.value(),
+                    Some(literal) => return error("Expected string literal", literal),
+                    None => variant_string.clone(),
+                };
                 match variant.fields {
                     Fields::Named(fields_block) => {
-                        let variant_fields_data = schema_named_fields(fields_block)?;
+                        let variant_fields_data =
+                            schema_named_fields(fields_block, variant_default_advanced)?;
                         let variant_field_idents = variant_fields_data.idents;
                         let variant_field_tys_ts = variant_fields_data.tys_ts;
                         let schema_variant_fields_code_ts = variant_fields_data.schema_code_ts;
@@ -573,11 +860,15 @@ fn schema(input: DeriveInput) -> Result<TokenStream2, TokenStream> {
                         }
                         field_idents.push(variant_ident.clone());
 
+                        let (cascade_advanced, basic) =
+                            (schema_attrs.cascade_advanced, schema_attrs.basic);
                         let TypeSchema {
                             default_ty_ts,
                             schema_code_ts,
                         } = type_schema(&fields_block.unnamed[0].ty, schema_attrs)?;
                         field_tys_ts.push(default_ty_ts);
+                        let schema_code_ts =
+                            subtree_attributes_schema(schema_code_ts, cascade_advanced, basic);
 
                         schema_variants_ts.push(quote!{{
                             let default = default.#variant_ident;
@@ -591,6 +882,7 @@ fn schema(input: DeriveInput) -> Result<TokenStream2, TokenStream> {
 
                 variant_idents.push(variant_ident);
                 variant_strings.push(variant_string);
+                variant_display_names.push(variant_display_name);
             }
 
             maybe_aux_objects_ts = Some(quote! {
@@ -608,6 +900,9 @@ fn schema(input: DeriveInput) -> Result<TokenStream2, TokenStream> {
             schema_root_code_ts = quote! {{
                 let mut variants = vec![];
                 #(variants.push((#variant_strings.into(), #schema_variants_ts));)*
+                let display_names = vec![
+                    #((#variant_strings.into(), #variant_display_names.into()),)*
+                ];
                 let default = serde_json::to_value(default.variant)
                     .unwrap()
                     .as_str()
@@ -616,9 +911,11 @@ fn schema(input: DeriveInput) -> Result<TokenStream2, TokenStream> {
 
                 settings_schema::SchemaNode {
                     advanced: false,
+                    keep_basic: false,
                     node_type: settings_schema::SchemaNodeType::Choice {
                         variants,
                         default,
+                        display_names,
                     }
                 }
             }}
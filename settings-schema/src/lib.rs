@@ -3,7 +3,11 @@ use serde_json as json;
 
 pub use settings_schema_derive::*;
 
-#[derive(Serialize, Deserialize, Clone)]
+// The derive macro refers to this crate by name
+#[cfg(test)]
+extern crate self as settings_schema;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub enum Switch<T> {
     Enabled(T),
     Disabled,
@@ -43,14 +47,20 @@ pub struct DictionaryDefault<V, D> {
     pub default: Vec<(String, D)>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub enum NumericGuiType {
     TextBox,
     UpDown,
     Slider,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub enum TextGuiType {
+    FilePath,
+    Password,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub enum SchemaNodeType {
     Section {
         entries: Vec<(String, SchemaNode)>,
@@ -58,6 +68,9 @@ pub enum SchemaNodeType {
     Choice {
         variants: Vec<(String, Option<SchemaNode>)>,
         default: String,
+        // Label of each variant for the GUI. Set with #[schema(name = "...")], otherwise it is the
+        // variant name
+        display_names: Vec<(String, String)>,
     },
     Optional {
         default_set: bool,
@@ -86,21 +99,1553 @@ pub enum SchemaNodeType {
     },
     Text {
         default: String,
+        gui: Option<TextGuiType>,
     },
     Array(Vec<SchemaNode>),
+    // [f32; 3] or [f32; 4] with the "ColorPicker" hint. Without alpha, default[3] is 1.
+    Color {
+        has_alpha: bool,
+        default: [f32; 4],
+    },
     Vector {
         default_element: Box<SchemaNode>,
         default: json::Value,
+        min_length: Option<usize>,
+        max_length: Option<usize>,
     },
     Dictionary {
         default_key: String,
         default_value: Box<SchemaNode>,
         default: json::Value,
+        min_length: Option<usize>,
+        max_length: Option<usize>,
     },
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct SchemaNode {
     pub advanced: bool,
+
+    // Set with #[schema(basic)]. The node is skipped by cascade_advanced(). Used only while building
+    // the schema
+    #[serde(skip)]
+    pub keep_basic: bool,
+
     pub node_type: SchemaNodeType,
 }
+
+// `label` is "default" for the schema defaults and "value" for a loaded settings file
+fn validate_length(
+    value: &json::Value,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    path: &str,
+    label: &str,
+    errors: &mut Vec<String>,
+) {
+    let length = value.as_array().map(|array| array.len()).unwrap_or(0);
+    if min_length.map(|min| length < min).unwrap_or(false)
+        || max_length.map(|max| length > max).unwrap_or(false)
+    {
+        errors.push(format!(
+            "{}: {} length {} out of range [{:?}, {:?}]",
+            path, label, length, min_length, max_length
+        ));
+    }
+}
+
+fn validate_node_defaults(node: &SchemaNode, path: &str, errors: &mut Vec<String>) {
+    match &node.node_type {
+        SchemaNodeType::Section { entries } => {
+            for (name, entry) in entries {
+                validate_node_defaults(entry, &format!("{}.{}", path, name), errors);
+            }
+        }
+        SchemaNodeType::Choice {
+            variants, default, ..
+        } => {
+            // Only the default variant is active
+            if let Some((_, Some(content))) = variants.iter().find(|(name, _)| name == default) {
+                validate_node_defaults(content, &format!("{}.{}", path, default), errors);
+            }
+        }
+        // The content of an unset optional or a disabled switch is only a placeholder
+        SchemaNodeType::Optional {
+            default_set,
+            content,
+        } => {
+            if *default_set {
+                validate_node_defaults(content, path, errors);
+            }
+        }
+        SchemaNodeType::Switch {
+            default_enabled,
+            content,
+        } => {
+            if *default_enabled {
+                validate_node_defaults(content, path, errors);
+            }
+        }
+        SchemaNodeType::Integer {
+            default, min, max, ..
+        } => {
+            if default < min || default > max {
+                errors.push(format!(
+                    "{}: default {} out of range [{}, {}]",
+                    path, default, min, max
+                ));
+            }
+        }
+        SchemaNodeType::Float {
+            default, min, max, ..
+        } => {
+            if min.map(|min| *default < min).unwrap_or(false)
+                || max.map(|max| *default > max).unwrap_or(false)
+            {
+                errors.push(format!(
+                    "{}: default {} out of range [{:?}, {:?}]",
+                    path, default, min, max
+                ));
+            }
+        }
+        SchemaNodeType::Array(content) => {
+            for (idx, element) in content.iter().enumerate() {
+                validate_node_defaults(element, &format!("{}[{}]", path, idx), errors);
+            }
+        }
+        SchemaNodeType::Color { default, .. } => {
+            if default
+                .iter()
+                .any(|component| *component < 0. || *component > 1.)
+            {
+                errors.push(format!(
+                    "{}: default color {:?} out of range [0, 1]",
+                    path, default
+                ));
+            }
+        }
+        SchemaNodeType::Vector {
+            default,
+            min_length,
+            max_length,
+            ..
+        }
+        | SchemaNodeType::Dictionary {
+            default,
+            min_length,
+            max_length,
+            ..
+        } => validate_length(default, *min_length, *max_length, path, "default", errors),
+        SchemaNodeType::Boolean { .. } | SchemaNodeType::Text { .. } => (),
+    }
+}
+
+// Check that the numeric values and the vector and dictionary lengths of a settings file are inside
+// the schema bounds, like validate_defaults() does for the defaults. Errors are appended to `errors`
+// with the path of the offending entry. Values of the wrong type are skipped and fail
+// deserialization.
+pub fn validate_json(node: &SchemaNode, value: &json::Value, path: &str, errors: &mut Vec<String>) {
+    match (&node.node_type, value) {
+        (SchemaNodeType::Section { entries }, json::Value::Object(map)) => {
+            for (name, entry) in entries {
+                if let Some(entry_value) = map.get(name) {
+                    validate_json(entry, entry_value, &format!("{}.{}", path, name), errors);
+                }
+            }
+        }
+        (SchemaNodeType::Choice { variants, .. }, json::Value::Object(map)) => {
+            for (name, variant_value) in map {
+                if let Some((_, Some(content))) = variants.iter().find(|(n, _)| n == name) {
+                    validate_json(
+                        content,
+                        variant_value,
+                        &format!("{}.{}", path, name),
+                        errors,
+                    );
+                }
+            }
+        }
+        (SchemaNodeType::Optional { content, .. }, value) if !value.is_null() => {
+            validate_json(content, value, path, errors)
+        }
+        (SchemaNodeType::Switch { content, .. }, json::Value::Object(map)) => {
+            if let Some(content_value) = map.get("Enabled") {
+                validate_json(content, content_value, path, errors);
+            }
+        }
+        (SchemaNodeType::Integer { min, max, .. }, json::Value::Number(number)) => {
+            let maybe_value = number
+                .as_i64()
+                .map(i128::from)
+                .or_else(|| number.as_u64().map(i128::from));
+            if let Some(value) = maybe_value {
+                if value < *min || value > *max {
+                    errors.push(format!(
+                        "{}: value {} out of range [{}, {}]",
+                        path, value, min, max
+                    ));
+                }
+            }
+        }
+        (SchemaNodeType::Float { min, max, .. }, json::Value::Number(number)) => {
+            if let Some(value) = number.as_f64() {
+                if min.map(|min| value < min).unwrap_or(false)
+                    || max.map(|max| value > max).unwrap_or(false)
+                {
+                    errors.push(format!(
+                        "{}: value {} out of range [{:?}, {:?}]",
+                        path, value, min, max
+                    ));
+                }
+            }
+        }
+        (SchemaNodeType::Array(content), json::Value::Array(values)) => {
+            for (idx, (element, element_value)) in content.iter().zip(values).enumerate() {
+                validate_json(
+                    element,
+                    element_value,
+                    &format!("{}[{}]", path, idx),
+                    errors,
+                );
+            }
+        }
+        (
+            SchemaNodeType::Vector {
+                default_element,
+                min_length,
+                max_length,
+                ..
+            },
+            json::Value::Array(values),
+        ) => {
+            validate_length(value, *min_length, *max_length, path, "value", errors);
+            for (idx, element_value) in values.iter().enumerate() {
+                validate_json(
+                    default_element,
+                    element_value,
+                    &format!("{}[{}]", path, idx),
+                    errors,
+                );
+            }
+        }
+        (
+            SchemaNodeType::Dictionary {
+                default_value,
+                min_length,
+                max_length,
+                ..
+            },
+            json::Value::Array(pairs),
+        ) => {
+            validate_length(value, *min_length, *max_length, path, "value", errors);
+            // each pair is serialized as [key, value]
+            for pair in pairs {
+                if let Some([json::Value::String(key), entry_value]) =
+                    pair.as_array().map(|pair| &pair[..])
+                {
+                    validate_json(
+                        default_value,
+                        entry_value,
+                        &format!("{}[{}]", path, key),
+                        errors,
+                    );
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+// Inverse of the schema function: extract the defaults as JSON, in the same format used by the
+// settings file.
+pub fn schema_to_default_json(node: &SchemaNode) -> json::Value {
+    match &node.node_type {
+        SchemaNodeType::Section { entries } => json::Value::Object(
+            entries
+                .iter()
+                .map(|(name, entry)| (name.clone(), schema_to_default_json(entry)))
+                .collect(),
+        ),
+        SchemaNodeType::Choice {
+            variants, default, ..
+        } => match variants.iter().find(|(name, _)| name == default) {
+            Some((_, Some(content))) => {
+                json::json!({ default: schema_to_default_json(content) })
+            }
+            _ => json::json!(default),
+        },
+        SchemaNodeType::Optional {
+            default_set,
+            content,
+        } => {
+            if *default_set {
+                schema_to_default_json(content)
+            } else {
+                json::Value::Null
+            }
+        }
+        SchemaNodeType::Switch {
+            default_enabled,
+            content,
+        } => {
+            if *default_enabled {
+                json::json!({ "Enabled": schema_to_default_json(content) })
+            } else {
+                json::json!("Disabled")
+            }
+        }
+        SchemaNodeType::Boolean { default } => json::json!(default),
+        SchemaNodeType::Integer { default, .. } => {
+            if *default < 0 {
+                json::json!(*default as i64)
+            } else {
+                json::json!(*default as u64)
+            }
+        }
+        SchemaNodeType::Float { default, .. } => json::json!(default),
+        SchemaNodeType::Text { default, .. } => json::json!(default),
+        SchemaNodeType::Array(content) => {
+            json::Value::Array(content.iter().map(schema_to_default_json).collect())
+        }
+        SchemaNodeType::Color { has_alpha, default } => {
+            let length = if *has_alpha { 4 } else { 3 };
+            json::json!(default[..length])
+        }
+        SchemaNodeType::Vector { default, .. } | SchemaNodeType::Dictionary { default, .. } => {
+            default.clone()
+        }
+    }
+}
+
+// Insert the defaults of the section entries missing from `value`, so that a settings file saved
+// before an entry was added can still be deserialized. The paths of the inserted entries are appended
+// to `filled_paths`. Values of the wrong type are left untouched and fail deserialization.
+pub fn fill_missing_json(
+    node: &SchemaNode,
+    value: &mut json::Value,
+    path: &str,
+    filled_paths: &mut Vec<String>,
+) {
+    match (&node.node_type, value) {
+        (SchemaNodeType::Section { entries }, json::Value::Object(map)) => {
+            for (name, entry) in entries {
+                let entry_path = format!("{}.{}", path, name);
+                match map.get_mut(name) {
+                    Some(entry_value) => {
+                        fill_missing_json(entry, entry_value, &entry_path, filled_paths)
+                    }
+                    None => {
+                        map.insert(name.clone(), schema_to_default_json(entry));
+                        filled_paths.push(entry_path);
+                    }
+                }
+            }
+        }
+        (SchemaNodeType::Choice { variants, .. }, json::Value::Object(map)) => {
+            for (name, variant_value) in map.iter_mut() {
+                if let Some((_, Some(content))) = variants.iter().find(|(n, _)| n == name) {
+                    fill_missing_json(
+                        content,
+                        variant_value,
+                        &format!("{}.{}", path, name),
+                        filled_paths,
+                    );
+                }
+            }
+        }
+        (SchemaNodeType::Optional { content, .. }, value) if !value.is_null() => {
+            fill_missing_json(content, value, path, filled_paths)
+        }
+        (SchemaNodeType::Switch { content, .. }, json::Value::Object(map)) => {
+            if let Some(content_value) = map.get_mut("Enabled") {
+                fill_missing_json(content, content_value, path, filled_paths);
+            }
+        }
+        (SchemaNodeType::Array(content), json::Value::Array(values)) => {
+            for (idx, (element, element_value)) in content.iter().zip(values).enumerate() {
+                fill_missing_json(
+                    element,
+                    element_value,
+                    &format!("{}[{}]", path, idx),
+                    filled_paths,
+                );
+            }
+        }
+        (
+            SchemaNodeType::Vector {
+                default_element, ..
+            },
+            json::Value::Array(values),
+        ) => {
+            for (idx, element_value) in values.iter_mut().enumerate() {
+                fill_missing_json(
+                    default_element,
+                    element_value,
+                    &format!("{}[{}]", path, idx),
+                    filled_paths,
+                );
+            }
+        }
+        (SchemaNodeType::Dictionary { default_value, .. }, json::Value::Array(pairs)) => {
+            // each pair is serialized as [key, value]
+            for pair in pairs {
+                if let json::Value::Array(pair) = pair {
+                    if let [json::Value::String(key), entry_value] = &mut pair[..] {
+                        let entry_path = format!("{}[{}]", path, key);
+                        fill_missing_json(default_value, entry_value, &entry_path, filled_paths);
+                    }
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+// Replace the enum variants unknown to the schema with the default variant, so that a settings file
+// saved by a newer version can still be deserialized. The paths of the replaced values are appended
+// to `replaced_paths`. Values of the wrong type are left untouched and fail deserialization.
+pub fn replace_unknown_variants_json(
+    node: &SchemaNode,
+    value: &mut json::Value,
+    path: &str,
+    replaced_paths: &mut Vec<String>,
+) {
+    match (&node.node_type, value) {
+        (SchemaNodeType::Section { entries }, json::Value::Object(map)) => {
+            for (name, entry) in entries {
+                if let Some(entry_value) = map.get_mut(name) {
+                    replace_unknown_variants_json(
+                        entry,
+                        entry_value,
+                        &format!("{}.{}", path, name),
+                        replaced_paths,
+                    );
+                }
+            }
+        }
+        (SchemaNodeType::Choice { variants, .. }, value) => {
+            // Unit variants are serialized as a string, the others as {variant: content}
+            let variant_name = match &*value {
+                json::Value::String(name) => name.clone(),
+                json::Value::Object(map) if map.len() == 1 => map.keys().next().unwrap().clone(),
+                _ => return,
+            };
+            match variants.iter().find(|(name, _)| *name == variant_name) {
+                Some((name, Some(content))) => {
+                    if let Some(content_value) = value.get_mut(name) {
+                        replace_unknown_variants_json(
+                            content,
+                            content_value,
+                            &format!("{}.{}", path, name),
+                            replaced_paths,
+                        );
+                    }
+                }
+                Some((_, None)) => (),
+                None => {
+                    *value = schema_to_default_json(node);
+                    replaced_paths.push(format!("{} ({})", path, variant_name));
+                }
+            }
+        }
+        (SchemaNodeType::Optional { content, .. }, value) if !value.is_null() => {
+            replace_unknown_variants_json(content, value, path, replaced_paths)
+        }
+        (SchemaNodeType::Switch { content, .. }, json::Value::Object(map)) => {
+            if let Some(content_value) = map.get_mut("Enabled") {
+                replace_unknown_variants_json(content, content_value, path, replaced_paths);
+            }
+        }
+        (SchemaNodeType::Array(content), json::Value::Array(values)) => {
+            for (idx, (element, element_value)) in content.iter().zip(values).enumerate() {
+                replace_unknown_variants_json(
+                    element,
+                    element_value,
+                    &format!("{}[{}]", path, idx),
+                    replaced_paths,
+                );
+            }
+        }
+        (
+            SchemaNodeType::Vector {
+                default_element, ..
+            },
+            json::Value::Array(values),
+        ) => {
+            for (idx, element_value) in values.iter_mut().enumerate() {
+                replace_unknown_variants_json(
+                    default_element,
+                    element_value,
+                    &format!("{}[{}]", path, idx),
+                    replaced_paths,
+                );
+            }
+        }
+        (SchemaNodeType::Dictionary { default_value, .. }, json::Value::Array(pairs)) => {
+            // each pair is serialized as [key, value]
+            for pair in pairs {
+                if let json::Value::Array(pair) = pair {
+                    if let [json::Value::String(key), entry_value] = &mut pair[..] {
+                        let entry_path = format!("{}[{}]", path, key);
+                        replace_unknown_variants_json(
+                            default_value,
+                            entry_value,
+                            &entry_path,
+                            replaced_paths,
+                        );
+                    }
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+// Mark the node and all its descendants as advanced, except the subtrees marked with
+// #[schema(basic)].
+pub fn cascade_advanced(node: &mut SchemaNode) {
+    if node.keep_basic {
+        return;
+    }
+
+    node.advanced = true;
+    match &mut node.node_type {
+        SchemaNodeType::Section { entries } => {
+            for (_, entry) in entries {
+                cascade_advanced(entry);
+            }
+        }
+        SchemaNodeType::Choice { variants, .. } => {
+            for (_, content) in variants {
+                if let Some(content) = content {
+                    cascade_advanced(content);
+                }
+            }
+        }
+        SchemaNodeType::Optional { content, .. } | SchemaNodeType::Switch { content, .. } => {
+            cascade_advanced(content)
+        }
+        SchemaNodeType::Array(content) => {
+            for element in content {
+                cascade_advanced(element);
+            }
+        }
+        SchemaNodeType::Vector {
+            default_element, ..
+        } => cascade_advanced(default_element),
+        SchemaNodeType::Dictionary { default_value, .. } => cascade_advanced(default_value),
+        SchemaNodeType::Boolean { .. }
+        | SchemaNodeType::Integer { .. }
+        | SchemaNodeType::Float { .. }
+        | SchemaNodeType::Text { .. }
+        | SchemaNodeType::Color { .. } => (),
+    }
+}
+
+enum PathSegment<'a> {
+    Name(&'a str),
+    Index(&'a str),
+}
+
+fn parse_path(path: &str) -> Option<Vec<PathSegment<'_>>> {
+    let mut segments = vec![];
+    let mut rest = path;
+    while !rest.is_empty() {
+        if rest.starts_with('[') {
+            let end = rest.find(']')?;
+            segments.push(PathSegment::Index(&rest[1..end]));
+            rest = &rest[end + 1..];
+        } else {
+            // The first name is not preceded by a dot
+            if !segments.is_empty() {
+                if !rest.starts_with('.') {
+                    return None;
+                }
+                rest = &rest[1..];
+            }
+            let end = rest.find(&['.', '['][..]).unwrap_or(rest.len());
+            if end == 0 {
+                return None;
+            }
+            segments.push(PathSegment::Name(&rest[..end]));
+            rest = &rest[end..];
+        }
+    }
+
+    Some(segments)
+}
+
+fn child_node<'a>(node: &'a SchemaNode, segment: &PathSegment) -> Option<&'a SchemaNode> {
+    match (&node.node_type, segment) {
+        (SchemaNodeType::Optional { content, .. }, _)
+        | (SchemaNodeType::Switch { content, .. }, _) => child_node(content, segment),
+        (SchemaNodeType::Section { entries }, PathSegment::Name(name)) => entries
+            .iter()
+            .find(|(entry_name, _)| entry_name == name)
+            .map(|(_, entry)| entry),
+        (SchemaNodeType::Choice { variants, .. }, PathSegment::Name(name)) => variants
+            .iter()
+            .find(|(variant_name, _)| variant_name == name)
+            .and_then(|(_, content)| content.as_ref()),
+        (SchemaNodeType::Array(content), PathSegment::Index(idx)) => {
+            content.get(idx.parse::<usize>().ok()?)
+        }
+        (
+            SchemaNodeType::Vector {
+                default_element, ..
+            },
+            PathSegment::Index(idx),
+        ) => idx.parse::<usize>().ok().map(|_| &**default_element),
+        (SchemaNodeType::Dictionary { default_value, .. }, PathSegment::Index(_)) => {
+            Some(default_value)
+        }
+        _ => None,
+    }
+}
+
+fn child_node_mut<'a>(
+    node: &'a mut SchemaNode,
+    segment: &PathSegment,
+) -> Option<&'a mut SchemaNode> {
+    match (&mut node.node_type, segment) {
+        (SchemaNodeType::Optional { content, .. }, _)
+        | (SchemaNodeType::Switch { content, .. }, _) => child_node_mut(content, segment),
+        (SchemaNodeType::Section { entries }, PathSegment::Name(name)) => entries
+            .iter_mut()
+            .find(|(entry_name, _)| entry_name == name)
+            .map(|(_, entry)| entry),
+        (SchemaNodeType::Choice { variants, .. }, PathSegment::Name(name)) => variants
+            .iter_mut()
+            .find(|(variant_name, _)| variant_name == name)
+            .and_then(|(_, content)| content.as_mut()),
+        (SchemaNodeType::Array(content), PathSegment::Index(idx)) => {
+            content.get_mut(idx.parse::<usize>().ok()?)
+        }
+        (
+            SchemaNodeType::Vector {
+                default_element, ..
+            },
+            PathSegment::Index(idx),
+        ) => idx
+            .parse::<usize>()
+            .ok()
+            .map(move |_| &mut **default_element),
+        (SchemaNodeType::Dictionary { default_value, .. }, PathSegment::Index(_)) => {
+            Some(default_value)
+        }
+        _ => None,
+    }
+}
+
+impl SchemaNode {
+    // Path syntax: section entries and choice variants are selected by name, separated by dots.
+    // Array elements are selected with [index], vector elements with any [index] and dictionary
+    // entries with any [key]; the last two return the default element. Optionals and switches are
+    // skipped over, as in the paths of validate_defaults() errors, but without the "root." prefix.
+    // An empty path returns the node itself. Example: "video.encoder.bitrate" or "devices[0].name"
+    pub fn get_by_path(&self, path: &str) -> Option<&SchemaNode> {
+        parse_path(path)?
+            .iter()
+            .try_fold(self, |node, segment| child_node(node, segment))
+    }
+
+    pub fn get_by_path_mut(&mut self, path: &str) -> Option<&mut SchemaNode> {
+        parse_path(path)?
+            .iter()
+            .try_fold(self, |node, segment| child_node_mut(node, segment))
+    }
+
+    // Check that numeric defaults are inside their bounds. Errors are returned with the path of
+    // the offending entry.
+    pub fn validate_defaults(&self) -> Result<(), Vec<String>> {
+        let mut errors = vec![];
+        validate_node_defaults(self, "root", &mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Only the generated schema is used
+    #[allow(dead_code)]
+    #[derive(SettingsSchema)]
+    struct Bounded {
+        #[schema(min = 1, max = 10)]
+        integer: u32,
+
+        #[schema(min = 0., max = 1.)]
+        float: f32,
+
+        #[schema(min = 1, max = 10)]
+        optional: Option<u32>,
+
+        #[schema(min = 1, max = 10)]
+        switch: Switch<u32>,
+    }
+
+    fn bounded_default() -> BoundedDefault {
+        BoundedDefault {
+            integer: 5,
+            float: 0.5,
+            optional: OptionalDefault {
+                set: true,
+                content: 5,
+            },
+            switch: SwitchDefault {
+                enabled: true,
+                content: 5,
+            },
+        }
+    }
+
+    #[test]
+    fn defaults_in_bounds_are_valid() {
+        assert!(bounded_schema(bounded_default())
+            .validate_defaults()
+            .is_ok());
+    }
+
+    #[test]
+    fn defaults_out_of_bounds_are_reported_with_path() {
+        let mut default = bounded_default();
+        default.integer = 11;
+        default.float = -0.5;
+        default.switch.content = 0;
+
+        let errors = bounded_schema(default).validate_defaults().unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].starts_with("root.integer: default 11"));
+        assert!(errors[1].starts_with("root.float: default -0.5"));
+        assert!(errors[2].starts_with("root.switch: default 0"));
+    }
+
+    #[test]
+    fn inactive_defaults_are_not_validated() {
+        let mut default = bounded_default();
+        default.optional = OptionalDefault {
+            set: false,
+            content: 0,
+        };
+        default.switch = SwitchDefault {
+            enabled: false,
+            content: 0,
+        };
+
+        assert!(bounded_schema(default).validate_defaults().is_ok());
+    }
+
+    fn entry<'a>(node: &'a SchemaNode, name: &str) -> &'a SchemaNode {
+        if let SchemaNodeType::Section { entries } = &node.node_type {
+            &entries
+                .iter()
+                .find(|(entry_name, _)| entry_name == name)
+                .unwrap()
+                .1
+        } else {
+            panic!("Not a section")
+        }
+    }
+
+    #[allow(dead_code)]
+    #[derive(SettingsSchema)]
+    struct GuiHints {
+        #[schema(gui = "ColorPicker")]
+        rgb: [f32; 3],
+
+        #[schema(gui = "ColorPicker")]
+        rgba: [f32; 4],
+
+        position: [f32; 3],
+
+        #[schema(gui = "FilePath")]
+        path: String,
+
+        #[schema(gui = "Password")]
+        password: String,
+    }
+
+    #[test]
+    fn gui_hints() {
+        let schema = guihints_schema(guihints_default());
+
+        assert!(matches!(
+            &entry(&schema, "rgb").node_type,
+            SchemaNodeType::Color {
+                has_alpha: false,
+                default,
+            } if *default == [1., 0.5, 0., 1.]
+        ));
+        assert!(matches!(
+            &entry(&schema, "rgba").node_type,
+            SchemaNodeType::Color {
+                has_alpha: true,
+                default,
+            } if *default == [0., 0., 1., 0.5]
+        ));
+        assert!(matches!(
+            &entry(&schema, "position").node_type,
+            SchemaNodeType::Array(content) if content.len() == 3
+        ));
+        assert!(matches!(
+            entry(&schema, "path").node_type,
+            SchemaNodeType::Text {
+                gui: Some(TextGuiType::FilePath),
+                ..
+            }
+        ));
+        assert!(matches!(
+            entry(&schema, "password").node_type,
+            SchemaNodeType::Text {
+                gui: Some(TextGuiType::Password),
+                ..
+            }
+        ));
+    }
+
+    fn guihints_default() -> GuiHintsDefault {
+        GuiHintsDefault {
+            rgb: [1., 0.5, 0.],
+            rgba: [0., 0., 1., 0.5],
+            position: [0., 1.6, 0.],
+            path: "".into(),
+            password: "".into(),
+        }
+    }
+
+    #[test]
+    fn color_default_json_has_alpha_only_if_requested() {
+        let default_json = schema_to_default_json(&guihints_schema(guihints_default()));
+        assert_eq!(default_json["rgb"], json::json!([1., 0.5, 0.]));
+        assert_eq!(default_json["rgba"], json::json!([0., 0., 1., 0.5]));
+    }
+
+    #[test]
+    fn color_default_out_of_range_is_reported() {
+        let mut default = guihints_default();
+        default.rgba = [0., 0., 1.5, 1.];
+
+        let errors = guihints_schema(default).validate_defaults().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("root.rgba: default color"));
+    }
+
+    #[allow(dead_code)]
+    #[derive(SettingsSchema)]
+    struct Lengths {
+        #[schema(min_length = 1, max_length = 3, min = 0, max = 10)]
+        list: Vec<u32>,
+
+        #[schema(max_length = 2)]
+        dictionary: Vec<(String, u32)>,
+    }
+
+    fn lengths_default() -> LengthsDefault {
+        LengthsDefault {
+            list: VectorDefault {
+                element: 0,
+                default: vec![1, 2],
+            },
+            dictionary: DictionaryDefault {
+                key: "key".into(),
+                value: 0,
+                default: vec![("a".into(), 1)],
+            },
+        }
+    }
+
+    #[test]
+    fn length_bounds_are_derived() {
+        let schema = lengths_schema(lengths_default());
+
+        assert!(matches!(
+            entry(&schema, "list").node_type,
+            SchemaNodeType::Vector {
+                min_length: Some(1),
+                max_length: Some(3),
+                ..
+            }
+        ));
+        assert!(matches!(
+            entry(&schema, "dictionary").node_type,
+            SchemaNodeType::Dictionary {
+                min_length: None,
+                max_length: Some(2),
+                ..
+            }
+        ));
+        assert!(schema.validate_defaults().is_ok());
+    }
+
+    #[test]
+    fn default_length_out_of_bounds_is_reported() {
+        let mut default = lengths_default();
+        default.list.default = vec![];
+
+        let errors = lengths_schema(default).validate_defaults().unwrap_err();
+        assert_eq!(
+            errors,
+            vec!["root.list: default length 0 out of range [Some(1), Some(3)]".to_owned()]
+        );
+    }
+
+    #[test]
+    fn json_bounds_are_validated() {
+        let schema = lengths_schema(lengths_default());
+
+        let mut errors = vec![];
+        let valid_json = json::json!({ "list": [10], "dictionary": [["a", 1], ["b", 2]] });
+        validate_json(&schema, &valid_json, "root", &mut errors);
+        assert!(errors.is_empty());
+
+        let invalid_json = json::json!({
+            "list": [1, 2, 3, 11],
+            "dictionary": [["a", 1], ["b", 2], ["c", 3]],
+        });
+        validate_json(&schema, &invalid_json, "root", &mut errors);
+        assert_eq!(
+            errors,
+            vec![
+                "root.list: value length 4 out of range [Some(1), Some(3)]".to_owned(),
+                "root.list[3]: value 11 out of range [0, 10]".to_owned(),
+                "root.dictionary: value length 3 out of range [None, Some(2)]".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn json_of_wrong_type_is_skipped() {
+        let schema = lengths_schema(lengths_default());
+        let wrong_type_json = json::json!({ "list": "abc" });
+
+        let mut errors = vec![];
+        validate_json(&schema, &wrong_type_json, "root", &mut errors);
+        assert!(errors.is_empty());
+    }
+
+    #[derive(SettingsSchema, Serialize, Deserialize, PartialEq, Debug)]
+    enum Mode {
+        Off,
+        Scale(f32),
+        Absolute { width: u32 },
+    }
+
+    #[derive(SettingsSchema, Serialize, Deserialize, PartialEq)]
+    struct Defaults {
+        mode: Mode,
+        optional: Option<u32>,
+        switch: Switch<i32>,
+        #[schema(gui = "ColorPicker")]
+        color: [f32; 3],
+        list: Vec<String>,
+    }
+
+    fn defaults_default() -> DefaultsDefault {
+        DefaultsDefault {
+            mode: ModeDefault {
+                variant: ModeDefaultVariant::Absolute,
+                Scale: 1.,
+                Absolute: ModeAbsoluteDefault { width: 100 },
+            },
+            optional: OptionalDefault {
+                set: true,
+                content: 3,
+            },
+            switch: SwitchDefault {
+                enabled: true,
+                content: -2,
+            },
+            color: [1., 0., 0.5],
+            list: VectorDefault {
+                element: "".into(),
+                default: vec!["a".into()],
+            },
+        }
+    }
+
+    #[test]
+    fn default_json_deserializes() {
+        let default_json = schema_to_default_json(&defaults_schema(defaults_default()));
+        assert_eq!(
+            default_json,
+            json::json!({
+                "mode": { "Absolute": { "width": 100 } },
+                "optional": 3,
+                "switch": { "Enabled": -2 },
+                "color": [1., 0., 0.5],
+                "list": ["a"],
+            })
+        );
+
+        let defaults: Defaults = json::from_value(default_json).unwrap();
+        assert!(
+            defaults
+                == Defaults {
+                    mode: Mode::Absolute { width: 100 },
+                    optional: Some(3),
+                    switch: Switch::Enabled(-2),
+                    color: [1., 0., 0.5],
+                    list: vec!["a".into()],
+                }
+        );
+    }
+
+    #[test]
+    fn unset_defaults_to_json() {
+        let mut default = defaults_default();
+        default.mode.variant = ModeDefaultVariant::Off;
+        default.optional.set = false;
+        default.switch.enabled = false;
+
+        let default_json = schema_to_default_json(&defaults_schema(default));
+        assert_eq!(default_json["mode"], json::json!("Off"));
+        assert_eq!(default_json["optional"], json::Value::Null);
+        assert_eq!(default_json["switch"], json::json!("Disabled"));
+
+        let defaults: Defaults = json::from_value(default_json).unwrap();
+        assert_eq!(defaults.mode, Mode::Off);
+        assert_eq!(defaults.optional, None);
+        assert!(defaults.switch == Switch::Disabled);
+    }
+
+    #[test]
+    fn missing_json_entries_are_filled() {
+        let schema = defaults_schema(defaults_default());
+        let mut value = json::json!({
+            "mode": { "Absolute": {} },
+            "optional": 3,
+            "switch": "Disabled",
+        });
+
+        let mut filled_paths = vec![];
+        fill_missing_json(&schema, &mut value, "root", &mut filled_paths);
+        assert_eq!(
+            filled_paths,
+            vec!["root.mode.Absolute.width", "root.color", "root.list"]
+        );
+
+        let defaults: Defaults = json::from_value(value).unwrap();
+        assert_eq!(defaults.mode, Mode::Absolute { width: 100 });
+        assert_eq!(defaults.color, [1., 0., 0.5]);
+        assert_eq!(defaults.list, vec!["a".to_owned()]);
+    }
+
+    #[test]
+    fn json_of_wrong_type_is_not_filled() {
+        let schema = defaults_schema(defaults_default());
+        let mut value = schema_to_default_json(&schema);
+        value["mode"] = json::json!({ "Absolute": 5 });
+
+        let mut filled_paths = vec![];
+        fill_missing_json(&schema, &mut value, "root", &mut filled_paths);
+        assert!(filled_paths.is_empty());
+        assert_eq!(value["mode"], json::json!({ "Absolute": 5 }));
+    }
+
+    #[allow(dead_code)]
+    #[derive(SettingsSchema)]
+    struct Inner {
+        value: u32,
+
+        #[schema(basic)]
+        basic_value: u32,
+    }
+
+    #[allow(dead_code)]
+    #[derive(SettingsSchema)]
+    struct Cascade {
+        #[schema(cascade_advanced)]
+        inner: Inner,
+
+        flag: bool,
+    }
+
+    #[test]
+    fn advanced_cascades_to_the_field_subtree() {
+        let schema = cascade_schema(CascadeDefault {
+            inner: InnerDefault {
+                value: 0,
+                basic_value: 0,
+            },
+            flag: false,
+        });
+
+        let inner = entry(&schema, "inner");
+        assert!(inner.advanced);
+        assert!(entry(inner, "value").advanced);
+        assert!(!entry(inner, "basic_value").advanced);
+        assert!(!entry(&schema, "flag").advanced);
+    }
+
+    #[test]
+    fn nodes_are_found_by_path() {
+        let schema = defaults_schema(defaults_default());
+
+        assert!(std::ptr::eq(schema.get_by_path("").unwrap(), &schema));
+        assert!(matches!(
+            schema.get_by_path("mode.Absolute.width").unwrap().node_type,
+            SchemaNodeType::Integer { default: 100, .. }
+        ));
+        assert!(matches!(
+            schema.get_by_path("color").unwrap().node_type,
+            SchemaNodeType::Color { .. }
+        ));
+        assert!(matches!(
+            schema.get_by_path("list[3]").unwrap().node_type,
+            SchemaNodeType::Text { .. }
+        ));
+
+        let lengths_schema = lengths_schema(lengths_default());
+        assert!(matches!(
+            lengths_schema
+                .get_by_path("dictionary[any key]")
+                .unwrap()
+                .node_type,
+            SchemaNodeType::Integer { .. }
+        ));
+    }
+
+    #[test]
+    fn invalid_paths_are_not_found() {
+        let schema = defaults_schema(defaults_default());
+
+        for path in &[
+            "missing",
+            "mode.Off",
+            "mode.Absolute.width.more",
+            "mode..Absolute",
+            ".mode",
+            "list[x]",
+            "list[0",
+            "[0]",
+        ] {
+            assert!(schema.get_by_path(path).is_none(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn nodes_are_modified_by_path() {
+        let mut schema = defaults_schema(defaults_default());
+        schema
+            .get_by_path_mut("mode.Absolute.width")
+            .unwrap()
+            .advanced = true;
+
+        assert!(schema.get_by_path("mode.Absolute.width").unwrap().advanced);
+        assert!(!schema.get_by_path("mode").unwrap().advanced);
+    }
+
+    #[test]
+    fn unknown_variants_are_replaced_with_default() {
+        let schema = defaults_schema(defaults_default());
+
+        for unknown_mode in &[json::json!("Fisheye"), json::json!({ "Stretch": 2 })] {
+            let mut value = schema_to_default_json(&schema);
+            value["mode"] = unknown_mode.clone();
+
+            let mut replaced_paths = vec![];
+            replace_unknown_variants_json(&schema, &mut value, "root", &mut replaced_paths);
+            assert_eq!(replaced_paths.len(), 1);
+            assert!(replaced_paths[0].starts_with("root.mode ("));
+
+            let defaults: Defaults = json::from_value(value).unwrap();
+            assert_eq!(defaults.mode, Mode::Absolute { width: 100 });
+        }
+    }
+
+    #[test]
+    fn known_variants_and_wrong_types_are_kept() {
+        let schema = defaults_schema(defaults_default());
+
+        for mode in &[
+            json::json!("Off"),
+            json::json!({ "Scale": 0.5 }),
+            json::json!(5),
+        ] {
+            let mut value = schema_to_default_json(&schema);
+            value["mode"] = mode.clone();
+
+            let mut replaced_paths = vec![];
+            replace_unknown_variants_json(&schema, &mut value, "root", &mut replaced_paths);
+            assert!(replaced_paths.is_empty());
+            assert_eq!(&value["mode"], mode);
+        }
+    }
+
+    #[allow(dead_code)]
+    #[derive(SettingsSchema)]
+    #[schema(advanced)]
+    struct AdvancedSection {
+        value: u32,
+
+        #[schema(basic)]
+        basic_value: u32,
+
+        inner: Inner,
+    }
+
+    #[test]
+    fn type_level_advanced_applies_to_fields() {
+        let schema = advancedsection_schema(AdvancedSectionDefault {
+            value: 0,
+            basic_value: 0,
+            inner: InnerDefault {
+                value: 0,
+                basic_value: 0,
+            },
+        });
+
+        assert!(!schema.advanced);
+        assert!(entry(&schema, "value").advanced);
+        assert!(!entry(&schema, "basic_value").advanced);
+
+        // Deeper levels are not affected
+        let inner = entry(&schema, "inner");
+        assert!(inner.advanced);
+        assert!(!entry(inner, "value").advanced);
+    }
+
+    #[allow(dead_code)]
+    #[derive(SettingsSchema)]
+    #[schema(advanced)]
+    enum AdvancedMode {
+        Off,
+        Scale(f32),
+
+        #[schema(basic)]
+        Fixed(u32),
+
+        Manual {
+            value: u32,
+        },
+    }
+
+    #[test]
+    fn type_level_advanced_applies_to_variants() {
+        let schema = advancedmode_schema(AdvancedModeDefault {
+            variant: AdvancedModeDefaultVariant::Off,
+            Scale: 1.,
+            Fixed: 0,
+            Manual: AdvancedModeManualDefault { value: 0 },
+        });
+
+        if let SchemaNodeType::Choice { variants, .. } = &schema.node_type {
+            let variant = |name: &str| {
+                variants
+                    .iter()
+                    .find(|(variant_name, _)| variant_name == name)
+                    .unwrap()
+                    .1
+                    .as_ref()
+            };
+            assert!(variant("Off").is_none());
+            assert!(variant("Scale").unwrap().advanced);
+            assert!(!variant("Fixed").unwrap().advanced);
+            assert!(entry(variant("Manual").unwrap(), "value").advanced);
+        } else {
+            panic!("Not a choice")
+        }
+    }
+
+    #[allow(dead_code)]
+    #[derive(SettingsSchema)]
+    struct LooseGuiHints {
+        #[schema(gui = " slider ")]
+        integer: u32,
+
+        #[schema(gui = "textbox")]
+        float: f32,
+
+        #[schema(gui = "FILEPATH")]
+        path: String,
+
+        #[schema(gui = "colorPicker")]
+        color: [f32; 3],
+    }
+
+    #[test]
+    fn gui_hints_are_case_insensitive() {
+        let schema = looseguihints_schema(LooseGuiHintsDefault {
+            integer: 0,
+            float: 0.,
+            path: "".into(),
+            color: [0.; 3],
+        });
+        assert!(matches!(
+            entry(&schema, "integer").node_type,
+            SchemaNodeType::Integer {
+                gui: Some(NumericGuiType::Slider),
+                ..
+            }
+        ));
+        assert!(matches!(
+            entry(&schema, "float").node_type,
+            SchemaNodeType::Float {
+                gui: Some(NumericGuiType::TextBox),
+                ..
+            }
+        ));
+        assert!(matches!(
+            entry(&schema, "path").node_type,
+            SchemaNodeType::Text {
+                gui: Some(TextGuiType::FilePath),
+                ..
+            }
+        ));
+        assert!(matches!(
+            entry(&schema, "color").node_type,
+            SchemaNodeType::Color { .. }
+        ));
+    }
+
+    #[allow(dead_code)]
+    #[derive(SettingsSchema)]
+    struct WideIntegers {
+        signed: i64,
+        unsigned: u64,
+    }
+
+    #[test]
+    fn integers_up_to_64_bit_keep_their_range() {
+        let schema = wideintegers_schema(WideIntegersDefault {
+            signed: i64::MIN,
+            unsigned: u64::MAX,
+        });
+
+        assert!(matches!(
+            entry(&schema, "signed").node_type,
+            SchemaNodeType::Integer {
+                default,
+                min,
+                max,
+                step: 1,
+                gui: None,
+            } if default == i64::MIN as i128 && min == i64::MIN as i128 && max == i64::MAX as i128
+        ));
+        assert!(matches!(
+            entry(&schema, "unsigned").node_type,
+            SchemaNodeType::Integer {
+                default,
+                min,
+                max,
+                step: 1,
+                gui: None,
+            } if default == u64::MAX as i128 && min == 0 && max == u64::MAX as i128
+        ));
+        assert_eq!(
+            schema_to_default_json(&schema),
+            json::json!({ "signed": i64::MIN, "unsigned": u64::MAX })
+        );
+    }
+
+    #[allow(dead_code)]
+    #[derive(SettingsSchema)]
+    struct FixedWidthIntegers {
+        #[schema(min = -1, max = 1)]
+        small: i8,
+        count: u32,
+    }
+
+    #[test]
+    fn fixed_width_integers_are_accepted() {
+        let schema = fixedwidthintegers_schema(FixedWidthIntegersDefault { small: 0, count: 3 });
+        assert!(matches!(
+            entry(&schema, "small").node_type,
+            SchemaNodeType::Integer {
+                min: -1,
+                max: 1,
+                ..
+            }
+        ));
+        assert!(matches!(
+            entry(&schema, "count").node_type,
+            SchemaNodeType::Integer { default: 3, .. }
+        ));
+    }
+
+    #[allow(dead_code)]
+    #[derive(SettingsSchema, Serialize, Deserialize)]
+    enum Filter {
+        #[schema(name = "Nearest neighbour")]
+        NearestNeighbour,
+        Bilinear,
+    }
+
+    #[test]
+    fn variant_display_names() {
+        let schema = filter_schema(FilterDefault {
+            variant: FilterDefaultVariant::Bilinear,
+        });
+
+        if let SchemaNodeType::Choice { display_names, .. } = schema.node_type {
+            assert_eq!(
+                display_names,
+                vec![
+                    ("NearestNeighbour".into(), "Nearest neighbour".into()),
+                    ("Bilinear".into(), "Bilinear".into()),
+                ]
+            );
+        } else {
+            panic!("Not a choice")
+        }
+    }
+}
+
+// Derive errors are checked with compile_fail doctests
+#[cfg(doctest)]
+mod derive_errors {
+    /// ```compile_fail
+    /// use settings_schema::SettingsSchema;
+    ///
+    /// #[derive(SettingsSchema)]
+    /// struct Test {
+    ///     #[schema(gui = "ColorPicker")]
+    ///     color: [u8; 3],
+    /// }
+    /// ```
+    struct ColorPickerRequiresF32;
+
+    /// ```compile_fail
+    /// use settings_schema::SettingsSchema;
+    ///
+    /// #[derive(SettingsSchema)]
+    /// struct Test {
+    ///     #[schema(gui = "ColorPicker")]
+    ///     color: [f32; 2],
+    /// }
+    /// ```
+    struct ColorPickerRequiresThreeOrFourComponents;
+
+    /// ```compile_fail
+    /// use settings_schema::SettingsSchema;
+    ///
+    /// #[derive(SettingsSchema)]
+    /// struct Test {
+    ///     #[schema(gui = "Slider")]
+    ///     path: String,
+    /// }
+    /// ```
+    struct TextGuiHint;
+
+    /// ```compile_fail
+    /// use settings_schema::SettingsSchema;
+    ///
+    /// #[derive(SettingsSchema)]
+    /// struct Test {
+    ///     #[schema(max_length = 3)]
+    ///     value: u32,
+    /// }
+    /// ```
+    struct LengthRequiresVec;
+
+    /// ```compile_fail
+    /// use settings_schema::SettingsSchema;
+    ///
+    /// #[derive(SettingsSchema)]
+    /// struct Test {
+    ///     #[schema(max_length = "3")]
+    ///     values: Vec<u32>,
+    /// }
+    /// ```
+    struct LengthRequiresInteger;
+
+    /// ```compile_fail
+    /// use settings_schema::SettingsSchema;
+    ///
+    /// #[derive(SettingsSchema)]
+    /// struct Test {
+    ///     #[schema(gui = "Knob")]
+    ///     value: u32,
+    /// }
+    /// ```
+    struct UnknownNumericGuiHint;
+
+    /// ```compile_fail
+    /// use settings_schema::SettingsSchema;
+    ///
+    /// #[derive(SettingsSchema)]
+    /// struct Test {
+    ///     #[schema(gui = "Path")]
+    ///     value: String,
+    /// }
+    /// ```
+    struct UnknownTextGuiHint;
+
+    /// ```compile_fail
+    /// use settings_schema::SettingsSchema;
+    ///
+    /// #[derive(SettingsSchema)]
+    /// struct Test {
+    ///     value: i128,
+    /// }
+    /// ```
+    struct RejectsI128;
+
+    /// ```compile_fail
+    /// use settings_schema::SettingsSchema;
+    ///
+    /// #[derive(SettingsSchema)]
+    /// struct Test {
+    ///     value: Option<u128>,
+    /// }
+    /// ```
+    struct RejectsNestedU128;
+
+    /// ```compile_fail
+    /// use settings_schema::SettingsSchema;
+    ///
+    /// #[derive(SettingsSchema)]
+    /// struct Test {
+    ///     value: usize,
+    /// }
+    /// ```
+    struct RejectsUsize;
+
+    /// ```compile_fail
+    /// use settings_schema::SettingsSchema;
+    ///
+    /// #[derive(SettingsSchema)]
+    /// struct Test {
+    ///     values: Vec<isize>,
+    /// }
+    /// ```
+    struct RejectsNestedIsize;
+
+    /// ```compile_fail
+    /// use settings_schema::SettingsSchema;
+    ///
+    /// #[derive(SettingsSchema)]
+    /// struct Test {
+    ///     #[schema(advanced, basic)]
+    ///     value: u32,
+    /// }
+    /// ```
+    struct BasicConflictsWithAdvanced;
+
+    /// ```compile_fail
+    /// use settings_schema::SettingsSchema;
+    ///
+    /// #[derive(SettingsSchema)]
+    /// struct Test {
+    ///     #[schema(name = "Value")]
+    ///     value: u32,
+    /// }
+    /// ```
+    struct NameRequiresVariant;
+
+    /// ```compile_fail
+    /// use settings_schema::SettingsSchema;
+    ///
+    /// #[derive(SettingsSchema)]
+    /// #[schema(min = 0)]
+    /// struct Test {
+    ///     value: u32,
+    /// }
+    /// ```
+    struct OnlyAdvancedOnTypes;
+}